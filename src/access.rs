@@ -0,0 +1,175 @@
+use std::{collections::VecDeque, fs, io, net::IpAddr, path::{Path, PathBuf}};
+
+use bevy::{prelude::*, utils::HashMap};
+use uuid::Uuid;
+
+use crate::{
+    jserver::{JServerClients, JServerSettings},
+    serverworld::WorldPath,
+};
+
+/// Each address may open this many connections per `THROTTLE_WINDOW` seconds.
+pub static MAX_CONNECTIONS_PER_WINDOW: usize = 10;
+/// Connections we don't know the address of share one allowance, so it's bigger.
+pub static MAX_UNKNOWN_CONNECTIONS_PER_WINDOW: usize = 60;
+pub static THROTTLE_WINDOW: f64 = 60.0;
+
+/// Server side: who may join, kept in the world directory.
+pub struct AccessPlugin;
+
+impl Plugin for AccessPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AccessLists>()
+        .init_resource::<ConnectionThrottle>()
+        .add_systems(Startup, load_access_lists)
+        ;
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct AccessLists {
+    /// Identities allowed in when `JServerSettings::whitelist` is on, with a name for people to read.
+    pub whitelist: HashMap<Uuid, String>,
+    /// Bans and their reasons.
+    pub banned_players: HashMap<Uuid, String>,
+}
+
+impl AccessLists {
+    pub fn whitelist_file(world: &WorldPath) -> PathBuf {
+        world.0.join("whitelist.txt")
+    }
+
+    pub fn bans_file(world: &WorldPath) -> PathBuf {
+        world.0.join("bans.txt")
+    }
+
+    /// `<uuid> [name]` per line in the whitelist; `player <uuid> [reason]` in bans.
+    pub fn load(world: &WorldPath) -> io::Result<Self> {
+        let mut lists = Self::default();
+
+        for line in read_lines(&Self::whitelist_file(world))? {
+            let (uuid, name) = line.split_once(' ').unwrap_or((&line, ""));
+            match Uuid::parse_str(uuid) {
+                Ok(uuid) => {
+                    lists.whitelist.insert(uuid, name.trim().to_string());
+                }
+                Err(_) => warn!("Ignoring bad whitelist line: {}", line),
+            }
+        }
+
+        for line in read_lines(&Self::bans_file(world))? {
+            let mut parts = line.splitn(3, ' ');
+            let (kind, target, reason) = (parts.next(), parts.next(), parts.next().unwrap_or("").trim().to_string());
+            match (kind, target) {
+                (Some("player"), Some(uuid)) if Uuid::parse_str(uuid).is_ok() => {
+                    lists.banned_players.insert(Uuid::parse_str(uuid).unwrap(), reason);
+                }
+                // Quinnet 0.9 doesn't tell us where network players connect from.
+                (Some("ip"), Some(_)) => warn!("Ignoring address ban, only players can be banned: {}", line),
+                _ => warn!("Ignoring bad ban line: {}", line),
+            }
+        }
+
+        Ok(lists)
+    }
+
+    pub fn save(&self, world: &WorldPath) -> io::Result<()> {
+        fs::create_dir_all(&world.0)?;
+
+        let whitelist: Vec<String> = self.whitelist.iter().map(|(uuid, name)| format!("{} {}\n", uuid, name)).collect();
+        fs::write(Self::whitelist_file(world), whitelist.concat())?;
+
+        let bans: Vec<String> = self.banned_players.iter().map(|(uuid, reason)| format!("player {} {}\n", uuid, reason)).collect();
+        fs::write(Self::bans_file(world), bans.concat())
+    }
+
+    /// Why this player can't join, checked on hello.
+    pub fn check_player(&self, settings: &JServerSettings, clients: &JServerClients, uuid: Uuid) -> Result<(), String> {
+        if let Some(reason) = self.banned_players.get(&uuid) {
+            return Err(banned_message(reason));
+        }
+        if settings.whitelist && !self.whitelist.contains_key(&uuid) {
+            return Err(String::from("You're not on this server's whitelist"));
+        }
+        if clients.players.len() >= settings.max_players {
+            return Err(format!("The server is full ({}/{})", clients.players.len(), settings.max_players));
+        }
+        Ok(())
+    }
+}
+
+pub fn banned_message(reason: &str) -> String {
+    if reason.is_empty() {
+        String::from("You're banned from this server")
+    } else {
+        format!("You're banned from this server: {}", reason)
+    }
+}
+
+/// Non-empty lines of a file, or none if it doesn't exist.
+fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Recent connection attempts by address, with those from unknown addresses under `None`.
+#[derive(Resource, Default)]
+pub struct ConnectionThrottle {
+    pub attempts: HashMap<Option<IpAddr>, VecDeque<f64>>,
+}
+
+impl ConnectionThrottle {
+    /// Records an attempt at `now`, false if there have been too many lately.
+    pub fn allow(&mut self, ip: Option<IpAddr>, now: f64) -> bool {
+        let attempts = self.attempts.entry(ip).or_default();
+        while attempts.front().map(|t| now - t > THROTTLE_WINDOW).unwrap_or(false) {
+            attempts.pop_front();
+        }
+        attempts.push_back(now);
+        attempts.len() <= if ip.is_some() { MAX_CONNECTIONS_PER_WINDOW } else { MAX_UNKNOWN_CONNECTIONS_PER_WINDOW }
+    }
+
+    /// Forgets addresses that have been quiet for a whole window.
+    pub fn forget_old(&mut self, now: f64) {
+        self.attempts.retain(|_, attempts| attempts.back().map(|t| now - t <= THROTTLE_WINDOW).unwrap_or(false));
+    }
+}
+
+pub fn load_access_lists(path: Res<WorldPath>, mut lists: ResMut<AccessLists>) {
+    match AccessLists::load(&path) {
+        Ok(loaded) => *lists = loaded,
+        // Refusing to start is better than letting banned players in.
+        Err(e) => {
+            eprintln!("Couldn't read the whitelist or bans in {}: {}", path.0.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_limits_each_address_and_unknown_ones_together() {
+        let mut throttle = ConnectionThrottle::default();
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        for _ in 0..MAX_CONNECTIONS_PER_WINDOW {
+            assert!(throttle.allow(Some(ip), 0.0));
+        }
+        assert!(!throttle.allow(Some(ip), 1.0));
+        assert!(throttle.allow(Some("10.0.0.8".parse().unwrap()), 1.0));
+        assert!(throttle.allow(Some(ip), THROTTLE_WINDOW + 1.5));
+
+        for _ in 0..MAX_UNKNOWN_CONNECTIONS_PER_WINDOW {
+            assert!(throttle.allow(None, 2.0));
+        }
+        assert!(!throttle.allow(None, 3.0));
+
+        throttle.forget_old(2.0 * THROTTLE_WINDOW + 4.0);
+        assert!(throttle.attempts.is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use noise::{Perlin, Seedable};
+
+use crate::{
+    chunk::{build_chunk_mesh, ensure_region_structures, generate_chunk_blocks, NonUserDataMap},
+    structure::StructureTemplates,
+    UserDataMap,
+};
+
+fn per_chunk(total: Duration, chunks: usize) -> f64 {
+    total.as_secs_f64() * 1000.0 / chunks as f64
+}
+
+/// Generates and meshes `chunks` chunks in a square around the origin and prints the timings.
+pub fn run_bench(chunks: usize, seed: Option<u32>) {
+    let perlin = seed.map(Perlin::new).unwrap_or_default();
+    let templates = StructureTemplates::default();
+    let udm = UserDataMap::default();
+    let mut nudm = NonUserDataMap::default();
+
+    let side = (chunks as f64).sqrt().ceil() as i32;
+    let cposes: Vec<IVec2> = (0..chunks as i32)
+        .map(|i| IVec2::new(i % side - side / 2, i / side - side / 2))
+        .collect();
+
+    let start = Instant::now();
+    for cpos in cposes.iter() {
+        ensure_region_structures(&perlin, &templates, &mut nudm, cpos);
+    }
+    let structures = start.elapsed();
+
+    let start = Instant::now();
+    let mut blocks = 0;
+    for cpos in cposes.iter() {
+        blocks += generate_chunk_blocks(&perlin, cpos).len();
+    }
+    let terrain = start.elapsed();
+
+    let start = Instant::now();
+    let mut vertices = 0;
+    for cpos in cposes.iter() {
+        vertices += build_chunk_mesh(&perlin, &udm, &nudm, cpos).count_vertices();
+    }
+    let meshing = start.elapsed();
+
+    println!("{} chunks, seed {}", cposes.len(), perlin.seed());
+    println!("structures: {:>8.2?} ({:.3} ms/chunk)", structures, per_chunk(structures, cposes.len()));
+    println!("terrain:    {:>8.2?} ({:.3} ms/chunk, {} blocks)", terrain, per_chunk(terrain, cposes.len()), blocks);
+    println!("meshing:    {:>8.2?} ({:.3} ms/chunk, {} vertices)", meshing, per_chunk(meshing, cposes.len()), vertices);
+}
@@ -0,0 +1,379 @@
+use bevy::{input::mouse::MouseWheel, prelude::*, utils::HashMap};
+use bevy_quinnet::shared::ClientId;
+use bevy_rapier3d::prelude::KinematicCharacterController;
+
+use crate::{
+    blockinfo::Blocks,
+    chunk::{blockat, rebuild_chunks_touching, JPerlin, NonUserDataMap, CH},
+    jclient::{JConnection, JConnectionState},
+    jserver::JServerClients,
+    movement::{player_overlaps_block, EYE_HEIGHT},
+    protocol::{send_to_client, send_to_server, ClientMessage, FromClient, FromServer, ServerMessage},
+    serverworld::BlockEdited,
+    transport::{ClientNet, ServerNet},
+    UserDataMap,
+};
+
+/// How far away a block can be broken or placed, from the eye.
+pub static REACH_DISTANCE: f32 = 6.0;
+/// Extra reach the server allows for the client being slightly ahead of it.
+pub static REACH_TOLERANCE: f32 = 1.5;
+/// The server accepts a break this much sooner than the block's break time, for latency.
+pub static BREAK_TIME_TOLERANCE: f64 = 0.15;
+
+static WATER: u32 = 2;
+
+/// Client side: hold left mouse to break, right click to place, scroll to pick a block.
+/// Edits apply immediately and are rolled back if the server refuses them.
+pub struct BlockEditPlugin;
+
+impl Plugin for BlockEditPlugin {
+    fn build(&self, app: &mut App) {
+        // Also registered here so editing works without a server.
+        app.add_event::<FromServer>()
+        .init_resource::<BlockEditing>()
+        .add_systems(Update, (choose_block, edit_blocks, receive_block_edits).chain())
+        ;
+    }
+}
+
+/// Server side: validates and applies edits from clients.
+pub struct BlockEditServerPlugin;
+
+impl Plugin for BlockEditServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BreakProgress>()
+        .add_systems(Update, handle_block_edits)
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct BlockEditing {
+    pub held_block: u32,
+    /// The block being broken and how long it's been held.
+    pub breaking: Option<(IVec3, f32)>,
+}
+
+impl Default for BlockEditing {
+    fn default() -> Self {
+        Self {
+            held_block: 10,
+            breaking: None,
+        }
+    }
+}
+
+/// When each client started breaking which block, by the server's clock.
+#[derive(Resource, Default)]
+pub struct BreakProgress(pub HashMap<ClientId, (IVec3, f64)>);
+
+/// Anything but air and water can be targeted, broken or built against.
+pub fn is_solid(block: u32) -> bool {
+    let id = block & Blocks::block_id_bits();
+    id != 0 && id != WATER
+}
+
+/// Walks the voxels along a ray and returns the first one `solid` accepts, with the
+/// normal of the face the ray entered through.
+pub fn raycast_blocks(origin: Vec3, direction: Vec3, max_distance: f32, mut solid: impl FnMut(IVec3) -> bool) -> Option<(IVec3, IVec3)> {
+    let dir = direction.normalize_or_zero();
+    if dir == Vec3::ZERO {
+        return None;
+    }
+
+    let mut spot = origin.floor().as_ivec3();
+    let step = dir.signum().as_ivec3();
+    let t_delta = Vec3::new(1.0 / dir.x.abs(), 1.0 / dir.y.abs(), 1.0 / dir.z.abs());
+    let boundary = |o: f32, s: i32, d: f32| {
+        if d > 0.0 {
+            (s as f32 + 1.0 - o) / d
+        } else if d < 0.0 {
+            (o - s as f32) / -d
+        } else {
+            f32::INFINITY
+        }
+    };
+    let mut t_max = Vec3::new(
+        boundary(origin.x, spot.x, dir.x),
+        boundary(origin.y, spot.y, dir.y),
+        boundary(origin.z, spot.z, dir.z),
+    );
+    let mut normal = IVec3::ZERO;
+    let mut t = 0.0;
+
+    while t <= max_distance {
+        if solid(spot) {
+            return Some((spot, normal));
+        }
+        if t_max.x < t_max.y && t_max.x < t_max.z {
+            spot.x += step.x;
+            t = t_max.x;
+            t_max.x += t_delta.x;
+            normal = IVec3::new(-step.x, 0, 0);
+        } else if t_max.y < t_max.z {
+            spot.y += step.y;
+            t = t_max.y;
+            t_max.y += t_delta.y;
+            normal = IVec3::new(0, -step.y, 0);
+        } else {
+            spot.z += step.z;
+            t = t_max.z;
+            t_max.z += t_delta.z;
+            normal = IVec3::new(0, 0, -step.z);
+        }
+    }
+    None
+}
+
+fn send_if_joined(net: &mut ClientNet, connection: &Option<Res<JConnection>>, message: ClientMessage) {
+    if let Some(connection) = connection.as_ref() {
+        if connection.state == JConnectionState::Joined {
+            send_to_server(net, &message);
+        }
+    }
+}
+
+pub fn choose_block(
+    mut wheel: EventReader<MouseWheel>,
+    mut editing: ResMut<BlockEditing>,
+    mut net: ClientNet,
+    connection: Option<Res<JConnection>>,
+) {
+    let count = Blocks::get_texs_length() as i32;
+
+    for event in wheel.read() {
+        let dir = if event.y > 0.0 { 1 } else if event.y < 0.0 { -1 } else { continue };
+        let mut block = editing.held_block as i32;
+        loop {
+            block = (block + dir).rem_euclid(count);
+            if block != 0 && block != WATER as i32 && !Blocks::is_non_placeable(block as u32) {
+                break;
+            }
+        }
+        editing.held_block = block as u32;
+        info!("Holding {}", Blocks::get_name(editing.held_block));
+        send_if_joined(&mut net, &connection, ClientMessage::HoldBlock { block: editing.held_block });
+    }
+}
+
+pub fn edit_blocks(
+    mut commands: Commands,
+    time: Res<Time>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    perlin: Res<JPerlin>,
+    mut udm: ResMut<UserDataMap>,
+    nudm: Res<NonUserDataMap>,
+    mut editing: ResMut<BlockEditing>,
+    mut net: ClientNet,
+    connection: Option<Res<JConnection>>,
+    eye: Query<&GlobalTransform, With<Camera3d>>,
+    me: Query<&Transform, With<KinematicCharacterController>>,
+) {
+    let (Ok(eye), Ok(me)) = (eye.get_single(), me.get_single()) else {
+        return;
+    };
+
+    let target = raycast_blocks(eye.translation(), *eye.forward(), REACH_DISTANCE, |spot| {
+        is_solid(blockat(&perlin.perlin, &udm, &nudm, &spot))
+    });
+
+    match (mouse.pressed(MouseButton::Left), target) {
+        (true, Some((spot, _))) => {
+            let elapsed = match editing.breaking {
+                Some((breaking, elapsed)) if breaking == spot => elapsed + time.delta_seconds(),
+                _ => {
+                    send_if_joined(&mut net, &connection, ClientMessage::StartBreaking { spot: spot.to_array() });
+                    0.0
+                }
+            };
+
+            let block = blockat(&perlin.perlin, &udm, &nudm, &spot) & Blocks::block_id_bits();
+            if elapsed >= Blocks::get_break_time(block) {
+                udm.insert(spot, 0);
+                rebuild_chunks_touching(&mut commands, &spot, &spot);
+                send_if_joined(&mut net, &connection, ClientMessage::BreakBlock { spot: spot.to_array() });
+                editing.breaking = None;
+            } else {
+                editing.breaking = Some((spot, elapsed));
+            }
+        }
+        _ => {
+            editing.breaking = None;
+        }
+    }
+
+    if mouse.just_pressed(MouseButton::Right) {
+        if let Some((spot, normal)) = target {
+            let place = spot + normal;
+            let existing = blockat(&perlin.perlin, &udm, &nudm, &place);
+
+            if normal != IVec3::ZERO
+                && !is_solid(existing)
+                && !Blocks::is_non_placeable(editing.held_block)
+                && !player_overlaps_block(me.translation, place)
+            {
+                udm.insert(place, editing.held_block);
+                rebuild_chunks_touching(&mut commands, &place, &place);
+                send_if_joined(&mut net, &connection, ClientMessage::PlaceBlock {
+                    spot: place.to_array(),
+                    block: editing.held_block,
+                });
+            }
+        }
+    }
+}
+
+pub fn receive_block_edits(
+    mut commands: Commands,
+    mut udm: ResMut<UserDataMap>,
+    mut editing: ResMut<BlockEditing>,
+    mut messages: EventReader<FromServer>,
+) {
+    for FromServer(message) in messages.read() {
+        let (spot, block) = match message {
+            ServerMessage::GiveBlock { block, count } => {
+                info!("Got {} {}", count, Blocks::get_name(*block));
+                editing.held_block = *block;
+                continue;
+            }
+            ServerMessage::BlockSet { spot, block } => (spot, block),
+            ServerMessage::BlockEditRejected { spot, block, reason } => {
+                info!("Edit at {:?} refused: {}", spot, reason);
+                (spot, block)
+            }
+            _ => continue,
+        };
+
+        let spot = IVec3::from_array(*spot);
+        udm.insert(spot, *block);
+        rebuild_chunks_touching(&mut commands, &spot, &spot);
+    }
+}
+
+/// Why an edit should be refused, or None if it's fine.
+fn check_reach(position: Vec3, spot: IVec3) -> Option<String> {
+    if spot.y < 0 || spot.y >= CH {
+        return Some(String::from("out of the world"));
+    }
+    let eye = position + Vec3::Y * EYE_HEIGHT;
+    if eye.distance(spot.as_vec3() + Vec3::splat(0.5)) > REACH_DISTANCE + REACH_TOLERANCE {
+        return Some(String::from("too far away"));
+    }
+    None
+}
+
+pub fn handle_block_edits(
+    time: Res<Time>,
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+    perlin: Res<JPerlin>,
+    mut udm: ResMut<UserDataMap>,
+    nudm: Res<NonUserDataMap>,
+    mut progress: ResMut<BreakProgress>,
+    mut messages: EventReader<FromClient>,
+    mut edited: EventWriter<BlockEdited>,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    for FromClient { client_id, message } in messages.read() {
+        let Some(player) = clients.players.get(client_id) else {
+            continue;
+        };
+
+        let (spot, result) = match message {
+            ClientMessage::StartBreaking { spot } => {
+                progress.0.insert(*client_id, (IVec3::from_array(*spot), now));
+                continue;
+            }
+            ClientMessage::BreakBlock { spot } => {
+                let spot = IVec3::from_array(*spot);
+                let existing = blockat(&perlin.perlin, &udm, &nudm, &spot);
+                let started = progress.0.remove(client_id).filter(|(s, _)| *s == spot).map(|(_, t)| t);
+
+                let refusal = check_reach(player.position, spot).or_else(|| {
+                    if !is_solid(existing) {
+                        return Some(String::from("nothing to break"));
+                    }
+                    let break_time = Blocks::get_break_time(existing & Blocks::block_id_bits()) as f64;
+                    match started {
+                        Some(t) if now - t >= break_time - BREAK_TIME_TOLERANCE => None,
+                        _ => Some(String::from("broken too quickly")),
+                    }
+                });
+                (spot, refusal.map(Err).unwrap_or(Ok(0)))
+            }
+            ClientMessage::PlaceBlock { spot, block } => {
+                let spot = IVec3::from_array(*spot);
+                let id = block & Blocks::block_id_bits();
+                let existing = blockat(&perlin.perlin, &udm, &nudm, &spot);
+
+                let refusal = check_reach(player.position, spot).or_else(|| {
+                    if id == 0 || id == WATER || id as usize >= Blocks::get_texs_length() || Blocks::is_non_placeable(id) {
+                        Some(String::from("can't place that"))
+                    } else if is_solid(existing) {
+                        Some(String::from("something is already there"))
+                    } else if clients.players.values().any(|p| player_overlaps_block(p.position, spot)) {
+                        Some(String::from("a player is in the way"))
+                    } else {
+                        None
+                    }
+                });
+                (spot, refusal.map(Err).unwrap_or(Ok(*block)))
+            }
+            _ => continue,
+        };
+
+        match result {
+            Ok(block) => {
+                udm.insert(spot, block);
+                edited.send(BlockEdited { spot, block });
+            }
+            Err(reason) => {
+                send_to_client(&mut net, *client_id, &ServerMessage::BlockEditRejected {
+                    spot: spot.to_array(),
+                    block: blockat(&perlin.perlin, &udm, &nudm, &spot),
+                    reason,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raycast_hits_the_first_solid_block_with_its_face() {
+        let wall = |spot: IVec3| spot.x == 5;
+        assert_eq!(raycast_blocks(Vec3::new(0.5, 0.5, 0.5), Vec3::X, 10.0, wall), Some((IVec3::new(5, 0, 0), IVec3::new(-1, 0, 0))));
+        assert_eq!(raycast_blocks(Vec3::new(9.5, 0.5, 0.5), -Vec3::X, 10.0, wall), Some((IVec3::new(5, 0, 0), IVec3::new(1, 0, 0))));
+
+        let floor = |spot: IVec3| spot.y < 0;
+        assert_eq!(raycast_blocks(Vec3::new(0.5, 3.2, 0.5), Vec3::new(0.3, -1.0, 0.2), 10.0, floor).map(|(spot, normal)| (spot.y, normal)), Some((-1, IVec3::Y)));
+    }
+
+    #[test]
+    fn raycast_walks_every_block_it_crosses() {
+        let mut visited = Vec::new();
+        let hit = raycast_blocks(Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, 1.0, 0.0), 3.0, |spot| {
+            visited.push(spot);
+            false
+        });
+        assert_eq!(hit, None);
+        for pair in visited.windows(2) {
+            let step = pair[1] - pair[0];
+            assert_eq!(step.abs().element_sum(), 1, "skipped from {} to {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn raycast_stops_at_max_distance() {
+        let wall = |spot: IVec3| spot.z == 5;
+        assert_eq!(raycast_blocks(Vec3::new(0.5, 0.5, 0.5), Vec3::Z, 3.0, wall), None);
+        // Starting inside a block hits it straight away, with no face.
+        assert_eq!(raycast_blocks(Vec3::new(0.5, 0.5, 5.5), Vec3::Z, 3.0, wall), Some((IVec3::new(0, 0, 5), IVec3::ZERO)));
+        assert_eq!(raycast_blocks(Vec3::ZERO, Vec3::ZERO, 3.0, wall), None);
+    }
+}
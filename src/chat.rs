@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    input::{keyboard::{Key, KeyboardInput}, ButtonState},
+    prelude::*,
+    utils::HashMap,
+};
+use bevy_quinnet::shared::ClientId;
+
+use crate::{
+    jclient::{JClientEvent, JConnection, JConnectionState},
+    jserver::{JServerClients, JServerSettings, PlayerJoined, PlayerLeft},
+    protocol::{send_to_client, send_to_clients, send_to_server, ClientMessage, FromClient, FromServer, ServerMessage},
+    transport::{ClientNet, ServerNet},
+    JControls,
+};
+
+pub static MAX_CHAT_LENGTH: usize = 256;
+pub static CHAT_HISTORY: usize = 200;
+pub static CHAT_VISIBLE_LINES: usize = 10;
+/// A player can send this many messages at once, then one per `CHAT_REFILL_SECONDS`.
+pub static CHAT_BURST: f32 = 5.0;
+pub static CHAT_REFILL_SECONDS: f32 = 1.0;
+
+/// Client side: the chat log and input box.
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<FromServer>()
+        .add_event::<JClientEvent>()
+        .init_resource::<Chat>()
+        .add_systems(Startup, spawn_chat_ui)
+        .add_systems(Update, (type_chat, receive_chat, show_connection_events, draw_chat).chain())
+        ;
+    }
+}
+
+/// Server side: fans chat out to everyone, with join and leave notices.
+pub struct ChatServerPlugin;
+
+impl Plugin for ChatServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatRateLimits>()
+        .add_event::<ChatCommand>()
+        .add_systems(Update, (announce_joins_and_leaves, relay_chat).chain())
+        ;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    pub sender: Option<String>,
+    pub text: String,
+}
+
+#[derive(Resource, Default)]
+pub struct Chat {
+    pub history: VecDeque<ChatLine>,
+    pub input: String,
+    pub open: bool,
+    /// Lines scrolled back from the newest.
+    pub scroll: usize,
+}
+
+impl Chat {
+    pub fn push(&mut self, line: ChatLine) {
+        match &line.sender {
+            Some(sender) => info!("<{}> {}", sender, line.text),
+            None => info!("{}", line.text),
+        }
+        self.history.push_back(line);
+        while self.history.len() > CHAT_HISTORY {
+            self.history.pop_front();
+        }
+    }
+}
+
+pub fn chat_closed(chat: Res<Chat>) -> bool {
+    !chat.open
+}
+
+#[derive(Component)]
+pub struct ChatLog;
+
+#[derive(Component)]
+pub struct ChatInput;
+
+/// A `/` line from a player, let through the same rate limit as chat, for `servercommands`.
+#[derive(Event, Debug, Clone)]
+pub struct ChatCommand {
+    pub client_id: ClientId,
+    pub text: String,
+}
+
+/// Per-client token buckets, shared by chat and commands.
+#[derive(Resource, Default)]
+pub struct ChatRateLimits(pub HashMap<ClientId, f32>);
+
+pub fn spawn_chat_ui(mut commands: Commands) {
+    let style = TextStyle {
+        font_size: 18.0,
+        color: Color::WHITE,
+        ..default()
+    };
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                bottom: Val::Px(10.0),
+                width: Val::Px(500.0),
+                flex_direction: FlexDirection::Column,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|b| {
+            b.spawn((TextBundle::from_section("", style.clone()), ChatLog));
+            b.spawn((
+                TextBundle::from_section("", style).with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.5)),
+                ChatInput,
+            ));
+        });
+}
+
+pub fn type_chat(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut keys: EventReader<KeyboardInput>,
+    mut chat: ResMut<Chat>,
+    mut controls: ResMut<JControls>,
+    mut net: ClientNet,
+    connection: Option<Res<JConnection>>,
+) {
+    if !chat.open {
+        keys.clear();
+        if keyboard.just_pressed(KeyCode::KeyT) || keyboard.just_pressed(KeyCode::Enter) || keyboard.just_pressed(KeyCode::Slash) {
+            chat.open = true;
+            chat.scroll = 0;
+            chat.input = if keyboard.just_pressed(KeyCode::Slash) { String::from("/") } else { String::new() };
+            // Don't keep walking while typing.
+            *controls = JControls::default();
+        }
+        return;
+    }
+
+    for key in keys.read() {
+        if key.state != ButtonState::Pressed {
+            continue;
+        }
+        match &key.logical_key {
+            Key::Character(c) if chat.input.chars().count() < MAX_CHAT_LENGTH => {
+                chat.input.push_str(c);
+            }
+            Key::Space if chat.input.chars().count() < MAX_CHAT_LENGTH => {
+                chat.input.push(' ');
+            }
+            Key::Backspace => {
+                chat.input.pop();
+            }
+            Key::PageUp => {
+                chat.scroll = (chat.scroll + CHAT_VISIBLE_LINES / 2).min(chat.history.len().saturating_sub(CHAT_VISIBLE_LINES));
+            }
+            Key::PageDown => {
+                chat.scroll = chat.scroll.saturating_sub(CHAT_VISIBLE_LINES / 2);
+            }
+            Key::Escape => {
+                chat.open = false;
+                chat.input.clear();
+            }
+            Key::Enter => {
+                let text = chat.input.trim().to_string();
+                chat.open = false;
+                chat.input.clear();
+                chat.scroll = 0;
+                if text.is_empty() {
+                    continue;
+                }
+
+                match connection.as_ref() {
+                    Some(connection) if connection.state == JConnectionState::Joined => {
+                        send_to_server(&mut net, &ClientMessage::ChatMessage { text });
+                    }
+                    _ => {
+                        chat.push(ChatLine { sender: None, text: String::from("Not connected to a server") });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn receive_chat(mut chat: ResMut<Chat>, mut messages: EventReader<FromServer>) {
+    for FromServer(message) in messages.read() {
+        if let ServerMessage::ChatMessage { sender, text } = message {
+            chat.push(ChatLine { sender: sender.clone(), text: text.clone() });
+            if chat.scroll > 0 {
+                // Stay on the lines being read while new ones arrive.
+                chat.scroll += 1;
+            }
+        }
+    }
+}
+
+/// Why we couldn't connect, were turned away or thrown out, where the player will see it.
+pub fn show_connection_events(mut chat: ResMut<Chat>, mut events: EventReader<JClientEvent>) {
+    for event in events.read() {
+        let text = match event {
+            JClientEvent::Rejected(reason) => format!("Couldn't join: {}", reason),
+            JClientEvent::Kicked(reason) => format!("Kicked: {}", reason),
+            JClientEvent::Disconnected => String::from("Disconnected from the server"),
+            JClientEvent::ConnectionFailed(reason) => format!("Couldn't connect: {}", reason),
+            _ => continue,
+        };
+        // Retries fail the same way every few seconds; once is enough to read.
+        if chat.history.back().map(|line| line.sender.is_none() && line.text == text).unwrap_or(false) {
+            continue;
+        }
+        chat.push(ChatLine { sender: None, text });
+    }
+}
+
+pub fn draw_chat(
+    chat: Res<Chat>,
+    mut log: Query<&mut Text, (With<ChatLog>, Without<ChatInput>)>,
+    mut input: Query<(&mut Text, &mut Visibility), With<ChatInput>>,
+) {
+    if !chat.is_changed() {
+        return;
+    }
+
+    if let Ok(mut text) = log.get_single_mut() {
+        let end = chat.history.len().saturating_sub(chat.scroll);
+        let start = end.saturating_sub(CHAT_VISIBLE_LINES);
+        text.sections[0].value = chat.history
+            .range(start..end)
+            .map(|line| match &line.sender {
+                Some(sender) => format!("<{}> {}", sender, line.text),
+                None => line.text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    if let Ok((mut text, mut visibility)) = input.get_single_mut() {
+        text.sections[0].value = format!("> {}_", chat.input);
+        *visibility = if chat.open { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
+
+pub fn broadcast_system_message(net: &mut ServerNet, clients: &JServerClients, text: String) {
+    info!("{}", text);
+    send_to_clients(net, clients.players.keys(), &ServerMessage::ChatMessage { sender: None, text });
+}
+
+pub fn announce_joins_and_leaves(
+    mut net: ServerNet,
+    settings: Res<JServerSettings>,
+    clients: Res<JServerClients>,
+    mut limits: ResMut<ChatRateLimits>,
+    mut joined: EventReader<PlayerJoined>,
+    mut left: EventReader<PlayerLeft>,
+) {
+    for event in joined.read() {
+        if let Some(player) = clients.players.get(&event.client_id) {
+            limits.0.insert(event.client_id, CHAT_BURST);
+            broadcast_system_message(&mut net, &clients, format!("{} joined the game", player.name));
+            if !settings.motd.is_empty() {
+                send_to_client(&mut net, event.client_id, &ServerMessage::ChatMessage {
+                    sender: None,
+                    text: settings.motd.clone(),
+                });
+            }
+        }
+    }
+    for event in left.read() {
+        limits.0.remove(&event.client_id);
+        broadcast_system_message(&mut net, &clients, format!("{} left the game", event.player.name));
+    }
+}
+
+pub fn relay_chat(
+    time: Res<Time>,
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+    mut limits: ResMut<ChatRateLimits>,
+    mut messages: EventReader<FromClient>,
+    mut commands: EventWriter<ChatCommand>,
+) {
+    for tokens in limits.0.values_mut() {
+        *tokens = (*tokens + time.delta_seconds() / CHAT_REFILL_SECONDS).min(CHAT_BURST);
+    }
+
+    for FromClient { client_id, message } in messages.read() {
+        let ClientMessage::ChatMessage { text } = message else {
+            continue;
+        };
+        let Some(player) = clients.players.get(client_id) else {
+            continue;
+        };
+
+        let tokens = limits.0.entry(*client_id).or_insert(CHAT_BURST);
+        if *tokens < 1.0 {
+            send_to_client(&mut net, *client_id, &ServerMessage::ChatMessage {
+                sender: None,
+                text: String::from("You're sending messages too quickly"),
+            });
+            continue;
+        }
+        *tokens -= 1.0;
+
+        // Commands are handled by `servercommands` and never shown to others.
+        if text.starts_with('/') {
+            commands.send(ChatCommand { client_id: *client_id, text: text.clone() });
+            continue;
+        }
+
+        let text: String = text.trim().chars().filter(|c| !c.is_control()).take(MAX_CHAT_LENGTH).collect();
+        if text.is_empty() {
+            continue;
+        }
+
+        info!("<{}> {}", player.name, text);
+        send_to_clients(&mut net, clients.players.keys(), &ServerMessage::ChatMessage {
+            sender: Some(player.name.clone()),
+            text,
+        });
+    }
+}
@@ -1,24 +1,38 @@
 use std::task::Poll;
 
-use bevy::{prelude::*, render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages}, tasks::{futures_lite::future, poll_once, AsyncComputeTaskPool, Task}, utils::HashMap};
+use bevy::{prelude::*, render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages}, tasks::{futures_lite::future, poll_once, AsyncComputeTaskPool, Task}, utils::{HashMap, HashSet}};
 use bevy_rapier3d::prelude::{Collider, KinematicCharacterController};
 use noise::{NoiseFn, Perlin};
 use num_enum::FromPrimitive;
 
-use crate::{blockinfo::Blocks, camera::JCamera, cube::{get_normal, Cube, CubeSide}, revindices::REV_INDS, ChunkSurveyTimer, JMyPlayer, MyHead, GOTTEN_SPOTS};
+use crate::{blockinfo::Blocks, camera::JCamera, city::{city_distance_at, city_ground_at, city_module_for_chunk, spawn_city_module, CITY_BLEND, CITY_GROUND, CITY_SLOPE}, cube::{get_normal, Cube, CubeSide}, revindices::REV_INDS, structure::{generate_region_dungeon, paste_structures, PasteStructure, StructureTemplates}, ChunkSurveyTimer, JMyPlayer, MyHead, UserDataMap, GOTTEN_SPOTS};
 
 
 pub static CW: i32 = 16;
 pub static CH: i32 = 256;
 
+/// Water level: natural water only exists below this height.
+pub static WL: f32 = 30.0;
+
+/// Chunks further than this from the player are despawned along with their city modules.
+pub static UNLOAD_DISTANCE: i32 = 10;
+
+/// Worldgen structures are planned per region of this many chunks on a side.
+pub static STRUCTURE_REGION_CHUNKS: i32 = 16;
+
 
 
 pub struct ChunkPlugin;
 
 impl Plugin for ChunkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, remesh_chunks)
+        app.init_resource::<UserDataMap>()
+        .init_resource::<NonUserDataMap>()
+        .init_resource::<StructureTemplates>()
+        .add_event::<PasteStructure>()
+        .add_systems(Update, remesh_chunks)
         .add_systems(Update, survey_chunks)
+        .add_systems(Update, paste_structures)
         ;
     }
 }
@@ -30,9 +44,31 @@ pub struct RebuildThisChunk;
 #[derive(Component)]
 struct MeshRebuildTask(Task<(Mesh, Collider)>);
 
+/// Blocks placed by worldgen structures, layered between player edits and natural terrain.
+/// Kept by chunk, like `UserDataMap`, so a box of the world is quick to look through.
+#[derive(Resource, Default)]
+pub struct NonUserDataMap {
+    pub chunks: HashMap<IVec2, HashMap<IVec3, u32>>,
+    pub generated_regions: HashSet<IVec2>,
+}
+
+impl NonUserDataMap {
+    pub fn get(&self, spot: &IVec3) -> Option<&u32> {
+        self.chunks.get(&spot_to_chunk_pos(spot))?.get(spot)
+    }
+
+    pub fn insert(&mut self, spot: IVec3, block: u32) {
+        self.chunks.entry(spot_to_chunk_pos(&spot)).or_default().insert(spot, block);
+    }
+
+    pub fn chunk(&self, cpos: &IVec2) -> Option<&HashMap<IVec3, u32>> {
+        self.chunks.get(cpos)
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct JPerlin {
-    perlin: Perlin
+    pub perlin: Perlin
 }
 
 pub fn spot_to_chunk_pos(spot: &IVec3) -> IVec2 {
@@ -42,10 +78,64 @@ pub fn spot_to_chunk_pos(spot: &IVec3) -> IVec2 {
     };
 }
 
+/// Natural terrain for a whole chunk, indexed by `chunk_block_index`.
+pub fn generate_chunk_blocks(perlin: &Perlin, cpos: &IVec2) -> Vec<u32> {
+    let mut blocks = Vec::with_capacity((CW * CW * CH) as usize);
+    for j in 0..CH {
+        for k in 0..CW {
+            for i in 0..CW {
+                blocks.push(natural_blockat(perlin, &IVec3::new(cpos.x * CW + i, j, cpos.y * CW + k)));
+            }
+        }
+    }
+    blocks
+}
+
+pub fn chunk_block_index(local: &IVec3) -> usize {
+    ((local.y * CW + local.z) * CW + local.x) as usize
+}
+
+/// The inverse of `chunk_block_index`.
+pub fn chunk_block_local(index: usize) -> IVec3 {
+    let index = index as i32;
+    IVec3::new(index % CW, index / (CW * CW), (index / CW) % CW)
+}
+
+pub fn rebuild_chunks_touching(commands: &mut Commands, min: &IVec3, max: &IVec3) {
+    // Faces on chunk borders depend on the neighbor too, so widen by one block.
+    let mincpos = spot_to_chunk_pos(&(*min - IVec3::ONE));
+    let maxcpos = spot_to_chunk_pos(&(*max + IVec3::ONE));
+
+    for x in mincpos.x..=maxcpos.x {
+        for z in mincpos.y..=maxcpos.y {
+            unsafe {
+                // The chunk may be on its way out, unloaded or regenerated for a new seed.
+                if let Some(mut chunk) = GOTTEN_SPOTS.get(&IVec2::new(x, z)).and_then(|chunkentity| commands.get_entity(*chunkentity)) {
+                    chunk.try_insert(RebuildThisChunk);
+                }
+            }
+        }
+    }
+}
+
+pub fn ensure_region_structures(perlin: &Perlin, templates: &StructureTemplates, nudm: &mut NonUserDataMap, cpos: &IVec2) {
+    let region = cpos.div_euclid(IVec2::splat(STRUCTURE_REGION_CHUNKS));
+
+    // Structures may spill into neighboring regions, so plan those too before meshing.
+    for i in -1..=1 {
+        for j in -1..=1 {
+            let r = region + IVec2::new(i, j);
+            if nudm.generated_regions.insert(r) {
+                generate_region_dungeon(perlin, &templates.dungeon_pool, &r, nudm);
+            }
+        }
+    }
+}
+
 pub fn survey_chunks(time: Res<Time>, mut timer: ResMut<ChunkSurveyTimer>, mut head: Query<(
     &mut Transform,
     
-), With<KinematicCharacterController>>, mut commands: Commands, asset_server: Res<AssetServer>, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
+), With<KinematicCharacterController>>, mut commands: Commands, asset_server: Res<AssetServer>, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>, perlin: Res<JPerlin>, templates: Res<StructureTemplates>, mut nudm: ResMut<NonUserDataMap>) {
     
     if timer.0.tick(time.delta()).just_finished() {
         let custom_texture_handle: Handle<Image> = asset_server.load("world.png");
@@ -59,10 +149,12 @@ pub fn survey_chunks(time: Res<Time>, mut timer: ResMut<ChunkSurveyTimer>, mut h
                 unsafe {
                     if !GOTTEN_SPOTS.contains_key(&cspot) {
 
+                        ensure_region_structures(&perlin.perlin, &templates, &mut nudm, &cspot);
+
                         let cube_mesh_handle: Handle<Mesh> = meshes.add(Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD));
 
 
-                        commands.spawn((
+                        let chunkentity = commands.spawn((
                             PbrBundle {
                                 mesh: cube_mesh_handle,
                                 material: materials.add(StandardMaterial {
@@ -74,20 +166,37 @@ pub fn survey_chunks(time: Res<Time>, mut timer: ResMut<ChunkSurveyTimer>, mut h
                             },
                             RebuildThisChunk,
                             Collider::halfspace(Vec3::Y).unwrap()
-                        )); 
+                        )).with_children(|b| {
+                            if let Some(module) = city_module_for_chunk(&perlin.perlin, &cspot) {
+                                spawn_city_module(b, &asset_server, &module, offset);
+                            }
+                        }).id(); 
 
-                        GOTTEN_SPOTS.insert(cspot, true);
+                        GOTTEN_SPOTS.insert(cspot, chunkentity);
                     }
                 }
 
             }
         }
+
+        unsafe {
+            let faraway: Vec<IVec2> = GOTTEN_SPOTS.iter()
+                .map(|entry| *entry.key())
+                .filter(|cspot| (*cspot - headcpos).abs().max_element() > UNLOAD_DISTANCE)
+                .collect();
+
+            for cspot in faraway {
+                if let Some((_, chunkentity)) = GOTTEN_SPOTS.remove(&cspot) {
+                    commands.entity(chunkentity).despawn_recursive();
+                }
+            }
+        }
     }
     
 }
 
 pub fn remesh_chunks(mut commands: Commands, mut chunks: Query<(Entity, &mut Handle<Mesh>, &Transform, &mut Collider), (With<RebuildThisChunk>, Without<MeshRebuildTask>)>,
- mut meshes: ResMut<Assets<Mesh>>, perlin: Res<JPerlin>) {
+ mut meshes: ResMut<Assets<Mesh>>, perlin: Res<JPerlin>, udm: Res<UserDataMap>, nudm: Res<NonUserDataMap>) {
     let task_pool = AsyncComputeTaskPool::get();
     
     let perlin = perlin.perlin;
@@ -96,170 +205,169 @@ pub fn remesh_chunks(mut commands: Commands, mut chunks: Query<(Entity, &mut Han
     
     for (entity, meshhandle, transform, mut collider) in chunks.iter() {
 
-        let mut memo: HashMap<IVec3, u32> = HashMap::new();
+        let chunkpos = spot_to_chunk_pos(&transform.translation.as_ivec3());
+        let mesh = build_chunk_mesh(&perlin, &udm, &nudm, &chunkpos);
 
-        let mut vertindex = 0;
+        let collider = Collider::from_bevy_mesh(&mesh, &bevy_rapier3d::prelude::ComputedColliderShape::TriMesh).unwrap();
 
-        let chunkpos = spot_to_chunk_pos(&transform.translation.as_ivec3());
+        if let Some(existing_mesh) = meshes.get_mut(meshhandle.id()) {
+            *existing_mesh = mesh;
+        }
+
+        commands
+        .entity(entity)
+        .try_insert(collider)
+        .remove::<RebuildThisChunk>();
 
-     
-            
         
-            let mut positions = Vec::new();
-            let mut uvs = Vec::new();
-            let mut normals = Vec::new();
-            let mut indices = Vec::new();
 
-            for i in 0..CW {
-                for k in 0..CW {
-    
-                    for j in (0..CH).rev() {
-                        let spot = IVec3 {
-                            x: (chunkpos.x * CW) + i,
-                            y: j,
-                            z: (chunkpos.y * CW) + k,
-                        };
-                        let combined = blockatmemo(&perlin, &spot, &mut memo);
-                        let block = combined & Blocks::block_id_bits();
-                        let flags = combined & Blocks::block_flag_bits();
-                        
+
+    }
+}
+
+/// The visible faces of a chunk, in chunk-local coordinates.
+pub fn build_chunk_mesh(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, chunkpos: &IVec2) -> Mesh {
+    let mut memo: HashMap<IVec3, u32> = HashMap::new();
+
+    let mut vertindex = 0;
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut indices = Vec::new();
+
+    for i in 0..CW {
+        for k in 0..CW {
     
-                        if block != 0 {
-                            // if !weatherstoptops.contains_key(&vec::IVec2 {
-                            //     x: i,
-                            //     y: k,
-                            // }) {
-                            //     weatherstoptops.insert(
-                            //         vec::IVec2 {
-                            //             x: i,
-                            //             y: k,
-                            //         },
-                            //         spot.y,
-                            //     );
-                            // }
-                            
+            for j in (0..CH).rev() {
+                let spot = IVec3 {
+                    x: (chunkpos.x * CW) + i,
+                    y: j,
+                    z: (chunkpos.y * CW) + k,
+                };
+                let combined = blockatmemo(perlin, udm, nudm, &spot, &mut memo);
+                let block = combined & Blocks::block_id_bits();
+                let flags = combined & Blocks::block_flag_bits();
+                
     
-                            
+                if block != 0 {
+                    // if !weatherstoptops.contains_key(&vec::IVec2 {
+                    //     x: i,
+                    //     y: k,
+                    // }) {
+                    //     weatherstoptops.insert(
+                    //         vec::IVec2 {
+                    //             x: i,
+                    //             y: k,
+                    //         },
+                    //         spot.y,
+                    //     );
+                    // }
+                    
     
+                    
     
-                                if Blocks::is_transparent(block) || Blocks::is_semi_transparent(block) || true {
-                                    for (indie, neigh) in Cube::get_neighbors().iter().enumerate() {
-                                        let neighspot = spot + *neigh;
-                                        let neigh_block = blockatmemo( &perlin, &neighspot, &mut memo)
-                                            & Blocks::block_id_bits();
-                                        let cubeside = CubeSide::from_primitive(indie);
-                                        let neigh_semi_trans = Blocks::is_semi_transparent(neigh_block);
-                                        let water_bordering_transparent = block == 2
-                                            && neigh_block != 2
-                                            && Blocks::is_transparent(neigh_block);
     
-                                        // let lmlock = self.lightmap.lock().unwrap();
+                        if Blocks::is_transparent(block) || Blocks::is_semi_transparent(block) || true {
+                            for (indie, neigh) in Cube::get_neighbors().iter().enumerate() {
+                                let neighspot = spot + *neigh;
+                                let neigh_block = blockatmemo(perlin, udm, nudm, &neighspot, &mut memo)
+                                    & Blocks::block_id_bits();
+                                let cubeside = CubeSide::from_primitive(indie);
+                                let neigh_semi_trans = Blocks::is_semi_transparent(neigh_block);
+                                let water_bordering_transparent = block == 2
+                                    && neigh_block != 2
+                                    && Blocks::is_transparent(neigh_block);
     
-                                        // let blocklighthere = match lmlock.get(&neighspot) {
-                                        //     Some(k) => k.sum(),
-                                        //     None => LightColor::ZERO,
-                                        // };
+                                // let lmlock = self.lightmap.lock().unwrap();
     
-                                        // if blocklighthere != 0 {
-                                        //     info!("Block light here: {}", blocklighthere);
-                                        // }
-                                        //drop(lmlock);
+                                // let blocklighthere = match lmlock.get(&neighspot) {
+                                //     Some(k) => k.sum(),
+                                //     None => LightColor::ZERO,
+                                // };
     
+                                // if blocklighthere != 0 {
+                                //     info!("Block light here: {}", blocklighthere);
+                                // }
+                                //drop(lmlock);
     
     
-                                        if neigh_block == 0
-                                            || neigh_semi_trans
-                                            || water_bordering_transparent
-                                        {
-                                            let side = Cube::get_side(cubeside);
     
+                                if neigh_block == 0
+                                    || neigh_semi_trans
+                                    || water_bordering_transparent
+                                {
+                                    let side = Cube::get_side(cubeside);
     
-                                            let texcoord = Blocks::get_tex_coords(block, cubeside);
-                                            let uvcoord = Blocks::get_uv_coords(*texcoord);
     
-                                            let normal = get_normal(cubeside);
+                                    let texcoord = Blocks::get_tex_coords(block, cubeside);
+                                    let uvcoord = Blocks::get_uv_coords(*texcoord);
     
+                                    let normal = get_normal(cubeside);
     
-                                            for (ind, v) in side.chunks(3).enumerate() {
-                                      
     
-                                                // let pack = PackedVertex::pack(
-                                                //     i as u8 + v[0],
-                                                //     j as u8 + v[1],
-                                                //     k as u8 + v[2],
-                                                //     ind as u8,
-                                                //     clamped_light,
-                                                //     0u8, //TEMPORARY UNUSED
-                                                //     texcoord.0,
-                                                //     texcoord.1,
-                                                // );
+                                    for (ind, v) in side.chunks(3).enumerate() {
+                              
     
-                                                positions.extend_from_slice(&[
-                                                    [(i + v[0] as i32) as f32, (j + v[1] as i32) as f32, (k + v[2] as i32) as f32 ]
-                                                ]);
-                                                uvs.extend_from_slice(&[
-                                                    [uvcoord[ind].0, uvcoord[ind].1]
-                                                ]);
-                                                
-                                                normals.extend_from_slice(&[
-                                                    [normal.x as f32, normal.y as f32, normal.z as f32]
-                                                ]);
-                                                
-                                                vertindex += 1;
+                                        // let pack = PackedVertex::pack(
+                                        //     i as u8 + v[0],
+                                        //     j as u8 + v[1],
+                                        //     k as u8 + v[2],
+                                        //     ind as u8,
+                                        //     clamped_light,
+                                        //     0u8, //TEMPORARY UNUSED
+                                        //     texcoord.0,
+                                        //     texcoord.1,
+                                        // );
     
-                                            }
+                                        positions.extend_from_slice(&[
+                                            [(i + v[0] as i32) as f32, (j + v[1] as i32) as f32, (k + v[2] as i32) as f32 ]
+                                        ]);
+                                        uvs.extend_from_slice(&[
+                                            [uvcoord[ind].0, uvcoord[ind].1]
+                                        ]);
+                                        
+                                        normals.extend_from_slice(&[
+                                            [normal.x as f32, normal.y as f32, normal.z as f32]
+                                        ]);
+                                        
+                                        vertindex += 1;
     
-                                        } else {
-                                            // tops.insert(
-                                            //     vec::IVec2 {
-                                            //         x: i + neigh.x,
-                                            //         y: k + neigh.z,
-                                            //     },
-                                            //     j + neigh.y,
-                                            // );
-                                        }
                                     }
+    
+                                } else {
+                                    // tops.insert(
+                                    //     vec::IVec2 {
+                                    //         x: i + neigh.x,
+                                    //         y: k + neigh.z,
+                                    //     },
+                                    //     j + neigh.y,
+                                    // );
                                 }
+                            }
+                        }
     
     
     
     
-                            
-                        }
-                    }
-    
                     
                 }
             }
-
-            indices.extend_from_slice(&REV_INDS[..vertindex]);
-
-            let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
-            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
-            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
-            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
-            .with_inserted_indices(Indices::U32(indices.iter().rev().map(|s| *s).collect::<Vec<_>>()));
-            
-            let collider = Collider::from_bevy_mesh(&mesh, &bevy_rapier3d::prelude::ComputedColliderShape::TriMesh).unwrap();
+    
             
-            // (mesh, collider)
-        
-            if let Some(existing_mesh) = meshes.get_mut(meshhandle.id()) {
-                *existing_mesh = mesh;
-            }
-
-            commands
-            .entity(entity)
-            .insert(collider)
-            .remove::<RebuildThisChunk>();
-
-        
+        }
+    }
 
+    indices.extend_from_slice(&REV_INDS[..vertindex]);
 
-    }
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_indices(Indices::U32(indices.iter().rev().map(|s| *s).collect::<Vec<_>>()))
 }
 
-pub fn blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
+pub fn blockat(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, spot: &IVec3) -> u32 {
     // if self.headless {
     //     if self.generated_chunks.contains_key(&ChunkSystem::spot_to_chunk_pos(&spot)) {
 
@@ -268,28 +376,26 @@ pub fn blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
     //     }
     // }
 
-    // match self.userdatamap.get(&spot) {
-    //     Some(id) => {
-    //         return *id;
-    //     }
-    //     None => {}
-    // }
-
-    // match self.nonuserdatamap.get(&spot) {
-    //     Some(id) => {
-    //         return *id;
-    //     }
-    //     None => return natural_blockat(perlin, spot),
-    // }
+    match udm.get(spot) {
+        Some(id) => {
+            return *id;
+        }
+        None => {}
+    }
 
-    return natural_blockat(perlin, &spot);
+    match nudm.get(spot) {
+        Some(id) => {
+            return *id;
+        }
+        None => return natural_blockat(perlin, spot),
+    }
 }
 
-pub fn blockatmemo(perlin: &Perlin, spot: &IVec3, memo: &mut HashMap<IVec3, u32>) -> u32 {
+pub fn blockatmemo(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, spot: &IVec3, memo: &mut HashMap<IVec3, u32>) -> u32 {
     return match memo.get(spot) {
         Some(b) => { *b },
         None => {
-            let b = blockat(perlin, &spot);
+            let b = blockat(perlin, udm, nudm, &spot);
             memo.insert(*spot, b);
             b
         },
@@ -313,6 +419,26 @@ pub fn biome_noise(perlin: &Perlin, spot: &IVec2) -> f64 {
     noise1
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Grassland,
+    /// Sand all the way down.
+    Desert,
+    /// Dead leaves on top.
+    Deadlands,
+}
+
+/// Which surface `natural_blockat` lays down in this column.
+pub fn biome_at(perlin: &Perlin, x: i32, z: i32) -> Biome {
+    if biome_noise(perlin, &IVec2 { x, y: z }) > 0.0 {
+        Biome::Desert
+    } else if biome_noise(perlin, &IVec2 { x: x * 20 + 5000, y: z * 20 + 5000 }) > 0.0 {
+        Biome::Deadlands
+    } else {
+        Biome::Grassland
+    }
+}
+
 pub fn ore_noise(perlin: &Perlin, spot: &IVec3) -> f64 {
     const XYZDIVISOR: f64 = 15.53;
 
@@ -365,17 +491,20 @@ pub fn natural_blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
         return 15;
     }
 
+    if let Some(ground) = city_ground_at(perlin, &IVec2::new(spot.x, spot.z)) {
+        return if spot.y < ground - 4 {
+            9
+        } else if spot.y < ground - 1 {
+            4
+        } else if spot.y < ground {
+            5
+        } else {
+            0
+        };
+    }
+    let city = city_distance_at(perlin, &IVec2::new(spot.x, spot.z));
+    let density = |spot: &IVec3| ground_density(perlin, spot, city);
 
-            static WL: f32 = 30.0;
-
-            let biomenum = biome_noise(perlin, &IVec2 {
-                x: spot.x,
-                y: spot.z,
-            });
-            let biomenum2 = biome_noise(perlin, &IVec2 {
-                x: spot.x * 20 + 5000,
-                y: spot.z * 20 + 5000,
-            });
 
             let mut underdirt = 5;
             let mut surface = 3;
@@ -383,20 +512,22 @@ pub fn natural_blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
             let mut liquid = 2;
             let mut beach = 1;
 
-            if biomenum > 0.0 {
-                underdirt = 1;
-                surface = 1;
-                undersurface = 1;
-                liquid = 2;
-                beach = 1;
-            } else {
-                if biomenum2 > 0.0 {
+            match biome_at(perlin, spot.x, spot.z) {
+                Biome::Desert => {
+                    underdirt = 1;
+                    surface = 1;
+                    undersurface = 1;
+                    liquid = 2;
+                    beach = 1;
+                }
+                Biome::Deadlands => {
                     surface = 34;
                 }
+                Biome::Grassland => {}
             }
 
-            let ret = if noise_func(perlin, &spot) > 10.0 {
-                if noise_func(perlin,&(*spot + IVec3 { x: 0, y: 10, z: 0 })) > 10.0 {
+            let ret = if density(spot) > 10.0 {
+                if density(&(*spot + IVec3 { x: 0, y: 10, z: 0 })) > 10.0 {
                     if ore_noise(perlin,&spot) > 1.0 {
                         35
                     } else {
@@ -406,9 +537,9 @@ pub fn natural_blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
 
                     let beachnoise = perlin.get([spot.y as f64/7.5, spot.z as f64/7.5, spot.x as f64/7.5]);
                     if spot.y > (WL + beachnoise as f32) as i32
-                    || noise_func(perlin,&(*spot + IVec3 { x: 0, y: 5, z: 0 })) > 10.0
+                    || density(&(*spot + IVec3 { x: 0, y: 5, z: 0 })) > 10.0
                     {
-                        if noise_func(perlin,&(*spot + IVec3 { x: 0, y: 1, z: 0 })) < 10.0 {
+                        if density(&(*spot + IVec3 { x: 0, y: 1, z: 0 })) < 10.0 {
                             surface
                         } else {
                             undersurface
@@ -432,12 +563,33 @@ pub fn natural_blockat(perlin: &Perlin, spot: &IVec3) -> u32 {
 
     if ret != 2 {
         if cave_noise(perlin, spot) > 0.5 {
-            return 0;
+            // Don't open a cave into the bottom of a body of water, or the water floats.
+            let water_above = spot.y + 1 < WL as i32
+                && density(&(*spot + IVec3 { x: 0, y: 1, z: 0 })) <= 10.0;
+            if !water_above {
+                return 0;
+            }
         }
     }
     ret
 }
 
+/// `noise_func` near a city, hemmed between a floor and a ceiling that meet at `CITY_GROUND` on
+/// its edge and spread apart with distance, so the land slopes into it instead of ending in a
+/// cliff. Solid where this is above 10, as with `noise_func`.
+fn ground_density(perlin: &Perlin, spot: &IVec3, city: f64) -> f64 {
+    if city < CITY_BLEND as f64 {
+        let height = (spot.y - CITY_GROUND) as f64;
+        if height >= city * CITY_SLOPE {
+            return 0.0;
+        }
+        if height < -city {
+            return 20.0;
+        }
+    }
+    noise_func(perlin, spot)
+}
+
 fn mix(a: f64, b: f64, t: f64) -> f64 {
     a * (1.0 - t) + b * t
 }
@@ -538,3 +690,141 @@ pub fn noise_func(perlin: &Perlin, spot: &IVec3) -> f64 {
     mix(noisemix + texture, noise3, p2.clamp(0.0, 1.0)).min(20.0) + p3
 }
 
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// FNV-1a, so the golden values don't depend on std's hasher.
+    fn hash_blocks(blocks: &[u32]) -> u64 {
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in blocks {
+            for byte in b.to_le_bytes() {
+                h ^= byte as u64;
+                h = h.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        h
+    }
+
+    // If a worldgen change is intentional, regenerate these and say so in the commit.
+    static GOLDEN: [(u32, IVec2, u64); 7] = [
+        (0, IVec2::new(0, 0), 0xdcc3a382b6a768a4), // sloping up from a city
+        (0, IVec2::new(-3, 5), 0x53991163e1f3c907),
+        (0, IVec2::new(40, -17), 0x481442191b71db25), // ocean
+        (0, IVec2::new(80, -70), 0xfa4980270e15ab25), // city
+        (1234, IVec2::new(0, 0), 0x0dad1ab91fcbf977),
+        (1234, IVec2::new(40, -17), 0x55cc524527bd6175),
+        (0, IVec2::new(-560, -987), 0x9bb5b8d2936687e7), // cave under a lake
+    ];
+
+    fn random_column(rng: &mut StdRng) -> (i32, i32) {
+        (rng.gen_range(-20000..20000), rng.gen_range(-20000..20000))
+    }
+
+    #[test]
+    fn golden_chunks() {
+        for (seed, cpos, expected) in GOLDEN.iter() {
+            let perlin = Perlin::new(*seed);
+            let hash = hash_blocks(&generate_chunk_blocks(&perlin, cpos));
+            assert_eq!(hash, *expected, "seed {} chunk {:?} hashed to {:#018x}", seed, cpos, hash);
+        }
+    }
+
+    #[test]
+    fn generation_is_repeatable() {
+        let perlin = Perlin::new(99);
+        let cpos = IVec2::new(-11, 23);
+        assert_eq!(generate_chunk_blocks(&perlin, &cpos), generate_chunk_blocks(&perlin, &cpos));
+    }
+
+    #[test]
+    fn bedrock_at_zero() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for seed in [0, 1234] {
+            let perlin = Perlin::new(seed);
+            for _ in 0..500 {
+                let (x, z) = random_column(&mut rng);
+                assert_eq!(natural_blockat(&perlin, &IVec3::new(x, 0, z)), 15, "no bedrock at {} 0 {}", x, z);
+            }
+        }
+    }
+
+    #[test]
+    fn water_only_below_water_level() {
+        let perlin = Perlin::default();
+        let blocks = generate_chunk_blocks(&perlin, &IVec2::new(40, -17));
+        for j in 0..CH {
+            for k in 0..CW {
+                for i in 0..CW {
+                    if blocks[chunk_block_index(&IVec3::new(i, j, k))] == 2 {
+                        assert!(j < WL as i32, "water at y {}", j);
+                    }
+                }
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..2000 {
+            let (x, z) = random_column(&mut rng);
+            for y in WL as i32..WL as i32 + 8 {
+                assert_ne!(natural_blockat(&perlin, &IVec3::new(x, y, z)), 2, "water at {} {} {}", x, y, z);
+            }
+        }
+    }
+
+    #[test]
+    fn no_floating_water() {
+        let perlin = Perlin::default();
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..5000 {
+            let (x, z) = random_column(&mut rng);
+            let mut below = natural_blockat(&perlin, &IVec3::new(x, 0, z));
+            for y in 1..WL as i32 {
+                let block = natural_blockat(&perlin, &IVec3::new(x, y, z));
+                if block == 2 {
+                    assert_ne!(below, 0, "water over air at {} {} {}", x, y, z);
+                }
+                below = block;
+            }
+        }
+    }
+
+    fn ground_height(perlin: &Perlin, x: i32, z: i32) -> i32 {
+        let city = city_distance_at(perlin, &IVec2::new(x, z));
+        (0..CH).rev().find(|y| ground_density(perlin, &IVec3::new(x, *y, z), city) > 10.0).unwrap()
+    }
+
+    #[test]
+    fn no_cliff_at_city_edge() {
+        use crate::city::{is_city_region, MODULE_SIZE, REGION_CELLS};
+
+        let regionblocks = REGION_CELLS * MODULE_SIZE;
+        for seed in [0, 1234] {
+            let perlin = Perlin::new(seed);
+            // A city with open country to its east.
+            let region = (-20..20)
+                .flat_map(|i| (-20..20).map(move |j| IVec2::new(i, j)))
+                .find(|r| is_city_region(&perlin, r) && !is_city_region(&perlin, &(*r + IVec2::X)))
+                .unwrap();
+            let edge = (region.x + 1) * regionblocks;
+            for step in 1..8 {
+                let z = region.y * regionblocks + step * regionblocks / 8;
+                assert_eq!(ground_height(&perlin, edge - 1, z), CITY_GROUND - 1);
+                // The ground may only stray from the city's as fast as the slope allows.
+                for x in edge..edge + CITY_BLEND {
+                    let away = (x - edge + 1) as f64;
+                    let h = ground_height(&perlin, x, z);
+                    let above = (h - CITY_GROUND) as f64;
+                    assert!(
+                        above < away * CITY_SLOPE && above >= -away - 1.0,
+                        "seed {} ground at {} {} is {} blocks from a city but {} high",
+                        seed, x, z, away, h
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy_quinnet::shared::ClientId;
+use noise::{Perlin, Seedable};
+
+use crate::{
+    chunk::{chunk_block_index, chunk_block_local, rebuild_chunks_touching, spot_to_chunk_pos, JPerlin, NonUserDataMap, CH, CW},
+    jserver::{JServerClients, JServerSettings, PlayerJoined, PlayerLeft},
+    protocol::{encode, send_to_client, send_to_clients, FromServer, ServerMessage},
+    serverworld::{BlockEdited, BlocksChanged},
+    transport::ServerNet,
+    UserDataMap, GOTTEN_SPOTS,
+};
+
+/// Per-client cap on chunk data; the rest waits for the next tick.
+pub static CHUNK_STREAM_BYTES_PER_SECOND: f32 = 256.0 * 1024.0;
+pub static MAX_CHUNKS_PER_TICK: usize = 16;
+
+/// Server side: sends each player the edits in the chunks around them.
+/// Clients generate the base terrain themselves from the seed in `WorldInfo`.
+pub struct ChunkStreamPlugin;
+
+impl Plugin for ChunkStreamPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChunkStreams>()
+        .add_systems(Update, (start_streams, end_streams, invalidate_changed_chunks, send_block_edits, stream_chunks).chain())
+        ;
+    }
+}
+
+/// Client side: applies streamed edits over the locally generated terrain.
+pub struct ChunkSyncPlugin;
+
+impl Plugin for ChunkSyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, receive_chunks);
+    }
+}
+
+#[derive(Default)]
+pub struct ClientChunkStream {
+    /// Chunks this client has an up to date copy of.
+    pub sent: HashSet<IVec2>,
+    /// Bytes it may still be sent; refills at `CHUNK_STREAM_BYTES_PER_SECOND`.
+    pub budget: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct ChunkStreams {
+    pub clients: HashMap<ClientId, ClientChunkStream>,
+}
+
+pub fn start_streams(
+    mut net: ServerNet,
+    perlin: Res<JPerlin>,
+    mut streams: ResMut<ChunkStreams>,
+    mut joined: EventReader<PlayerJoined>,
+) {
+    for event in joined.read() {
+        streams.clients.insert(event.client_id, ClientChunkStream::default());
+        send_to_client(&mut net, event.client_id, &ServerMessage::WorldInfo {
+            seed: perlin.perlin.seed(),
+        });
+    }
+}
+
+pub fn end_streams(mut streams: ResMut<ChunkStreams>, mut left: EventReader<PlayerLeft>) {
+    for event in left.read() {
+        streams.clients.remove(&event.client_id);
+    }
+}
+
+pub fn invalidate_changed_chunks(mut streams: ResMut<ChunkStreams>, mut changes: EventReader<BlocksChanged>) {
+    for change in changes.read() {
+        let mincpos = spot_to_chunk_pos(&change.min);
+        let maxcpos = spot_to_chunk_pos(&change.max);
+
+        for stream in streams.clients.values_mut() {
+            stream.sent.retain(|cpos| {
+                cpos.x < mincpos.x || cpos.x > maxcpos.x || cpos.y < mincpos.y || cpos.y > maxcpos.y
+            });
+        }
+    }
+}
+
+/// Single edits go straight to whoever already has the chunk; the rest get them with it.
+pub fn send_block_edits(mut net: ServerNet, streams: Res<ChunkStreams>, mut edits: EventReader<BlockEdited>) {
+    for edit in edits.read() {
+        let cpos = spot_to_chunk_pos(&edit.spot);
+        let holders = streams.clients.iter().filter(|(_, stream)| stream.sent.contains(&cpos)).map(|(id, _)| id);
+        send_to_clients(&mut net, holders, &ServerMessage::BlockSet {
+            spot: edit.spot.to_array(),
+            block: edit.block,
+        });
+    }
+}
+
+pub fn stream_chunks(
+    time: Res<Time>,
+    settings: Res<JServerSettings>,
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+    udm: Res<UserDataMap>,
+    mut streams: ResMut<ChunkStreams>,
+) {
+    // What each client is missing, nearest first.
+    let mut wanted: Vec<(ClientId, Vec<IVec2>)> = Vec::new();
+    for (client_id, stream) in streams.clients.iter_mut() {
+        stream.budget = (stream.budget + time.delta_seconds() * CHUNK_STREAM_BYTES_PER_SECOND).min(CHUNK_STREAM_BYTES_PER_SECOND);
+
+        let Some(player) = clients.players.get(client_id) else {
+            continue;
+        };
+        let headcpos = spot_to_chunk_pos(&player.position.floor().as_ivec3());
+
+        // Forget what they've surely unloaded, so it's sent again if they come back.
+        stream.sent.retain(|cpos| (*cpos - headcpos).abs().max_element() <= settings.unload_distance());
+
+        if stream.budget <= 0.0 {
+            continue;
+        }
+
+        let mut missing = Vec::new();
+        let view = settings.view_distance;
+        for i in -view..=view {
+            for j in -view..=view {
+                let cpos = headcpos + IVec2::new(i, j);
+                if !stream.sent.contains(&cpos) {
+                    missing.push(cpos);
+                }
+            }
+        }
+        missing.sort_by_key(|cpos| (*cpos - headcpos).abs().max_element());
+        missing.truncate(MAX_CHUNKS_PER_TICK);
+
+        if !missing.is_empty() {
+            wanted.push((*client_id, missing));
+        }
+    }
+
+    if wanted.is_empty() {
+        return;
+    }
+
+    for (client_id, cposes) in wanted {
+        let stream = streams.clients.get_mut(&client_id).unwrap();
+
+        for cpos in cposes {
+            if stream.budget <= 0.0 {
+                break;
+            }
+
+            let origin = IVec3::new(cpos.x * CW, 0, cpos.y * CW);
+            let edits = udm.chunk(&cpos).map(|edits| edits.iter()
+                .filter(|(spot, _)| spot.y >= 0 && spot.y < CH)
+                .map(|(spot, block)| (chunk_block_index(&(*spot - origin)) as u16, *block))
+                .collect());
+            let message = ServerMessage::ChunkEdits {
+                cpos: cpos.to_array(),
+                edits: edits.unwrap_or_default(),
+            };
+            let payload = encode(&message);
+            stream.budget -= payload.len() as f32;
+
+            if let Err(e) = net.send(client_id, message.channel(), payload) {
+                warn!("Couldn't send chunk {} to client {}: {}", cpos, client_id, e);
+                break;
+            }
+            stream.sent.insert(cpos);
+        }
+    }
+}
+
+pub fn receive_chunks(
+    mut commands: Commands,
+    mut perlin: ResMut<JPerlin>,
+    mut udm: ResMut<UserDataMap>,
+    mut nudm: ResMut<NonUserDataMap>,
+    mut messages: EventReader<FromServer>,
+) {
+    for FromServer(message) in messages.read() {
+        match message {
+            ServerMessage::WorldInfo { seed } => {
+                // The server is the only source of edits now.
+                udm.chunks.clear();
+
+                if perlin.perlin.seed() != *seed {
+                    info!("World seed is {}", seed);
+                    perlin.perlin = Perlin::new(*seed);
+                    *nudm = NonUserDataMap::default();
+
+                    // Everything we generated so far was from the wrong seed.
+                    unsafe {
+                        for entry in GOTTEN_SPOTS.iter() {
+                            commands.entity(*entry.value()).despawn_recursive();
+                        }
+                        GOTTEN_SPOTS.clear();
+                    }
+                }
+            }
+            ServerMessage::ChunkEdits { cpos, edits } => {
+                let cpos = IVec2::from_array(*cpos);
+                let origin = IVec3::new(cpos.x * CW, 0, cpos.y * CW);
+
+                let edits: HashMap<IVec3, u32> = edits.iter()
+                    .map(|(index, block)| (origin + chunk_block_local(*index as usize), *block))
+                    .collect();
+                udm.chunks.insert(cpos, edits);
+
+                rebuild_chunks_touching(&mut commands, &origin, &(origin + IVec3::new(CW - 1, CH - 1, CW - 1)));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::{AsyncSceneCollider, Collider, ComputedColliderShape};
+use noise::{Perlin, Seedable};
+
+use crate::{chunk::{spot_to_chunk_pos, CW}, voxelize::for_each_gltf_triangle};
+
+/// Every city/street glTF module is a 100x100 tile centered on its origin.
+pub const MODULE_SIZE: i32 = 100;
+/// A city region is REGION_CELLS x REGION_CELLS modules.
+pub const REGION_CELLS: i32 = 6;
+/// Every STREET_SPACING'th row and column of a region is a street.
+pub const STREET_SPACING: i32 = 3;
+/// One in this many regions is a city.
+pub const CITY_RARITY: u32 = 4;
+/// Terrain under a city is flattened so its surface sits at this height.
+pub const CITY_GROUND: i32 = 40;
+/// Terrain this many blocks around a city slopes to meet its ground, rather than ending in a cliff.
+pub const CITY_BLEND: i32 = 96;
+/// How many blocks the ground around a city may rise above its ground per block away from it.
+pub const CITY_SLOPE: f64 = 1.5;
+
+static BUILDINGS: [&str; 5] = [
+    "models/city1.gltf",
+    "models/city2.gltf",
+    "models/city3.gltf",
+    "models/city4.gltf",
+    "models/cityblank.gltf",
+];
+
+static STREETS: [&str; 2] = [
+    "models/street1.gltf",
+    "models/street2.gltf",
+];
+
+static INTERSECTION: &str = "models/street3.gltf";
+
+#[derive(Component)]
+pub struct CityModuleMarker;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CityModule {
+    pub cell: IVec2,
+    pub asset: &'static str,
+    pub rotation: f32,
+}
+
+impl CityModule {
+    pub fn center(&self) -> IVec2 {
+        self.cell * MODULE_SIZE + IVec2::splat(MODULE_SIZE / 2)
+    }
+}
+
+pub fn cell_hash(seed: u32, x: i32, z: i32) -> u32 {
+    let mut h = (seed as u64) ^ 0x9E37_79B9_7F4A_7C15;
+    h ^= ((x as u32 as u64) << 32) | (z as u32 as u64);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (h ^ (h >> 31)) as u32
+}
+
+pub fn spot_to_cell(spot: &IVec2) -> IVec2 {
+    spot.div_euclid(IVec2::splat(MODULE_SIZE))
+}
+
+pub fn is_city_region(perlin: &Perlin, region: &IVec2) -> bool {
+    cell_hash(perlin.seed(), region.x, region.y).is_multiple_of(CITY_RARITY)
+}
+
+pub fn city_module_at(perlin: &Perlin, cell: &IVec2) -> Option<CityModule> {
+    let region = cell.div_euclid(IVec2::splat(REGION_CELLS));
+    if !is_city_region(perlin, &region) {
+        return None;
+    }
+
+    let local = cell.rem_euclid(IVec2::splat(REGION_CELLS));
+    let street_x = local.y % STREET_SPACING == 0;
+    let street_z = local.x % STREET_SPACING == 0;
+
+    let h = cell_hash(perlin.seed().wrapping_add(1), cell.x, cell.y);
+
+    let (asset, rotation) = match (street_x, street_z) {
+        (true, true) => (INTERSECTION, 0.0),
+        (true, false) => (STREETS[(h % STREETS.len() as u32) as usize], 0.0),
+        (false, true) => (STREETS[(h % STREETS.len() as u32) as usize], FRAC_PI_2),
+        (false, false) => (
+            BUILDINGS[(h % BUILDINGS.len() as u32) as usize],
+            ((h >> 8) % 4) as f32 * FRAC_PI_2,
+        ),
+    };
+
+    Some(CityModule {
+        cell: *cell,
+        asset,
+        rotation,
+    })
+}
+
+/// The flattened surface height at this column, if it lies under a city.
+pub fn city_ground_at(perlin: &Perlin, spot: &IVec2) -> Option<i32> {
+    let region = spot_to_cell(spot).div_euclid(IVec2::splat(REGION_CELLS));
+    if is_city_region(perlin, &region) {
+        Some(CITY_GROUND)
+    } else {
+        None
+    }
+}
+
+/// How far this column is from the nearest city, in blocks; 0 under one. Only cities in the
+/// neighboring regions are looked for, since regions are much wider than `CITY_BLEND`.
+pub fn city_distance_at(perlin: &Perlin, spot: &IVec2) -> f64 {
+    let regionblocks = REGION_CELLS * MODULE_SIZE;
+    let region = spot_to_cell(spot).div_euclid(IVec2::splat(REGION_CELLS));
+    let mut nearest = f64::INFINITY;
+    for i in -1..=1 {
+        for j in -1..=1 {
+            let r = region + IVec2::new(i, j);
+            if !is_city_region(perlin, &r) {
+                continue;
+            }
+            let min = r * regionblocks;
+            let max = min + IVec2::splat(regionblocks - 1);
+            let outside = (min - *spot).max(*spot - max).max(IVec2::ZERO);
+            nearest = nearest.min(outside.as_dvec2().length());
+        }
+    }
+    nearest
+}
+
+/// Modules are owned by the chunk their center falls in, so they stream in and out with it.
+pub fn city_module_for_chunk(perlin: &Perlin, cpos: &IVec2) -> Option<CityModule> {
+    let chunkcenter = *cpos * CW + IVec2::splat(CW / 2);
+    let module = city_module_at(perlin, &spot_to_cell(&chunkcenter))?;
+    let center = module.center();
+
+    if spot_to_chunk_pos(&IVec3::new(center.x, 0, center.y)) == *cpos {
+        Some(module)
+    } else {
+        None
+    }
+}
+
+/// Where a module sits relative to the chunk that owns it.
+pub fn module_transform(module: &CityModule, chunkoffset: Vec3) -> Transform {
+    let center = module.center();
+    let local = Vec3::new(center.x as f32, CITY_GROUND as f32 + 0.05, center.y as f32) - chunkoffset;
+    Transform::from_translation(local).with_rotation(Quat::from_rotation_y(module.rotation))
+}
+
+pub fn spawn_city_module(b: &mut ChildBuilder, asset_server: &AssetServer, module: &CityModule, chunkoffset: Vec3) {
+    b.spawn((
+        SceneBundle {
+            scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset(module.asset)),
+            transform: module_transform(module, chunkoffset),
+            ..default()
+        },
+        AsyncSceneCollider {
+            shape: Some(ComputedColliderShape::TriMesh),
+            ..default()
+        },
+        CityModuleMarker,
+    ));
+}
+
+/// A trimesh of the module read straight from its glTF file, for the headless server,
+/// which has no scene spawner to build `AsyncSceneCollider`s from.
+pub fn load_module_collider(asset: &str) -> Option<Collider> {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+
+    let path = format!("assets/{}", asset);
+    if let Err(e) = for_each_gltf_triangle(&path, |tri| {
+        let base = vertices.len() as u32;
+        vertices.extend_from_slice(&tri.positions);
+        indices.push([base, base + 1, base + 2]);
+    }) {
+        warn!("Couldn't load {} for its collider: {}", path, e);
+        return None;
+    }
+
+    if indices.is_empty() {
+        None
+    } else {
+        Some(Collider::trimesh(vertices, indices))
+    }
+}
@@ -0,0 +1,205 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    identity::IDENTITY_FILE,
+    serverconfig::{ConfigError, ServerConfig, DEFAULT_CONFIG_FILE},
+};
+
+/// Running with no subcommand starts the client offline.
+#[derive(Parser, Debug)]
+#[command(version, about = "Distant Garden")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Play, on a server or offline.
+    Client(ClientArgs),
+    /// Run a dedicated server.
+    Server(ServerArgs),
+    /// Play a local world with its own server.
+    Singleplayer(SingleplayerArgs),
+    /// List servers on the local network.
+    Lan(LanArgs),
+    /// Ask a running server how it's doing.
+    Status(StatusArgs),
+    /// Play a traffic recording back into a headless client or server.
+    Replay(ReplayArgs),
+    /// Render a top-down map of generated terrain to a png.
+    WorldgenMap(WorldgenMapArgs),
+    /// Turn a glTF model into a structure file.
+    Voxelize(VoxelizeArgs),
+    /// Time terrain generation and meshing.
+    Bench(BenchArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ClientArgs {
+    /// Server to join, as host:port or just host.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub connect: Option<String>,
+    /// Name shown to other players: up to 16 letters, digits and underscores.
+    #[arg(long, default_value = "Player")]
+    pub name: String,
+    /// Where to keep this player's identity; use another file to play as someone else.
+    #[arg(long, default_value = IDENTITY_FILE)]
+    pub identity: PathBuf,
+    /// Write every message to and from the server to this file.
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+}
+
+impl Default for ClientArgs {
+    fn default() -> Self {
+        Self {
+            connect: None,
+            name: String::from("Player"),
+            identity: PathBuf::from(IDENTITY_FILE),
+            record: None,
+        }
+    }
+}
+
+/// Settings come from the config file, then any of these that are given.
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    #[arg(long, default_value = DEFAULT_CONFIG_FILE)]
+    pub config: PathBuf,
+    #[arg(long)]
+    pub name: Option<String>,
+    #[arg(long)]
+    pub bind_address: Option<IpAddr>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub status_port: Option<u16>,
+    #[arg(long)]
+    pub world: Option<PathBuf>,
+    #[arg(long)]
+    pub seed: Option<u32>,
+    #[arg(long)]
+    pub view_distance: Option<i32>,
+    #[arg(long)]
+    pub tick_rate: Option<f64>,
+    #[arg(long)]
+    pub motd: Option<String>,
+    #[arg(long)]
+    pub max_players: Option<usize>,
+    #[arg(long)]
+    pub whitelist: Option<bool>,
+    #[arg(long)]
+    pub lan_announce: Option<bool>,
+    /// Write every message to and from clients to this file.
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    pub fn config(&self) -> Result<ServerConfig, ConfigError> {
+        let mut config = ServerConfig::load(&self.config)?;
+        let overrides = [
+            ("name", self.name.clone()),
+            ("bind_address", self.bind_address.map(|v| v.to_string())),
+            ("port", self.port.map(|v| v.to_string())),
+            ("status_port", self.status_port.map(|v| v.to_string())),
+            ("world", self.world.as_ref().map(|v| v.display().to_string())),
+            ("seed", self.seed.map(|v| v.to_string())),
+            ("view_distance", self.view_distance.map(|v| v.to_string())),
+            ("tick_rate", self.tick_rate.map(|v| v.to_string())),
+            ("motd", self.motd.clone()),
+            ("max_players", self.max_players.map(|v| v.to_string())),
+            ("whitelist", self.whitelist.map(|v| v.to_string())),
+            ("lan_announce", self.lan_announce.map(|v| v.to_string())),
+        ];
+        for (key, value) in overrides {
+            if let Some(value) = value {
+                config.set(key, &value)?;
+            }
+        }
+        // Only now, so overrides that only make sense together, like moving both ports, work.
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SingleplayerArgs {
+    /// The world directory, created if it doesn't exist.
+    #[arg(long, default_value = "world")]
+    pub world: PathBuf,
+    /// Seed for a new world.
+    #[arg(long)]
+    pub seed: Option<u32>,
+    /// Port the world listens on once it's opened with `/lan`.
+    #[arg(long, default_value_t = 6000)]
+    pub port: u16,
+    #[arg(long, default_value = "Player")]
+    pub name: String,
+    #[arg(long, default_value = IDENTITY_FILE)]
+    pub identity: PathBuf,
+    /// Write every message to and from the world's server to this file.
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct LanArgs {
+    /// How long to listen for announcements.
+    #[arg(long, default_value_t = 3.0)]
+    pub seconds: f64,
+}
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    /// The server's status port, 6001 unless it's been changed.
+    #[arg(value_name = "HOST:PORT")]
+    pub address: String,
+}
+
+/// A client recording replays into a client, a server recording into a server started from a
+/// fresh copy of the world it recorded, with the config it was running.
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// A file written with `--record`.
+    pub recording: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct WorldgenMapArgs {
+    /// Block x of the map's top left corner.
+    #[arg(long, allow_negative_numbers = true, default_value_t = 0)]
+    pub x: i32,
+    /// Block z of the map's top left corner.
+    #[arg(long, allow_negative_numbers = true, default_value_t = 0)]
+    pub z: i32,
+    #[arg(long, default_value_t = 512)]
+    pub width: u32,
+    #[arg(long, default_value_t = 512)]
+    pub depth: u32,
+    #[arg(long)]
+    pub seed: Option<u32>,
+    /// The png to write.
+    pub out: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct VoxelizeArgs {
+    pub input: PathBuf,
+    /// The .jstruct to write.
+    pub output: PathBuf,
+    #[arg(long, default_value_t = 1.0)]
+    pub blocks_per_unit: f32,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Chunks to generate and mesh, in a square around the origin.
+    #[arg(long, default_value_t = 64)]
+    pub chunks: usize,
+    #[arg(long)]
+    pub seed: Option<u32>,
+}
@@ -0,0 +1,53 @@
+use std::f64::consts::TAU;
+
+use bevy::prelude::*;
+
+use crate::{protocol::{FromServer, ServerMessage}, serverworld::DAY_LENGTH};
+
+pub static DAY_BRIGHTNESS: f32 = 2000.0;
+pub static NIGHT_BRIGHTNESS: f32 = 150.0;
+
+/// Client side: follows the server's time of day. Offline it stays day.
+pub struct DaylightPlugin;
+
+impl Plugin for DaylightPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<FromServer>()
+        .init_resource::<ClientTime>()
+        .add_systems(Update, (receive_time, apply_daylight).chain())
+        ;
+    }
+}
+
+/// Seconds into the day, once the server has told us.
+#[derive(Resource, Default)]
+pub struct ClientTime {
+    pub time: Option<f64>,
+}
+
+/// 0 at night, 1 in the day, with a short dawn and dusk around sunrise (time 0) and sunset.
+pub fn daylight(time: f64) -> f32 {
+    let sun = (time / DAY_LENGTH * TAU).sin() as f32;
+    (sun * 3.0 + 0.5).clamp(0.0, 1.0)
+}
+
+pub fn receive_time(time: Res<Time>, mut clock: ResMut<ClientTime>, mut messages: EventReader<FromServer>) {
+    if let Some(t) = clock.time.as_mut() {
+        *t = (*t + time.delta_seconds_f64()).rem_euclid(DAY_LENGTH);
+    }
+    for FromServer(message) in messages.read() {
+        if let ServerMessage::TimeOfDay { time } = message {
+            clock.time = Some(*time);
+        }
+    }
+}
+
+pub fn apply_daylight(clock: Res<ClientTime>, mut ambient: ResMut<AmbientLight>, mut clear: ResMut<ClearColor>) {
+    let Some(time) = clock.time else {
+        return;
+    };
+    let day = daylight(time);
+
+    ambient.brightness = NIGHT_BRIGHTNESS + (DAY_BRIGHTNESS - NIGHT_BRIGHTNESS) * day;
+    clear.0 = Color::srgb(0.02, 0.02, 0.06).mix(&Color::srgb(0.5, 0.7, 1.0), day);
+}
@@ -0,0 +1,384 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    jserver::JServerClients,
+    protocol::{decode, encode, PROTOCOL_VERSION},
+    serverconfig::ServerConfig,
+    transport::ServerNet,
+};
+
+/// Servers announce themselves to this group; anyone on the network can listen in.
+pub static DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 77);
+pub static DISCOVERY_PORT: u16 = 6077;
+pub static ANNOUNCE_INTERVAL: f32 = 1.5;
+/// A server we haven't heard from in this long has gone.
+pub static SERVER_TIMEOUT: f64 = 5.0;
+static MAGIC: [u8; 4] = *b"DGLA";
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct LanAnnouncement {
+    pub magic: [u8; 4],
+    pub protocol: u32,
+    /// Random per server run, so the copies that arrive over the network and over loopback count once.
+    pub instance: u64,
+    pub name: String,
+    pub motd: String,
+    pub players: u32,
+    pub max_players: u32,
+    /// The game port; the address is wherever the announcement came from.
+    pub port: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct LanServer {
+    pub address: SocketAddr,
+    pub announcement: LanAnnouncement,
+    pub last_seen: f64,
+}
+
+/// Servers heard on the local network, by instance.
+#[derive(Resource, Default)]
+pub struct LanServers {
+    pub servers: HashMap<u64, LanServer>,
+}
+
+impl LanServers {
+    /// Takes in everything waiting on the socket.
+    pub fn receive(&mut self, socket: &UdpSocket, now: f64) {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Couldn't read LAN announcements: {}", e);
+                    break;
+                }
+            };
+            let Some(announcement) = decode::<LanAnnouncement>(&buf[..len]) else {
+                continue;
+            };
+            if announcement.magic != MAGIC {
+                continue;
+            }
+            let address = SocketAddr::new(from.ip(), announcement.port);
+            self.servers
+                .entry(announcement.instance)
+                .and_modify(|server| {
+                    // A server on this machine is heard both ways, and loopback always reaches it.
+                    if address.ip().is_loopback() {
+                        server.address = address;
+                    }
+                    server.announcement = announcement.clone();
+                    server.last_seen = now;
+                })
+                .or_insert(LanServer { address, announcement, last_seen: now });
+        }
+    }
+
+    pub fn forget_old(&mut self, now: f64) {
+        self.servers.retain(|_, server| now - server.last_seen <= SERVER_TIMEOUT);
+    }
+
+    /// Nearest first, then by name.
+    pub fn sorted(&self) -> Vec<&LanServer> {
+        let mut servers: Vec<&LanServer> = self.servers.values().collect();
+        servers.sort_by(|a, b| {
+            (!a.address.ip().is_loopback(), &a.announcement.name).cmp(&(!b.address.ip().is_loopback(), &b.announcement.name))
+        });
+        servers
+    }
+}
+
+impl LanServer {
+    pub fn describe(&self) -> String {
+        let compatible = if self.announcement.protocol == PROTOCOL_VERSION { "" } else { " (different version)" };
+        let mut line = format!(
+            "{} - {}/{} players - {}{}",
+            self.announcement.name, self.announcement.players, self.announcement.max_players, self.address, compatible
+        );
+        if !self.announcement.motd.is_empty() {
+            line.push_str(&format!("\n    {}", self.announcement.motd));
+        }
+        line
+    }
+}
+
+/// Announcements go out on the network and on loopback, each to the group on its own interface.
+/// Multicast reaches every socket in the group, so several clients on one machine all hear them.
+static DISCOVERY_INTERFACES: [Ipv4Addr; 2] = [Ipv4Addr::UNSPECIFIED, Ipv4Addr::LOCALHOST];
+
+/// Joins the discovery group.
+pub fn discovery_listener() -> io::Result<UdpSocket> {
+    discovery_listener_on(DISCOVERY_PORT)
+}
+
+fn discovery_listener_on(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+    // Without a network only loopback joins, and only servers on this machine show.
+    let mut joined = false;
+    for interface in DISCOVERY_INTERFACES {
+        match socket.join_multicast_v4(&DISCOVERY_GROUP, &interface) {
+            Ok(()) => joined = true,
+            Err(e) => debug!("Couldn't join the LAN discovery group on {}: {}", interface, e),
+        }
+    }
+    if !joined {
+        return Err(io::Error::other("couldn't join the LAN discovery group"));
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Sends to the group out of `interface`, or wherever the routes say for `UNSPECIFIED`.
+fn announcer(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn announce(sockets: &[UdpSocket], payload: &[u8], port: u16) {
+    for socket in sockets {
+        if let Err(e) = socket.send_to(payload, SocketAddrV4::new(DISCOVERY_GROUP, port)) {
+            debug!("Couldn't announce from {:?}: {}", socket.local_addr(), e);
+        }
+    }
+}
+
+/// Server side: tells the local network about us while we're listening.
+pub struct LanAnnouncePlugin;
+
+impl Plugin for LanAnnouncePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, start_announcing)
+        .add_systems(Update, announce_server.run_if(resource_exists::<LanAnnouncer>))
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct LanAnnouncer {
+    sockets: Vec<UdpSocket>,
+    instance: u64,
+    timer: Timer,
+}
+
+pub fn start_announcing(mut commands: Commands, config: Res<ServerConfig>) {
+    if !config.lan_announce {
+        return;
+    }
+    let sockets: Vec<UdpSocket> = DISCOVERY_INTERFACES.iter()
+        .filter_map(|interface| announcer(*interface).map_err(|e| debug!("Couldn't announce on {}: {}", interface, e)).ok())
+        .collect();
+    if sockets.is_empty() {
+        warn!("Couldn't open a socket to announce on the LAN");
+        return;
+    }
+    commands.insert_resource(LanAnnouncer {
+        sockets,
+        instance: rand::random(),
+        timer: Timer::from_seconds(ANNOUNCE_INTERVAL, TimerMode::Repeating),
+    });
+}
+
+pub fn announce_server(
+    time: Res<Time>,
+    net: ServerNet,
+    config: Res<ServerConfig>,
+    clients: Res<JServerClients>,
+    mut announcer: ResMut<LanAnnouncer>,
+) {
+    // A singleplayer world stays quiet until it's opened with /lan.
+    if !announcer.timer.tick(time.delta()).just_finished() || !net.is_listening() {
+        return;
+    }
+
+    let payload = encode(&LanAnnouncement {
+        magic: MAGIC,
+        protocol: PROTOCOL_VERSION,
+        instance: announcer.instance,
+        name: config.name.clone(),
+        motd: config.motd.clone(),
+        players: clients.players.len() as u32,
+        max_players: config.max_players as u32,
+        port: config.port,
+    });
+    announce(&announcer.sockets, &payload, DISCOVERY_PORT);
+}
+
+/// Client side: keeps `LanServers` up to date, with a list toggled by F4.
+pub struct LanDiscoveryPlugin;
+
+impl Plugin for LanDiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LanServers>()
+        .add_systems(Startup, (start_discovery, spawn_server_list))
+        .add_systems(Update, (discover_servers.run_if(resource_exists::<LanListener>), draw_server_list).chain())
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct LanListener(pub UdpSocket);
+
+#[derive(Component)]
+pub struct ServerList;
+
+pub fn start_discovery(mut commands: Commands) {
+    match discovery_listener() {
+        Ok(socket) => commands.insert_resource(LanListener(socket)),
+        Err(e) => warn!("Couldn't listen for LAN servers: {}", e),
+    }
+}
+
+pub fn discover_servers(time: Res<Time>, listener: Res<LanListener>, mut servers: ResMut<LanServers>) {
+    let now = time.elapsed_seconds_f64();
+    servers.receive(&listener.0, now);
+    servers.forget_old(now);
+}
+
+pub fn spawn_server_list(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section("", TextStyle { font_size: 18.0, color: Color::WHITE, ..default() })
+            .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.5))
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                right: Val::Px(10.0),
+                top: Val::Px(10.0),
+                ..default()
+            }),
+        ServerList,
+        Visibility::Hidden,
+    ));
+}
+
+pub fn draw_server_list(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    servers: Res<LanServers>,
+    mut list: Query<(&mut Text, &mut Visibility), With<ServerList>>,
+) {
+    let Ok((mut text, mut visibility)) = list.get_single_mut() else {
+        return;
+    };
+    if keyboard.just_pressed(KeyCode::F4) {
+        *visibility = if *visibility == Visibility::Hidden { Visibility::Inherited } else { Visibility::Hidden };
+    }
+    if *visibility == Visibility::Hidden {
+        return;
+    }
+
+    let lines: Vec<String> = servers.sorted().iter().map(|server| server.describe()).collect();
+    text.sections[0].value = if lines.is_empty() {
+        String::from("Looking for servers on the local network...")
+    } else {
+        format!("Servers on the local network (join with --connect):\n{}", lines.join("\n"))
+    };
+}
+
+/// `lan`: listens for a while and prints what it heard.
+pub fn run_lan_cli(seconds: f64) {
+    let socket = match discovery_listener() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Couldn't listen on port {}: {}", DISCOVERY_PORT, e);
+            std::process::exit(1);
+        }
+    };
+
+    let start = Instant::now();
+    let mut servers = LanServers::default();
+    while start.elapsed().as_secs_f64() < seconds {
+        servers.receive(&socket, start.elapsed().as_secs_f64());
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    if servers.servers.is_empty() {
+        println!("No servers found");
+    }
+    for server in servers.sorted() {
+        println!("{}", server.describe());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn every_listener_hears_a_loopback_announcement() {
+        // Off the real port, so a client running on this machine doesn't get in the way.
+        let port = DISCOVERY_PORT + 1000;
+        let listeners = [discovery_listener_on(port).unwrap(), discovery_listener_on(port).unwrap()];
+        let announcement = LanAnnouncement {
+            magic: MAGIC,
+            protocol: PROTOCOL_VERSION,
+            instance: rand::random(),
+            name: String::from("Test"),
+            motd: String::new(),
+            players: 1,
+            max_players: 4,
+            port: 6000,
+        };
+        announce(&[announcer(Ipv4Addr::LOCALHOST).unwrap()], &encode(&announcement), port);
+
+        for listener in listeners.iter() {
+            let mut servers = LanServers::default();
+            let start = Instant::now();
+            while servers.servers.is_empty() && start.elapsed() < Duration::from_secs(2) {
+                servers.receive(listener, 0.0);
+                thread::sleep(Duration::from_millis(10));
+            }
+            let server = &servers.servers[&announcement.instance];
+            assert_eq!(server.address, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6000));
+            assert_eq!(server.announcement, announcement);
+        }
+    }
+
+    #[test]
+    fn a_later_loopback_copy_replaces_the_network_address() {
+        let port = DISCOVERY_PORT + 1001;
+        let listener = discovery_listener_on(port).unwrap();
+        let announcement = LanAnnouncement {
+            magic: MAGIC,
+            protocol: PROTOCOL_VERSION,
+            instance: 7,
+            name: String::from("Test"),
+            motd: String::new(),
+            players: 0,
+            max_players: 4,
+            port: 6000,
+        };
+        let mut servers = LanServers::default();
+        servers.servers.insert(7, LanServer { address: "192.0.2.2:6000".parse().unwrap(), announcement: announcement.clone(), last_seen: 0.0 });
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&encode(&announcement), ("127.0.0.1", port)).unwrap();
+        let start = Instant::now();
+        while servers.servers[&7].last_seen == 0.0 && start.elapsed() < Duration::from_secs(2) {
+            servers.receive(&listener, 1.0);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(servers.servers[&7].address.ip().is_loopback());
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fs, io, path::Path};
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::KinematicCharacterController;
+use rand::RngCore;
+use uuid::Uuid;
+
+use crate::{
+    blockedit::BlockEditing,
+    camera::JCamera,
+    movement::yaw_rotation,
+    playerdb::MAX_HEALTH,
+    protocol::{FromServer, ServerMessage},
+    JMyId,
+};
+
+/// Where the client keeps its identity, relative to the working directory.
+pub static IDENTITY_FILE: &str = "identity.txt";
+
+/// Client side: what the server remembers about us, restored when we join.
+pub struct PlayerStatusPlugin;
+
+impl Plugin for PlayerStatusPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<FromServer>()
+        .init_resource::<PlayerStatus>()
+        .add_systems(Update, receive_player_data)
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct PlayerStatus {
+    pub health: f32,
+    pub inventory: Vec<(u32, u32)>,
+}
+
+impl Default for PlayerStatus {
+    fn default() -> Self {
+        Self {
+            health: MAX_HEALTH,
+            inventory: Vec::new(),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<[u8; 32]> {
+    let text = text.trim();
+    if text.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Our uuid on the first line and the secret token on the second.
+fn read_identity(path: &Path) -> io::Result<JMyId> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "expected a uuid line and a 64 digit hex token line");
+    let uuid = lines.next().and_then(|l| Uuid::parse_str(l.trim()).ok()).ok_or_else(bad)?;
+    let token = lines.next().and_then(from_hex).ok_or_else(bad)?;
+    Ok(JMyId { uuid, token })
+}
+
+/// What we prove ourselves with to one server: the secret keyed over who that server is.
+/// A server only ever sees its own token, so it can't pass for us anywhere else.
+pub fn server_token(secret: &[u8; 32], server: &str) -> [u8; 32] {
+    *blake3::keyed_hash(secret, server.as_bytes()).as_bytes()
+}
+
+/// Loads the identity we've used before, or makes one. Losing the file means servers
+/// treat us as someone new, so a corrupt one is an error rather than replaced.
+pub fn load_or_create_identity(path: &Path) -> io::Result<JMyId> {
+    match read_identity(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut token = [0; 32];
+            rand::thread_rng().fill_bytes(&mut token);
+            let id = JMyId { uuid: Uuid::new_v4(), token };
+            fs::write(path, format!("{}\n{}\n", id.uuid, to_hex(&id.token)))?;
+            info!("Created a new identity in {}", path.display());
+            Ok(id)
+        }
+        result => result,
+    }
+}
+
+pub fn receive_player_data(
+    mut status: ResMut<PlayerStatus>,
+    mut editing: ResMut<BlockEditing>,
+    mut camera: ResMut<JCamera>,
+    mut messages: EventReader<FromServer>,
+    mut me: Query<&mut Transform, With<KinematicCharacterController>>,
+) {
+    for FromServer(message) in messages.read() {
+        match message {
+            ServerMessage::PlayerData { position, yaw, pitch, held_block, inventory, health } => {
+                if let Ok(mut transform) = me.get_single_mut() {
+                    transform.translation = Vec3::from_array(*position);
+                    transform.rotation = yaw_rotation(*yaw);
+                }
+                camera.yaw = *yaw;
+                camera.pitch = *pitch;
+                camera.recalculate();
+                editing.held_block = *held_block;
+                status.inventory = inventory.clone();
+                status.health = *health;
+            }
+            ServerMessage::GiveBlock { block, count } => {
+                match status.inventory.iter_mut().find(|(b, _)| b == block) {
+                    Some((_, have)) => *have += count,
+                    None => status.inventory.push((*block, *count)),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_is_created_once_and_a_corrupt_one_is_kept() {
+        let path = std::env::temp_dir().join(format!("identity-test-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let created = load_or_create_identity(&path).unwrap();
+        let loaded = load_or_create_identity(&path).unwrap();
+        assert_eq!((created.uuid, created.token), (loaded.uuid, loaded.token));
+
+        fs::write(&path, "not an identity\n").unwrap();
+        assert_eq!(load_or_create_identity(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not an identity\n");
+        fs::remove_file(&path).unwrap();
+
+        let unwritable = std::env::temp_dir().join(format!("identity-test-{}-missing", std::process::id())).join("identity.txt");
+        assert!(load_or_create_identity(&unwritable).is_err());
+    }
+}
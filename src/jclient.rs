@@ -0,0 +1,339 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_quinnet::client::{
+    certificate::{CertConnectionAbortEvent, CertTrustUpdateEvent, CertVerificationStatus, CertVerifierAction, CertVerifierBehaviour, CertificateVerificationMode, TrustOnFirstUseConfig},
+    connection::{ClientEndpointConfiguration, ConnectionEvent, ConnectionFailedEvent, ConnectionLocalId, ConnectionLostEvent},
+    QuinnetClientPlugin, DEFAULT_KNOWN_HOSTS_FILE,
+};
+
+use crate::{identity::server_token, protocol::{channels_configuration, decode, peek_protocol, send_to_server, ClientMessage, FromServer, ServerMessage, PROTOCOL_VERSION}, traffic::TrafficEvent, transport::{ClientNet, LinkEvent}, JMyId};
+
+pub struct JClientPlugin;
+
+impl Plugin for JClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(QuinnetClientPlugin::default())
+        .init_resource::<JClientConfig>()
+        .init_resource::<JConnection>()
+        .add_event::<JClientEvent>()
+        .add_event::<FromServer>()
+        .add_systems(Startup, start_connection)
+        .add_systems(Update, (handle_cert_events, handle_connection_events, receive_server_messages, reconnect).chain())
+        ;
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct JClientConfig {
+    pub host: String,
+    pub port: u16,
+    pub name: String,
+    pub reconnect_delay: Duration,
+}
+
+impl Default for JClientConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from("127.0.0.1"),
+            port: 6000,
+            name: String::from("Player"),
+            reconnect_delay: Duration::from_secs(3),
+        }
+    }
+}
+
+impl JClientConfig {
+    /// Accepts `host:port` or a bare host, which keeps the default port.
+    pub fn from_address(address: &str) -> Self {
+        let mut config = Self::default();
+        (config.host, config.port) = split_address(address, config.port);
+        config
+    }
+}
+
+/// `host:port`, `[v6]:port` or a bare host, which gets `default_port`.
+pub fn split_address(address: &str, default_port: u16) -> (String, u16) {
+    match address.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => {
+            (host.trim_matches(|c| c == '[' || c == ']').to_string(), port.parse().unwrap())
+        }
+        _ => (address.to_string(), default_port),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    /// Connected and the server accepted our hello.
+    Joined,
+}
+
+#[derive(Resource, Default)]
+pub struct JConnection {
+    pub state: JConnectionState,
+    pub id: Option<ConnectionLocalId>,
+    pub reconnect_timer: Option<Timer>,
+    /// Set when the server turns us away, so we don't keep knocking.
+    pub rejected: Option<String>,
+    /// The server simulates our movement; we only predict it.
+    pub authoritative_movement: bool,
+    /// The certificate the server showed us this time, which our token for it is derived from.
+    pub server_fingerprint: Option<String>,
+}
+
+#[derive(Event, Debug, Clone)]
+pub enum JClientEvent {
+    Connected,
+    Joined,
+    Disconnected,
+    ConnectionFailed(String),
+    Rejected(String),
+    Kicked(String),
+}
+
+fn resolve(config: &JClientConfig) -> Option<SocketAddr> {
+    (config.host.as_str(), config.port).to_socket_addrs().ok()?.next()
+}
+
+fn open(net: &mut ClientNet, config: &JClientConfig, connection: &mut JConnection, events: &mut EventWriter<JClientEvent>) {
+    // Singleplayer: the server is in this process and connects straight away.
+    if let Some(loopback) = net.loopback_mut() {
+        loopback.connect();
+        connection.state = JConnectionState::Connecting;
+        return;
+    }
+    // Replaying: the recording says when we connected.
+    if net.is_replaying() {
+        connection.state = JConnectionState::Connecting;
+        return;
+    }
+    let Some(client) = net.quinnet_mut() else {
+        return;
+    };
+
+    if let Some(id) = connection.id.take() {
+        let _ = client.close_connection(id);
+    }
+    connection.server_fingerprint = None;
+
+    let Some(server) = resolve(config) else {
+        warn!("Couldn't resolve {}:{}", config.host, config.port);
+        events.send(JClientEvent::ConnectionFailed(format!("couldn't resolve {}", config.host)));
+        connection.state = JConnectionState::Disconnected;
+        connection.reconnect_timer = Some(Timer::new(config.reconnect_delay, TimerMode::Once));
+        return;
+    };
+
+    let local = match server.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    // The server's certificate is self-signed, so trust it the first time we see it and
+    // refuse to connect if it ever changes.
+    // Storing a trusted certificate again is how we hear its fingerprint on every connection.
+    let mut trust = TrustOnFirstUseConfig::default();
+    trust.verifier_behaviour.insert(
+        CertVerificationStatus::UntrustedCertificate,
+        CertVerifierBehaviour::ImmediateAction(CertVerifierAction::AbortConnection),
+    );
+    trust.verifier_behaviour.insert(
+        CertVerificationStatus::TrustedCertificate,
+        CertVerifierBehaviour::ImmediateAction(CertVerifierAction::TrustAndStore),
+    );
+    match client.open_connection(
+        ClientEndpointConfiguration::from_addrs(server, SocketAddr::new(local, 0)),
+        CertificateVerificationMode::TrustOnFirstUse(trust),
+        channels_configuration(),
+    ) {
+        Ok(id) => {
+            info!("Connecting to {}", server);
+            connection.id = Some(id);
+            connection.state = JConnectionState::Connecting;
+        }
+        Err(e) => {
+            warn!("Couldn't open connection to {}: {}", server, e);
+            events.send(JClientEvent::ConnectionFailed(e.to_string()));
+            connection.state = JConnectionState::Disconnected;
+            connection.reconnect_timer = Some(Timer::new(config.reconnect_delay, TimerMode::Once));
+        }
+    }
+}
+
+pub fn start_connection(
+    mut net: ClientNet,
+    config: Res<JClientConfig>,
+    mut connection: ResMut<JConnection>,
+    mut events: EventWriter<JClientEvent>,
+) {
+    open(&mut net, &config, &mut connection, &mut events);
+}
+
+pub fn handle_connection_events(
+    mut net: ClientNet,
+    config: Res<JClientConfig>,
+    myid: Res<JMyId>,
+    mut connection: ResMut<JConnection>,
+    mut connected: EventReader<ConnectionEvent>,
+    mut failed: EventReader<ConnectionFailedEvent>,
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut events: EventWriter<JClientEvent>,
+) {
+    let mut newly_connected = connected.read().count();
+    let mut newly_lost = lost.read().count();
+    for event in net.take_link_events() {
+        match event {
+            LinkEvent::Connected => newly_connected += 1,
+            LinkEvent::Lost => newly_lost += 1,
+        }
+    }
+    for _ in 0..newly_connected {
+        net.record(TrafficEvent::Connected { client_id: 0, address: None });
+    }
+    for _ in 0..newly_lost {
+        net.record(TrafficEvent::Disconnected { client_id: 0 });
+    }
+
+    for _ in 0..newly_connected {
+        // The certificate is what a server can't fake, so our token is keyed on it. Quinnet reports
+        // it before the connection, so it's always here for a real server.
+        let server = match (&connection.server_fingerprint, net.loopback_mut().is_some(), net.is_replaying()) {
+            (Some(fingerprint), _, _) => Some(fingerprint.clone()),
+            (None, true, _) => Some(String::from("loopback")),
+            (None, false, true) => None,
+            (None, false, false) => {
+                // The host name isn't the server: the same one can be reached as localhost, an IP or a DNS name.
+                warn!("{}:{} connected without showing a certificate, disconnecting", config.host, config.port);
+                if let (Some(client), Some(id)) = (net.quinnet_mut(), connection.id.take()) {
+                    let _ = client.close_connection(id);
+                }
+                connection.state = JConnectionState::Disconnected;
+                connection.reconnect_timer = Some(Timer::new(config.reconnect_delay, TimerMode::Once));
+                events.send(JClientEvent::ConnectionFailed(String::from("the server didn't show a certificate")));
+                continue;
+            }
+        };
+
+        info!("Connected to {}:{}", config.host, config.port);
+        connection.state = JConnectionState::Connected;
+        connection.reconnect_timer = None;
+        events.send(JClientEvent::Connected);
+
+        // Nobody reads what a replay sends.
+        if let Some(server) = server {
+            send_to_server(&mut net, &ClientMessage::Hello {
+                protocol: PROTOCOL_VERSION,
+                uuid: myid.uuid.into_bytes(),
+                name: config.name.clone(),
+                token: server_token(&myid.token, &server),
+            });
+        }
+    }
+    for event in failed.read() {
+        warn!("Connection to {}:{} failed: {}", config.host, config.port, event.err);
+        connection.state = JConnectionState::Disconnected;
+        if connection.rejected.is_none() {
+            connection.reconnect_timer = Some(Timer::new(config.reconnect_delay, TimerMode::Once));
+        }
+        events.send(JClientEvent::ConnectionFailed(event.err.to_string()));
+    }
+    for _ in 0..newly_lost {
+        warn!("Lost connection to {}:{}", config.host, config.port);
+        connection.state = JConnectionState::Disconnected;
+        if connection.rejected.is_none() {
+            connection.reconnect_timer = Some(Timer::new(config.reconnect_delay, TimerMode::Once));
+        }
+        events.send(JClientEvent::Disconnected);
+    }
+}
+
+pub fn receive_server_messages(
+    mut net: ClientNet,
+    mut connection: ResMut<JConnection>,
+    mut messages: EventWriter<FromServer>,
+    mut events: EventWriter<JClientEvent>,
+) {
+    while let Some(payload) = net.try_receive() {
+        let Some(message) = decode::<ServerMessage>(&payload) else {
+            if let (JConnectionState::Connected, Some(protocol)) = (connection.state, peek_protocol(&payload)) {
+                let reason = format!("Server is on protocol {}, you are on {}", protocol, PROTOCOL_VERSION);
+                error!("Server rejected us: {}", reason);
+                connection.rejected = Some(reason.clone());
+                connection.reconnect_timer = None;
+                events.send(JClientEvent::Rejected(reason));
+                continue;
+            }
+            warn!("Undecodable message from server");
+            continue;
+        };
+
+        match message {
+            ServerMessage::Welcome { protocol, client_id, authoritative_movement } => {
+                info!("Joined as client {} (protocol {})", client_id, protocol);
+                connection.state = JConnectionState::Joined;
+                connection.authoritative_movement = authoritative_movement;
+                events.send(JClientEvent::Joined);
+            }
+            ServerMessage::Rejected { reason } => {
+                error!("Server rejected us: {}", reason);
+                connection.rejected = Some(reason.clone());
+                connection.reconnect_timer = None;
+                events.send(JClientEvent::Rejected(reason));
+            }
+            ServerMessage::Kicked { reason } => {
+                error!("Kicked from the server: {}", reason);
+                connection.rejected = Some(reason.clone());
+                connection.reconnect_timer = None;
+                events.send(JClientEvent::Kicked(reason));
+            }
+            #[allow(unreachable_patterns)]
+            message => {
+                messages.send(FromServer(message));
+            }
+        }
+    }
+}
+
+pub fn handle_cert_events(
+    mut connection: ResMut<JConnection>,
+    mut trust_updates: EventReader<CertTrustUpdateEvent>,
+    mut aborts: EventReader<CertConnectionAbortEvent>,
+    mut events: EventWriter<JClientEvent>,
+) {
+    for event in trust_updates.read() {
+        debug!("Trusting certificate {:?}", event.cert_info);
+        connection.server_fingerprint = Some(event.cert_info.fingerprint.to_base64());
+    }
+    for event in aborts.read() {
+        error!("Server certificate changed ({:?}, {:?}), refusing to connect", event.status, event.cert_info);
+        let reason = format!(
+            "the server's certificate has changed since you last joined. If that's expected, remove {:?} from {}",
+            event.cert_info.server_name, DEFAULT_KNOWN_HOSTS_FILE,
+        );
+        connection.state = JConnectionState::Disconnected;
+        connection.rejected = Some(reason.clone());
+        connection.reconnect_timer = None;
+        events.send(JClientEvent::Rejected(reason));
+    }
+}
+
+pub fn reconnect(
+    time: Res<Time>,
+    mut net: ClientNet,
+    config: Res<JClientConfig>,
+    mut connection: ResMut<JConnection>,
+    mut events: EventWriter<JClientEvent>,
+) {
+    let Some(timer) = connection.reconnect_timer.as_mut() else {
+        return;
+    };
+    if timer.tick(time.delta()).just_finished() {
+        connection.reconnect_timer = None;
+        open(&mut net, &config, &mut connection, &mut events);
+    }
+}
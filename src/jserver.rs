@@ -1,28 +1,531 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::{io, net::SocketAddr, time::Duration};
 
-use bevy::prelude::*;
-use bevy_quinnet::client::certificate::CertificateVerificationMode;
-use bevy_quinnet::shared::channels::ChannelType;
-use bevy_quinnet::{client::*, server::*, shared::channels::ChannelsConfiguration};
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy_quinnet::server::*;
+use bevy_quinnet::shared::ClientId;
 
 use bevy_quinnet::server::certificate::CertificateRetrievalMode;
-use connection::ClientEndpointConfiguration;
-
-
-pub fn start_listening(mut server: ResMut<QuinnetServer>) {
-    server
-        .start_endpoint(
-            ServerEndpointConfiguration::from_ip(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 6000),
-            CertificateRetrievalMode::GenerateSelfSigned {
-                server_hostname: String::from("Distant Garden Server"),
-            },
-            ChannelsConfiguration::from_types(vec![
-                ChannelType::Unreliable, //Player updates
-                ChannelType::Unreliable, //Mob updates
-                ChannelType::OrderedReliable,  //Inventory updates
-                ChannelType::OrderedReliable,  //
-            ])
-            .unwrap(),
-        )
-        .unwrap();
+use uuid::Uuid;
+
+use crate::protocol::{channels_configuration, decode, peek_protocol, send_to_client, send_to_clients, ClientMessage, FromClient, ServerMessage, PROTOCOL_VERSION};
+use crate::blockedit::BlockEditServerPlugin;
+use crate::chat::ChatServerPlugin;
+use crate::chunkstream::ChunkStreamPlugin;
+use crate::mobs::MobServerPlugin;
+use crate::pathfinding::PathfindingPlugin;
+use crate::serverplayers::ServerPlayersPlugin;
+use crate::serverworld::{save_world, ServerWorldPlugin, WorldPath, WorldTime};
+use crate::servercommands::ServerCommandsPlugin;
+use crate::playerdb::{authenticate, save_online_players, PlayerDatabasePlugin, MAX_HEALTH};
+use crate::chunk::JPerlin;
+use crate::UserDataMap;
+use crate::movement::SPAWN_POSITION;
+use crate::serverconfig::ServerConfig;
+use crate::access::{AccessLists, AccessPlugin, ConnectionThrottle};
+use crate::discovery::LanAnnouncePlugin;
+use crate::status::StatusPlugin;
+use crate::traffic::{ReplayLink, TrafficEvent, TrafficPlugin};
+use crate::transport::{with_server_net, LinkEvent, LoopbackServer, ServerNet, LOOPBACK_CLIENT_ID};
+
+/// How often every player's latest transform is sent to everyone else.
+pub static PLAYER_BROADCAST_RATE: f32 = 20.0;
+/// Kicked and rejected clients get this long for the reason to arrive before the connection closes.
+pub static DISCONNECT_DELAY: f32 = 0.5;
+/// Player names are 1 to this many letters, digits and underscores, unique ignoring case.
+pub static MAX_NAME_LENGTH: usize = 16;
+/// Seconds between saves of the world and everyone in it.
+pub static AUTOSAVE_INTERVAL: f32 = 300.0;
+/// The server's certificate and key, in the world directory.
+pub static CERT_FILE: &str = "cert.pem";
+pub static KEY_FILE: &str = "key.pem";
+
+
+pub struct JServerPlugin;
+
+impl Plugin for JServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((QuinnetServerPlugin::default(), ServerWorldPlugin, ServerPlayersPlugin, ChunkStreamPlugin, BlockEditServerPlugin, ChatServerPlugin, ServerCommandsPlugin, PlayerDatabasePlugin, AccessPlugin, LanAnnouncePlugin, StatusPlugin, MobServerPlugin, PathfindingPlugin, TrafficPlugin))
+        .init_resource::<ServerConfig>()
+        .init_resource::<JServerSettings>()
+        .init_resource::<JServerClients>()
+        .init_resource::<PendingDisconnects>()
+        .insert_resource(PlayerBroadcastTimer(Timer::new(Duration::from_secs_f32(1.0 / PLAYER_BROADCAST_RATE), TimerMode::Repeating)))
+        .insert_resource(AutosaveTimer(Timer::new(Duration::from_secs_f32(AUTOSAVE_INTERVAL), TimerMode::Repeating)))
+        .add_event::<FromClient>()
+        .add_event::<PlayerJoined>()
+        .add_event::<PlayerLeft>()
+        .add_event::<StopServer>()
+        // A singleplayer world only listens once it's opened to LAN.
+        .add_systems(Startup, start_listening.run_if(not(resource_exists::<LoopbackServer>)).run_if(not(resource_exists::<ReplayLink>)))
+        .add_systems(Update, (handle_server_connections, receive_client_messages, announce_players, apply_player_updates, broadcast_player_updates, finish_disconnects).chain())
+        .add_systems(Update, autosave)
+        .add_systems(Last, (stop_with_host.run_if(resource_exists::<LoopbackServer>), stop_server, save_on_exit).chain())
+        ;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectedPlayer {
+    pub uuid: Uuid,
+    /// Proves they're the same player as last time, see `playerdb`.
+    pub token: [u8; 32],
+    pub name: String,
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub moving: bool,
+    /// The collider we simulate their movement with.
+    pub entity: Option<Entity>,
+    pub held_block: u32,
+    pub inventory: Vec<(u32, u32)>,
+    pub health: f32,
+}
+
+impl ConnectedPlayer {
+    pub fn new(uuid: Uuid, token: [u8; 32], name: String) -> Self {
+        Self {
+            uuid,
+            token,
+            name,
+            position: SPAWN_POSITION,
+            yaw: 0.0,
+            pitch: 0.0,
+            moving: false,
+            entity: None,
+            held_block: 10,
+            inventory: Vec::new(),
+            health: MAX_HEALTH,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct PlayerBroadcastTimer(Timer);
+
+#[derive(Resource)]
+pub struct JServerSettings {
+    /// Simulate player movement from inputs instead of trusting the positions clients send.
+    pub authoritative_movement: bool,
+    /// Only let in players on the whitelist, see `access`.
+    pub whitelist: bool,
+    pub max_players: usize,
+    /// Chunks streamed around each player.
+    pub view_distance: i32,
+    /// Shown to players when they join.
+    pub motd: String,
+}
+
+impl JServerSettings {
+    /// Clients are assumed to have dropped chunks this far away.
+    pub fn unload_distance(&self) -> i32 {
+        self.view_distance + 3
+    }
+}
+
+impl Default for JServerSettings {
+    fn default() -> Self {
+        Self {
+            authoritative_movement: true,
+            whitelist: false,
+            max_players: 20,
+            view_distance: 7,
+            motd: String::new(),
+        }
+    }
+}
+
+/// Connections that haven't said hello yet, and the players that have.
+#[derive(Resource, Default)]
+pub struct JServerClients {
+    pub pending: HashSet<ClientId>,
+    pub players: HashMap<ClientId, ConnectedPlayer>,
+}
+
+/// Connections that have been told why they're being dropped, and when to drop them.
+#[derive(Resource, Default)]
+pub struct PendingDisconnects(pub Vec<(ClientId, Timer)>);
+
+impl PendingDisconnects {
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.0.iter().any(|(id, _)| *id == client_id)
+    }
+
+    fn schedule(&mut self, client_id: ClientId) {
+        if !self.contains(client_id) {
+            self.0.push((client_id, Timer::from_seconds(DISCONNECT_DELAY, TimerMode::Once)));
+        }
+    }
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct PlayerJoined {
+    pub client_id: ClientId,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct PlayerLeft {
+    pub client_id: ClientId,
+    pub player: ConnectedPlayer,
+}
+
+/// Drops a client from our side. Quinnet doesn't report connections we close ourselves
+/// as lost, so this sends `PlayerLeft` itself.
+pub fn drop_client(net: &mut ServerNet, clients: &mut JServerClients, left: &mut EventWriter<PlayerLeft>, client_id: ClientId) {
+    net.disconnect(client_id);
+    clients.pending.remove(&client_id);
+    if let Some(player) = clients.players.remove(&client_id) {
+        info!("{} left", player.name);
+        left.send(PlayerLeft { client_id, player });
+    }
+}
+
+/// Tells a player why they're being removed, then drops them shortly after.
+pub fn kick_client(net: &mut ServerNet, disconnects: &mut PendingDisconnects, client_id: ClientId, reason: String) {
+    info!("Kicking client {}: {}", client_id, reason);
+    send_to_client(net, client_id, &ServerMessage::Kicked { reason });
+    disconnects.schedule(client_id);
+}
+
+/// Why a name can't be used, if it can't. Commands find players by name, so names have to be
+/// typeable and tell players apart.
+pub fn check_player_name(clients: &JServerClients, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(String::from("You need a name"));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Names can't be longer than {} characters", MAX_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(String::from("Names can only have letters, digits and underscores"));
+    }
+    if clients.players.values().any(|p| p.name.eq_ignore_ascii_case(name)) {
+        return Err(format!("Someone called {} is already playing", name));
+    }
+    Ok(())
+}
+
+fn version_mismatch(protocol: u32) -> String {
+    format!("Server is on protocol {}, you are on {}", PROTOCOL_VERSION, protocol)
+}
+
+/// Like `kick_client`, for connections that haven't joined.
+pub fn reject_client(net: &mut ServerNet, disconnects: &mut PendingDisconnects, client_id: ClientId, reason: String) {
+    info!("Rejecting client {}: {}", client_id, reason);
+    send_to_client(net, client_id, &ServerMessage::Rejected { reason });
+    disconnects.schedule(client_id);
+}
+
+pub fn finish_disconnects(
+    time: Res<Time>,
+    mut net: ServerNet,
+    mut clients: ResMut<JServerClients>,
+    mut disconnects: ResMut<PendingDisconnects>,
+    mut left: EventWriter<PlayerLeft>,
+) {
+    disconnects.0.retain_mut(|(client_id, timer)| {
+        if timer.tick(time.delta()).finished() {
+            drop_client(&mut net, &mut clients, &mut left, *client_id);
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Opens the quinnet endpoint for the config's address and port.
+/// The certificate is kept with the world, so clients that trusted it once still do after a restart.
+pub fn listen(server: &mut QuinnetServer, config: &ServerConfig) -> Result<SocketAddr, String> {
+    let address = SocketAddr::new(config.bind_address, config.port);
+    server.start_endpoint(
+        ServerEndpointConfiguration::from_ip(config.bind_address, config.port),
+        CertificateRetrievalMode::LoadFromFileOrGenerateSelfSigned {
+            cert_file: config.world.join(CERT_FILE).display().to_string(),
+            key_file: config.world.join(KEY_FILE).display().to_string(),
+            save_on_disk: true,
+            server_hostname: config.name.clone(),
+        },
+        channels_configuration(),
+    )
+    .map_err(|e| format!("Couldn't listen on {}: {}", address, e))?;
+    info!("{} listening on {}", config.name, address);
+    Ok(address)
+}
+
+pub fn start_listening(mut server: ResMut<QuinnetServer>, config: Res<ServerConfig>) {
+    if let Err(e) = listen(&mut server, &config) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Asks the server to kick everyone, save and exit.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct StopServer;
+
+#[derive(Resource)]
+pub struct AutosaveTimer(Timer);
+
+/// The world and everyone still in it.
+pub fn save_everything(world: &World) -> io::Result<()> {
+    save_world(
+        world.resource::<WorldPath>(),
+        world.resource::<JPerlin>(),
+        world.resource::<WorldTime>(),
+        world.resource::<UserDataMap>(),
+    )?;
+    save_online_players(world.resource::<WorldPath>(), world.resource::<JServerClients>())
+}
+
+pub fn autosave(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    if !world.resource_mut::<AutosaveTimer>().0.tick(delta).just_finished() {
+        return;
+    }
+    match save_everything(world) {
+        Ok(()) => info!("Autosaved {}", world.resource::<WorldPath>().0.display()),
+        Err(e) => error!("Couldn't autosave the world: {}", e),
+    }
+}
+
+/// A singleplayer world lasts as long as its player.
+pub fn stop_with_host(loopback: Res<LoopbackServer>, mut stop: EventWriter<StopServer>) {
+    if loopback.closed {
+        stop.send(StopServer);
+    }
+}
+
+/// Kicks everyone, then exits a frame after the last of them has been dropped, so
+/// `PlayerLeft` has been handled and they're saved.
+pub fn stop_server(world: &mut World, mut stopping: Local<bool>, mut empty_frames: Local<u32>) {
+    if !*stopping {
+        if world.resource_mut::<Events<StopServer>>().drain().count() == 0 {
+            return;
+        }
+        *stopping = true;
+        info!("Stopping the server");
+        let clients = world.resource::<JServerClients>();
+        let client_ids: Vec<ClientId> = clients.pending.iter().chain(clients.players.keys()).copied().collect();
+        world.resource_scope(|world, mut disconnects: Mut<PendingDisconnects>| with_server_net(world, |net| {
+            for client_id in client_ids {
+                kick_client(net, &mut disconnects, client_id, String::from("The server stopped"));
+            }
+        }));
+    }
+
+    if !world.resource::<PendingDisconnects>().0.is_empty() {
+        return;
+    }
+    *empty_frames += 1;
+    if *empty_frames >= 2 {
+        world.send_event(AppExit::Success);
+    }
+}
+
+/// However the app is told to exit, the world is saved on the way out.
+pub fn save_on_exit(world: &mut World, mut saved: Local<bool>) {
+    if *saved || world.resource::<Events<AppExit>>().is_empty() {
+        return;
+    }
+    *saved = true;
+    match save_everything(world) {
+        Ok(()) => info!("Saved {}", world.resource::<WorldPath>().0.display()),
+        Err(e) => error!("Couldn't save the world: {}", e),
+    }
+}
+
+pub fn handle_server_connections(
+    time: Res<Time>,
+    mut net: ServerNet,
+    mut throttle: ResMut<ConnectionThrottle>,
+    mut clients: ResMut<JServerClients>,
+    mut disconnects: ResMut<PendingDisconnects>,
+    mut connected: EventReader<ConnectionEvent>,
+    mut lost: EventReader<ConnectionLostEvent>,
+    mut left: EventWriter<PlayerLeft>,
+) {
+    let now = time.elapsed_seconds_f64();
+    throttle.forget_old(now);
+
+    let mut opened: Vec<ClientId> = connected.read().map(|event| event.id).collect();
+    let mut closed: Vec<ClientId> = lost.read().map(|event| event.id).collect();
+    for (client_id, event) in net.poll_links() {
+        match event {
+            LinkEvent::Connected => opened.push(client_id),
+            LinkEvent::Lost => closed.push(client_id),
+        }
+    }
+    for client_id in &opened {
+        let address = net.client_ip(*client_id).map(|ip| ip.to_string());
+        net.record(TrafficEvent::Connected { client_id: *client_id, address });
+    }
+    for client_id in &closed {
+        net.record(TrafficEvent::Disconnected { client_id: *client_id });
+    }
+
+    for client_id in opened {
+        clients.pending.insert(client_id);
+        // The local player is always welcome on their own world.
+        if client_id == LOOPBACK_CLIENT_ID {
+            continue;
+        }
+        if !throttle.allow(net.client_ip(client_id), now) {
+            reject_client(&mut net, &mut disconnects, client_id, String::from("Too many connection attempts, wait a minute and try again"));
+        }
+    }
+    for client_id in closed {
+        clients.pending.remove(&client_id);
+        disconnects.0.retain(|(id, _)| *id != client_id);
+        if let Some(player) = clients.players.remove(&client_id) {
+            info!("{} left", player.name);
+            left.send(PlayerLeft { client_id, player });
+        }
+    }
+}
+
+pub fn receive_client_messages(
+    mut net: ServerNet,
+    settings: Res<JServerSettings>,
+    world_path: Res<WorldPath>,
+    access: Res<AccessLists>,
+    mut clients: ResMut<JServerClients>,
+    mut disconnects: ResMut<PendingDisconnects>,
+    mut messages: EventWriter<FromClient>,
+    mut joined: EventWriter<PlayerJoined>,
+    mut left: EventWriter<PlayerLeft>,
+) {
+    for client_id in net.clients() {
+        while let Some(payload) = net.try_receive(client_id) {
+            // They've been told they're going; nothing they say now matters.
+            if disconnects.contains(client_id) {
+                continue;
+            }
+            let Some(message) = decode::<ClientMessage>(&payload) else {
+                // A hello we can't read is most likely from another version; say which.
+                if !clients.players.contains_key(&client_id) {
+                    if let Some(protocol) = peek_protocol(&payload) {
+                        reject_client(&mut net, &mut disconnects, client_id, version_mismatch(protocol));
+                        break;
+                    }
+                }
+                warn!("Undecodable message from client {}, disconnecting", client_id);
+                drop_client(&mut net, &mut clients, &mut left, client_id);
+                break;
+            };
+
+            if clients.players.contains_key(&client_id) {
+                messages.send(FromClient { client_id, message });
+                continue;
+            }
+
+            match message {
+                ClientMessage::Hello { protocol, uuid, name, token } => {
+                    let uuid = Uuid::from_bytes(uuid);
+                    let mut player = ConnectedPlayer::new(uuid, token, name);
+                    let refusal = if protocol != PROTOCOL_VERSION {
+                        Err(version_mismatch(protocol))
+                    } else if clients.players.values().any(|p| p.uuid == uuid) {
+                        Err(String::from("You're already connected"))
+                    } else if let Err(reason) = check_player_name(&clients, &player.name) {
+                        Err(reason)
+                    } else {
+                        let access_check = if client_id == LOOPBACK_CLIENT_ID {
+                            Ok(())
+                        } else {
+                            access.check_player(&settings, &clients, uuid)
+                        };
+                        access_check.and_then(|_| authenticate(&world_path, token, &mut player))
+                    };
+                    if let Err(reason) = refusal {
+                        reject_client(&mut net, &mut disconnects, client_id, reason);
+                        break;
+                    }
+
+                    info!("{} joined", player.name);
+                    clients.pending.remove(&client_id);
+                    clients.players.insert(client_id, player);
+                    send_to_client(&mut net, client_id, &ServerMessage::Welcome {
+                        protocol: PROTOCOL_VERSION,
+                        client_id,
+                        authoritative_movement: settings.authoritative_movement,
+                    });
+                    joined.send(PlayerJoined { client_id });
+                }
+                #[allow(unreachable_patterns)]
+                _ => {
+                    // Nothing but a hello is allowed before the handshake.
+                }
+            }
+        }
+    }
+}
+
+pub fn announce_players(
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+    mut joined: EventReader<PlayerJoined>,
+    mut left: EventReader<PlayerLeft>,
+) {
+    for event in joined.read() {
+        let Some(newcomer) = clients.players.get(&event.client_id) else {
+            continue;
+        };
+        let others: Vec<ClientId> = clients.players.keys().filter(|id| **id != event.client_id).copied().collect();
+
+        for id in others.iter() {
+            let other = &clients.players[id];
+            send_to_client(&mut net, event.client_id, &ServerMessage::PlayerJoined {
+                uuid: other.uuid.into_bytes(),
+                name: other.name.clone(),
+            });
+        }
+        send_to_clients(&mut net, others.iter(), &ServerMessage::PlayerJoined {
+            uuid: newcomer.uuid.into_bytes(),
+            name: newcomer.name.clone(),
+        });
+    }
+
+    for event in left.read() {
+        send_to_clients(&mut net, clients.players.keys(), &ServerMessage::PlayerLeft {
+            uuid: event.player.uuid.into_bytes(),
+        });
+    }
+}
+
+pub fn apply_player_updates(settings: Res<JServerSettings>, mut clients: ResMut<JServerClients>, mut messages: EventReader<FromClient>) {
+    for FromClient { client_id, message } in messages.read() {
+        if settings.authoritative_movement {
+            continue;
+        }
+        if let ClientMessage::PlayerUpdate { position, yaw, pitch, moving } = message {
+            if let Some(player) = clients.players.get_mut(client_id) {
+                player.position = Vec3::from_array(*position);
+                player.yaw = *yaw;
+                player.pitch = *pitch;
+                player.moving = *moving;
+            }
+        }
+    }
+}
+
+pub fn broadcast_player_updates(
+    time: Res<Time>,
+    mut timer: ResMut<PlayerBroadcastTimer>,
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    for (client_id, player) in clients.players.iter() {
+        let message = ServerMessage::PlayerUpdate {
+            uuid: player.uuid.into_bytes(),
+            server_time: time.elapsed_seconds_f64(),
+            position: player.position.to_array(),
+            yaw: player.yaw,
+            pitch: player.pitch,
+            moving: player.moving,
+        };
+        send_to_clients(&mut net, clients.players.keys().filter(|id| *id != client_id), &message);
+    }
 }
@@ -1,31 +1,101 @@
 
 mod jclient;
 mod jserver;
+mod protocol;
+mod remoteplayers;
+mod snapshot;
+mod chunkstream;
+mod blockedit;
+mod chat;
+mod movement;
+mod prediction;
+mod serverplayers;
+mod serverworld;
+mod serverconfig;
+mod cli;
+mod bench;
+mod identity;
+mod playerdb;
+mod access;
+mod servercommands;
+mod transport;
+mod discovery;
+mod status;
+mod mobs;
+mod pathfinding;
+mod traffic;
+mod daylight;
 mod camera;
 mod chunk;
 mod cube; 
 mod blockinfo;
 mod revindices;
+mod city;
+mod structure;
+mod voxelize;
+mod worldmap;
 
-use std::{env, f32::consts::PI, time::Duration};
+use std::{f32::consts::PI, path::Path, thread, time::Duration};
 
-use bevy::{animation::animate_targets, input::mouse::MouseMotion, pbr::CascadeShadowConfigBuilder, prelude::*, render::{mesh::PrimitiveTopology, render_asset::RenderAssetUsages, Render}, utils::HashMap, window::{CursorGrabMode, PrimaryWindow}};
+use bevy::{animation::animate_targets, app::ScheduleRunnerPlugin, input::mouse::MouseMotion, pbr::CascadeShadowConfigBuilder, prelude::*, render::{mesh::PrimitiveTopology, render_asset::RenderAssetUsages, Render}, utils::HashMap, window::{CursorGrabMode, PrimaryWindow}};
+use blockedit::BlockEditPlugin;
 use camera::JCamera;
-use chunk::{ChunkPlugin, JPerlin, RebuildThisChunk, CW};
+use chat::{chat_closed, ChatPlugin};
+use chunkstream::ChunkSyncPlugin;
+use daylight::DaylightPlugin;
+use discovery::LanDiscoveryPlugin;
+use identity::{load_or_create_identity, PlayerStatusPlugin};
+use chunk::{spot_to_chunk_pos, ChunkPlugin, JPerlin, RebuildThisChunk, CW};
 use dashmap::DashMap;
-use jserver::start_listening;
+use jclient::{JClientConfig, JClientPlugin};
+use jserver::JServerPlugin;
+use mobs::{ChildMob, ClientMob, MobPlugin};
+use serverconfig::ServerConfig;
+use serverworld::WorldSave;
+use traffic::{log_server_messages, replay_server_config, RecordedSide, ReplayLink, TrafficPlugin, TrafficRecorder};
+use transport::loopback_pair;
+use clap::Parser;
+use cli::{ClientArgs, Cli, Command};
+use remoteplayers::RemotePlayersPlugin;
 use bevy_rapier3d::prelude::*;
+use movement::{player_collider, player_controller, GRAVITY, GROUND_TIMER, JUMP_SPEED, MOVEMENT_SPEED, SPAWN_POSITION};
 use once_cell::sync::Lazy;
+use prediction::{moving_locally, PredictionPlugin};
 use uuid::Uuid;
 
-const GROUND_TIMER: f32 = 0.5;
-const MOVEMENT_SPEED: f32 = 4.0;
-const JUMP_SPEED: f32 = 14.0;
-const GRAVITY: f32 = -9.81;
 
-#[derive(Resource)]
+/// Player edits, grouped by chunk so everything in one chunk is a single lookup away.
+#[derive(Resource, Default)]
 pub struct UserDataMap {
-    pub map: HashMap<IVec3, u32>
+    pub chunks: HashMap<IVec2, HashMap<IVec3, u32>>,
+}
+
+impl UserDataMap {
+    pub fn get(&self, spot: &IVec3) -> Option<&u32> {
+        self.chunks.get(&spot_to_chunk_pos(spot))?.get(spot)
+    }
+
+    pub fn insert(&mut self, spot: IVec3, block: u32) {
+        self.chunks.entry(spot_to_chunk_pos(&spot)).or_default().insert(spot, block);
+    }
+
+    pub fn chunk(&self, cpos: &IVec2) -> Option<&HashMap<IVec3, u32>> {
+        self.chunks.get(cpos)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&IVec3, &u32)> {
+        self.chunks.values().flat_map(|edits| edits.iter())
+    }
+}
+
+impl FromIterator<(IVec3, u32)> for UserDataMap {
+    fn from_iter<T: IntoIterator<Item = (IVec3, u32)>>(iter: T) -> Self {
+        let mut udm = Self::default();
+        for (spot, block) in iter {
+            udm.insert(spot, block);
+        }
+        udm
+    }
 }
 
 #[derive(Resource, Reflect)] 
@@ -37,13 +107,13 @@ pub struct MyPlayerInitialized {
 #[derive(Component, Reflect)]
 #[reflect(Component)]
 pub struct ChildJId {
-    uuid: Uuid
+    pub uuid: Uuid
 }
 
 #[derive(Component, Reflect)]
 #[reflect(Component)]
 pub struct JId {
-    uuid: Uuid
+    pub uuid: Uuid
 }
 
 #[derive(Resource)]
@@ -57,7 +127,8 @@ impl Default for MyPlayerInitialized {
 
 #[derive(Resource)] 
 pub struct JMyId {
-    pub uuid: Uuid
+    pub uuid: Uuid,
+    pub token: [u8; 32],
 }
 
 #[derive(Component)]
@@ -79,13 +150,13 @@ pub struct Animations {
 
 #[derive(Resource, Default)] 
 pub struct JControls {
-    f: bool,
-    b: bool,
-    r: bool,
-    l: bool,
-    sprinting: bool,
-    jump: bool,
-    moving: bool
+    pub f: bool,
+    pub b: bool,
+    pub r: bool,
+    pub l: bool,
+    pub sprinting: bool,
+    pub jump: bool,
+    pub moving: bool
 }
 
 #[derive(Resource, Reflect, Clone)] 
@@ -121,70 +192,206 @@ impl Default for JMoveState {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    let mut isserver = false;
-
-    for arg in args {
-        if arg == "s" { //ITS A SERVER IF ARG S IS THERE
-            isserver = true;
-            break;
-        } else {
-            
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Client(ClientArgs::default())) {
+        Command::Client(args) => {
+            let connect = args.connect.as_deref().map(|address| JClientConfig {
+                name: args.name.clone(),
+                ..JClientConfig::from_address(address)
+            });
+            let mut app = client_app(connect, load_identity(&args.identity));
+            record_traffic(&mut app, args.record.as_deref(), RecordedSide::Client, None);
+            app.run();
+        }
+        Command::Server(args) => {
+            let mut config = match args.config() {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            // A replay has to make the same world, so a new one can't be left to a random seed.
+            if args.record.is_some() && config.seed.is_none() && !WorldSave::file(&config.world).exists() {
+                config.seed = Some(rand::random());
+            }
+            let mut app = server_app(&config);
+            record_traffic(&mut app, args.record.as_deref(), RecordedSide::Server, Some(&config));
+            app.run();
+        }
+        Command::Singleplayer(args) => {
+            let id = load_identity(&args.identity);
+            let config = ServerConfig {
+                name: String::from("Singleplayer"),
+                port: args.port,
+                world: args.world.clone(),
+                seed: args.seed,
+                ..default()
+            };
+            // The world's server runs headless on its own thread, linked to us in memory,
+            // so singleplayer plays by exactly the same rules as a dedicated server.
+            let (server_end, client_end) = loopback_pair();
+            let server = thread::spawn(move || {
+                let mut app = server_app(&config);
+                app.insert_resource(server_end);
+                app.run();
+            });
+
+            let connect = JClientConfig {
+                host: String::from("localhost"),
+                port: args.port,
+                name: args.name.clone(),
+                ..default()
+            };
+            let mut client = client_app(Some(connect), id);
+            client.insert_resource(client_end);
+            record_traffic(&mut client, args.record.as_deref(), RecordedSide::Client, None);
+            client.run();
+
+            // Closing the link is the server's cue to save and stop.
+            drop(client);
+            if server.join().is_err() {
+                eprintln!("The world's server crashed, it may not have saved");
+                std::process::exit(1);
+            }
+        }
+        Command::Lan(args) => {
+            discovery::run_lan_cli(args.seconds);
+        }
+        Command::Status(args) => {
+            status::run_status_cli(&args.address);
+        }
+        Command::Replay(args) => {
+            let replay = match ReplayLink::load(&args.recording) {
+                Ok(replay) => replay,
+                Err(e) => {
+                    eprintln!("Couldn't read {}: {}", args.recording.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            let mut app = match replay.side() {
+                RecordedSide::Client => replay_client_app(),
+                RecordedSide::Server => match replay_server_config(&args.recording, &replay.header) {
+                    Ok(config) => server_app(&config),
+                    Err(e) => {
+                        eprintln!("Can't replay {}: {}", args.recording.display(), e);
+                        std::process::exit(1);
+                    }
+                },
+            };
+            app.insert_resource(replay);
+            app.run();
+        }
+        Command::WorldgenMap(args) => {
+            worldmap::run_worldmap_cli(IVec2::new(args.x, args.z), UVec2::new(args.width, args.depth), &args.out, args.seed);
+        }
+        Command::Voxelize(args) => {
+            voxelize::run_voxelize_cli(&args.input, &args.output, args.blocks_per_unit);
+        }
+        Command::Bench(args) => {
+            bench::run_bench(args.chunks, args.seed);
         }
     }
+}
 
+fn server_app(config: &ServerConfig) -> App {
     let mut a = App::new();
+    config.apply(&mut a);
+
+    // Headless, but still enough of bevy for rapier to simulate players against the world.
+    a.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / config.tick_rate))),
+        TransformPlugin,
+        HierarchyPlugin,
+        AssetPlugin::default(),
+        bevy::scene::ScenePlugin,
+        RapierPhysicsPlugin::<NoUserData>::default(),
+        JServerPlugin,
+    ))
+    .init_asset::<Mesh>()
+    ;
+
+    a
+}
 
-    if isserver {
-        a.add_plugins(MinimalPlugins)
-        .add_systems(Startup, start_listening)
-        ;
-
-
-    } else {
+/// A client with nothing to show, for replaying what a real one received.
+fn replay_client_app() -> App {
+    let mut a = App::new();
+    a.add_plugins((MinimalPlugins, JClientPlugin, TrafficPlugin))
+    .insert_resource(JMyId { uuid: Uuid::new_v4(), token: [0; 32] })
+    .add_systems(Update, log_server_messages)
+    ;
 
+    a
+}
 
+fn record_traffic(app: &mut App, path: Option<&Path>, side: RecordedSide, config: Option<&ServerConfig>) {
+    let Some(path) = path else {
+        return;
+    };
+    match TrafficRecorder::create(path, side, config) {
+        Ok(recorder) => {
+            println!("Recording traffic to {}", path.display());
+            app.insert_resource(recorder);
+        }
+        Err(e) => {
+            eprintln!("Couldn't record to {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
 
-        a.add_plugins((DefaultPlugins.set (
-            ImagePlugin::default_nearest()
-        ),
-            RapierPhysicsPlugin::<NoUserData>::default()))
-        .init_resource::<JPerlin>()
-        .add_plugins(ChunkPlugin)
-        .insert_resource(AmbientLight {
-            color: Color::WHITE,
-            brightness: 2000.,
-        })
-        .init_resource::<JControls>()
-        .init_resource::<JVars>()
-        .init_resource::<JCamera>()
-        .insert_resource(ChunkSurveyTimer(Timer::new(Duration::from_secs(2), TimerMode::Repeating)))
-        
-        .init_resource::<MyPlayerInitialized>()
-        .insert_resource(
-            {
-                let id = Uuid::new_v4();
-                println!("My id is {}", id);
-                JMyId { uuid: id }
-            }
-           
-        )
-        .add_systems(Startup, start_physical_world)
-        .add_systems(Update, player_movement)
-            //a.add_systems(Update, add_colliders_to_meshes);
-        .add_systems(Startup, initial_grab_cursor)
-        .add_systems(Update, handle_input)
-        .add_systems(Update, set_anim_states)
-        .add_systems(Update, setup_scene_once_loaded.before(animate_targets))
-        .add_systems(Startup, animations_setup)
-        .add_systems(Update, move_from_controls)
-        ;
+fn load_identity(path: &Path) -> JMyId {
+    match load_or_create_identity(path) {
+        Ok(id) => {
+            println!("My id is {}", id.uuid);
+            id
+        }
+        Err(e) => {
+            eprintln!("Couldn't load identity from {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
     }
+}
 
-    a.run();
+fn client_app(connect: Option<JClientConfig>, id: JMyId) -> App {
+    let mut a = App::new();
 
+    a.add_plugins((DefaultPlugins.set (
+        ImagePlugin::default_nearest()
+    ),
+        RapierPhysicsPlugin::<NoUserData>::default()))
+    .init_resource::<JPerlin>()
+    .add_plugins((ChunkPlugin, BlockEditPlugin, ChatPlugin, DaylightPlugin, PlayerStatusPlugin, LanDiscoveryPlugin))
+    .insert_resource(AmbientLight {
+        color: Color::WHITE,
+        brightness: 2000.,
+    })
+    .init_resource::<JControls>()
+    .init_resource::<JVars>()
+    .init_resource::<JCamera>()
+    .insert_resource(ChunkSurveyTimer(Timer::new(Duration::from_secs(2), TimerMode::Repeating)))
     
+    .init_resource::<MyPlayerInitialized>()
+    .insert_resource(id)
+    .add_systems(Startup, start_physical_world)
+    .add_systems(Update, player_movement.run_if(moving_locally))
+        //a.add_systems(Update, add_colliders_to_meshes);
+    .add_systems(Startup, initial_grab_cursor)
+    .add_systems(Update, handle_input.run_if(chat_closed))
+    .add_systems(Update, set_anim_states)
+    .add_systems(Update, setup_scene_once_loaded.before(animate_targets))
+    .add_systems(Startup, animations_setup)
+    .add_systems(Update, move_from_controls)
+    ;
+
+    if let Some(config) = connect {
+        a.insert_resource(config)
+        .add_plugins((JClientPlugin, RemotePlayersPlugin, MobPlugin, PredictionPlugin, ChunkSyncPlugin, TrafficPlugin));
+    }
+
+    a
 }
 
 pub fn set_anim_states(
@@ -240,7 +447,7 @@ fn initial_grab_cursor(mut primary_window: Query<&mut Window, With<PrimaryWindow
 
 
 
-pub static mut GOTTEN_SPOTS: Lazy<DashMap<IVec2, bool>> = Lazy::new(|| DashMap::new());
+pub static mut GOTTEN_SPOTS: Lazy<DashMap<IVec2, Entity>> = Lazy::new(|| DashMap::new());
 
 
 pub fn start_physical_world(mut commands: Commands, asset_server: Res<AssetServer>, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>,
@@ -275,16 +482,6 @@ pub fn start_physical_world(mut commands: Commands, asset_server: Res<AssetServe
     //     }
     // }
 
-    //spawn a test floor
-    commands.spawn((
-        SceneBundle {
-            scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset("models/cityblank.gltf")),
-            ..default()
-        },
-
-        Collider::halfspace(Vec3::Y).unwrap())
-    );
-
     // // Create an empty mesh and get its handle
     // let mesh_handle = meshes.add(Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD));
 
@@ -298,32 +495,15 @@ pub fn start_physical_world(mut commands: Commands, asset_server: Res<AssetServe
 
 
 
-    let trans = Transform::from_xyz(0.0, 1000.0, 0.0);
+    let trans = Transform::from_translation(SPAWN_POSITION);
     commands
         .spawn((
             SpatialBundle {
                 transform: trans,
                 ..default()
             },
-            Collider::round_cylinder(0.99, 0.3, 0.2),
-            KinematicCharacterController {
-                custom_mass: Some(5.0),
-                up: Vec3::Y,
-                offset: CharacterLength::Absolute(0.01),
-                slide: true,
-                autostep: Some(CharacterAutostep {
-                    max_height: CharacterLength::Relative(0.3),
-                    min_width: CharacterLength::Relative(0.5),
-                    include_dynamic_bodies: false,
-                }),
-                // Don’t allow climbing slopes larger than 45 degrees.
-                max_slope_climb_angle: 45.0_f32.to_radians(),
-                // Automatically slide down on slopes smaller than 30 degrees.
-                min_slope_slide_angle: 30.0_f32.to_radians(),
-                apply_impulse_to_dynamic_bodies: true,
-                snap_to_ground: None,
-                ..default()
-            },
+            player_collider(),
+            player_controller(),
             MyCollider{},
             
         ))
@@ -490,7 +670,7 @@ fn handle_input(
     mut head: Query<(
         &mut Transform,
         
-    ), With<MyHead>>
+    ), With<MyHead>>,
 ) {
     controls.f = keyboard.pressed(KeyCode::KeyW);
     controls.b = keyboard.pressed(KeyCode::KeyS);
@@ -550,6 +730,7 @@ fn setup_scene_once_loaded(
     mut players: Query<(Entity, &mut AnimationPlayer), Added<AnimationPlayer>>,
     parents_query: Query<&Parent>,
     id_query: Query<&JId>,
+    mob_query: Query<&ClientMob>,
 ) {
     for (entity, mut player) in &mut players {
         let mut transitions = AnimationTransitions::new();
@@ -566,10 +747,16 @@ fn setup_scene_once_loaded(
             //.insert(JGameCam{})
             ;
         let mut current_jid = None;
+        let mut current_mob = None;
              // Traverse up the parent hierarchy to find an entity with JId
         let mut current_entity = entity;
         while let Ok(parent) = parents_query.get(current_entity) {
             current_entity = parent.get();
+
+            if let Ok(mob) = mob_query.get(current_entity) {
+                current_mob = Some(mob.id);
+                break;
+            }
             
             let jid = id_query.get(current_entity);
 
@@ -592,10 +779,15 @@ fn setup_scene_once_loaded(
             }
         }
 
+        if let Some(id) = current_mob {
+            commands.entity(entity).insert(ChildMob { id });
+        }
+
         
 
 
-        if !(*mpi).value {
+        // Remote avatars have a JId above them and mobs a ClientMob; ours is the only one with neither.
+        if !(*mpi).value && current_jid.is_none() && current_mob.is_none() {
 
             commands.entity(entity).insert(JMyPlayer{});
             
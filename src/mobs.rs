@@ -0,0 +1,683 @@
+use std::{f32::consts::TAU, str::FromStr, time::Duration};
+
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy_quinnet::shared::ClientId;
+use bevy_rapier3d::prelude::*;
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+use noise::{Perlin, Seedable};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    blockedit::is_solid,
+    blockinfo::Blocks,
+    chunk::{biome_at, blockat, spot_to_chunk_pos, Biome, JPerlin, NonUserDataMap, CH, CW},
+    daylight::daylight,
+    jclient::JClientEvent,
+    jserver::{JServerClients, JServerSettings, PlayerLeft},
+    movement::{step_character, yaw_rotation, MovementState, PLAYER_HALF_EXTENTS},
+    pathfinding::{FindPath, NavPath, PathAction, PathAgent, PathSearch},
+    protocol::{send_to_client, FromServer, MobUpdate, ServerMessage},
+    serverworld::{build_chunk_colliders, load_world, ChunkRegistry, WorldTime},
+    snapshot::{ServerClock, Snapshot, SnapshotBuffer},
+    transport::ServerNet,
+    JMoveState, UserDataMap,
+};
+
+pub static MAX_MOBS: usize = 150;
+/// Spawning stops around a player once this many mobs are near them.
+pub static MAX_MOBS_PER_PLAYER: usize = 12;
+pub static SPAWN_INTERVAL: f32 = 2.0;
+/// Mobs appear in a ring around each player, out of sight but not too far to meet.
+pub static SPAWN_MIN_DISTANCE: f32 = 16.0;
+pub static SPAWN_MAX_DISTANCE: f32 = 48.0;
+/// Mobs this far from every player are dropped.
+pub static DESPAWN_DISTANCE: f32 = 96.0;
+/// Hostile mobs notice players this close, and lose them a little further out.
+pub static CHASE_DISTANCE: f32 = 16.0;
+pub static GIVE_UP_DISTANCE: f32 = 24.0;
+/// Chasers stop this close instead of walking into the player.
+pub static CHASE_STOP_DISTANCE: f32 = 1.2;
+pub static WANDER_RADIUS: f32 = 8.0;
+/// Chasers look for a new path at most this often, and only once the old one is used up or stale.
+pub static REPATH_INTERVAL: f64 = 0.5;
+/// How often mob positions go out.
+pub static MOB_SEND_RATE: f32 = 10.0;
+/// Keeps a `MobUpdates` inside one datagram.
+pub static MOB_UPDATES_PER_MESSAGE: usize = 48;
+/// A light block lights everything within this many blocks, fading with distance.
+pub static LIGHT_RADIUS: i32 = 7;
+static WATER: u32 = 2;
+static GRASS: u32 = 3;
+static SAND: u32 = 1;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MobKind {
+    /// Grazes on grassland in daylight.
+    Deer,
+    /// Scuttles about on sand in daylight.
+    Crab,
+    /// Comes out in the dark and goes for players.
+    Lurker,
+}
+
+impl MobKind {
+    pub const ALL: [MobKind; 3] = [MobKind::Deer, MobKind::Crab, MobKind::Lurker];
+
+    pub fn hostile(self) -> bool {
+        self == MobKind::Lurker
+    }
+
+    pub fn half_height(self) -> f32 {
+        match self {
+            MobKind::Deer => 0.9,
+            MobKind::Crab => 0.4,
+            MobKind::Lurker => 1.19,
+        }
+    }
+
+    pub fn radius(self) -> f32 {
+        match self {
+            MobKind::Deer => 0.45,
+            MobKind::Crab => 0.4,
+            MobKind::Lurker => 0.5,
+        }
+    }
+
+    /// Shaped like `player_collider`: a cylinder with rounded edges.
+    pub fn collider(self) -> Collider {
+        Collider::round_cylinder(self.half_height() - 0.2, self.radius() - 0.2, 0.2)
+    }
+
+    pub fn walk_speed(self) -> f32 {
+        match self {
+            MobKind::Deer => 2.5,
+            MobKind::Crab => 1.5,
+            MobKind::Lurker => 2.0,
+        }
+    }
+
+    /// A sprinting player can still get away.
+    pub fn chase_speed(self) -> f32 {
+        match self {
+            MobKind::Lurker => 5.0,
+            _ => self.walk_speed(),
+        }
+    }
+
+    /// `step_character` can't climb yet, so mobs keep off ladders.
+    pub fn path_agent(self) -> PathAgent {
+        PathAgent { can_climb: false, ..PathAgent::character(self.half_height()) }
+    }
+
+    /// Placeholder until mobs get models of their own.
+    pub fn model(self) -> &'static str {
+        "models/player.glb"
+    }
+
+    /// The player model is drawn at 0.3 for a 1.19 half height; keep mobs in proportion.
+    pub fn model_scale(self) -> f32 {
+        0.3 * self.half_height() / 1.19
+    }
+
+    /// Whether this kind may appear standing on `ground` in `biome` at this light level (0 to 15).
+    pub fn can_spawn(self, biome: Biome, ground: u32, light: u8) -> bool {
+        match self {
+            MobKind::Deer => biome == Biome::Grassland && ground == GRASS && light >= 8,
+            MobKind::Crab => ground == SAND && light >= 8,
+            MobKind::Lurker => light <= 4,
+        }
+    }
+}
+
+impl FromStr for MobKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "deer" => Ok(MobKind::Deer),
+            "crab" => Ok(MobKind::Crab),
+            "lurker" => Ok(MobKind::Lurker),
+            _ => Err(format!("{} isn't a mob (deer, crab, lurker)", s)),
+        }
+    }
+}
+
+/// 0 to 15: open sky scaled by the time of day, or the glow of a nearby light block, whichever is brighter.
+pub fn light_level(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, spot: IVec3, daylight: f32) -> u8 {
+    let open = (spot.y + 1..CH).all(|y| !is_solid(blockat(perlin, udm, nudm, &IVec3::new(spot.x, y, spot.z))));
+    let sky = if open { (daylight * 15.0).round() as i32 } else { 0 };
+
+    let mut glow = 0;
+    for x in -LIGHT_RADIUS..=LIGHT_RADIUS {
+        for y in -LIGHT_RADIUS..=LIGHT_RADIUS {
+            for z in -LIGHT_RADIUS..=LIGHT_RADIUS {
+                let distance = x.abs() + y.abs() + z.abs();
+                if 14 - distance <= glow {
+                    continue;
+                }
+                let near = spot + IVec3::new(x, y, z);
+                // Light blocks only come from players and structures, never terrain.
+                if let Some(id) = udm.get(&near).or_else(|| nudm.get(&near)) {
+                    if Blocks::is_light(*id & Blocks::block_id_bits()) {
+                        glow = 14 - distance;
+                    }
+                }
+            }
+        }
+    }
+    sky.max(glow).clamp(0, 15) as u8
+}
+
+/// Server side: spawns, moves and despawns mobs, and tells each client about the ones near them.
+pub struct MobServerPlugin;
+
+impl Plugin for MobServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Mobs>()
+        .init_resource::<MobViewers>()
+        .insert_resource(MobSpawnTimer(Timer::new(Duration::from_secs_f32(SPAWN_INTERVAL), TimerMode::Repeating)))
+        .insert_resource(MobSendTimer(Timer::new(Duration::from_secs_f32(1.0 / MOB_SEND_RATE), TimerMode::Repeating)))
+        .insert_resource(MobRng(StdRng::seed_from_u64(0)))
+        .add_systems(Startup, seed_mob_rng.after(load_world))
+        .add_systems(Update, (spawn_mobs, despawn_far_mobs, plan_mob_paths, forget_mob_viewers, replicate_mobs).chain())
+        .add_systems(FixedUpdate, simulate_mobs.after(build_chunk_colliders))
+        ;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MobBehavior {
+    Idle { until: f64 },
+    Wander { target: Vec3, until: f64 },
+    Chase { client_id: ClientId },
+}
+
+#[derive(Component)]
+pub struct Mob {
+    pub id: u32,
+    pub kind: MobKind,
+    pub behavior: MobBehavior,
+    pub yaw: f32,
+    pub moving: bool,
+    /// Walked into something last tick, so try jumping.
+    pub blocked: bool,
+    pub next_plan: f64,
+}
+
+#[derive(Resource, Default)]
+pub struct Mobs {
+    pub next_id: u32,
+}
+
+impl Mobs {
+    pub fn allocate_id(&mut self) -> u32 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+}
+
+/// Which mobs each client has been told about.
+#[derive(Resource, Default)]
+pub struct MobViewers(pub HashMap<ClientId, HashSet<u32>>);
+
+#[derive(Resource)]
+pub struct MobSpawnTimer(Timer);
+
+/// Where mobs get their luck, seeded from the world so a replay spawns and moves them the same way.
+#[derive(Resource)]
+pub struct MobRng(pub StdRng);
+
+pub fn seed_mob_rng(perlin: Res<JPerlin>, mut rng: ResMut<MobRng>) {
+    rng.0 = StdRng::seed_from_u64(perlin.perlin.seed() as u64);
+}
+
+#[derive(Resource)]
+pub struct MobSendTimer(Timer);
+
+/// Everything a server mob is spawned with; the mob collider is a sensor so mobs don't shove players.
+pub fn mob_bundle(id: u32, kind: MobKind, position: Vec3) -> impl Bundle {
+    (
+        TransformBundle::from_transform(Transform::from_translation(position)),
+        kind.collider(),
+        Sensor,
+        MovementState::default(),
+        Mob {
+            id,
+            kind,
+            behavior: MobBehavior::Idle { until: 0.0 },
+            yaw: 0.0,
+            moving: false,
+            blocked: false,
+            next_plan: 0.0,
+        },
+    )
+}
+
+/// The highest block in this column near `around_y` with room above it for a mob, if any.
+fn find_ground(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, x: i32, z: i32, around_y: i32, headroom: i32) -> Option<IVec3> {
+    let top = (around_y + 24).min(CH - 2);
+    let bottom = (around_y - 24).max(0);
+    let mut clear = 0;
+    for y in (bottom..=top).rev() {
+        let block = blockat(perlin, udm, nudm, &IVec3::new(x, y, z)) & Blocks::block_id_bits();
+        if is_solid(block) {
+            if clear >= headroom {
+                return Some(IVec3::new(x, y, z));
+            }
+            clear = 0;
+        } else if block == WATER {
+            clear = 0;
+        } else {
+            clear += 1;
+        }
+    }
+    None
+}
+
+pub fn spawn_mobs(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut timer: ResMut<MobSpawnTimer>,
+    perlin: Res<JPerlin>,
+    udm: Res<UserDataMap>,
+    nudm: Res<NonUserDataMap>,
+    registry: Res<ChunkRegistry>,
+    world_time: Res<WorldTime>,
+    clients: Res<JServerClients>,
+    mut ids: ResMut<Mobs>,
+    mut rng: ResMut<MobRng>,
+    mobs: Query<&Transform, With<Mob>>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let mut total = mobs.iter().count();
+    let day = daylight(world_time.time);
+    let rng = &mut rng.0;
+
+    for player in clients.players.values() {
+        if total >= MAX_MOBS {
+            return;
+        }
+        let nearby = mobs.iter().filter(|t| t.translation.distance(player.position) <= SPAWN_MAX_DISTANCE).count();
+        if nearby >= MAX_MOBS_PER_PLAYER {
+            continue;
+        }
+
+        let angle = rng.gen_range(0.0..TAU);
+        let distance = rng.gen_range(SPAWN_MIN_DISTANCE..SPAWN_MAX_DISTANCE);
+        let column = (player.position + Vec3::new(angle.cos(), 0.0, angle.sin()) * distance).floor().as_ivec3();
+        if !registry.is_ready(&spot_to_chunk_pos(&column)) {
+            continue;
+        }
+        // Nobody should see a mob pop in next to them.
+        if clients.players.values().any(|p| p.position.distance(column.as_vec3()) < SPAWN_MIN_DISTANCE) {
+            continue;
+        }
+
+        let Some(ground) = find_ground(&perlin.perlin, &udm, &nudm, column.x, column.z, column.y, 3) else {
+            continue;
+        };
+        let block = blockat(&perlin.perlin, &udm, &nudm, &ground) & Blocks::block_id_bits();
+        let biome = biome_at(&perlin.perlin, ground.x, ground.z);
+        let light = light_level(&perlin.perlin, &udm, &nudm, ground + IVec3::Y, day);
+
+        let candidates: Vec<MobKind> = MobKind::ALL.into_iter().filter(|kind| kind.can_spawn(biome, block, light)).collect();
+        let Some(kind) = candidates.choose(rng).copied() else {
+            continue;
+        };
+
+        let position = ground.as_vec3() + Vec3::new(0.5, 1.05 + kind.half_height(), 0.5);
+        commands.spawn(mob_bundle(ids.allocate_id(), kind, position));
+        total += 1;
+    }
+}
+
+pub fn despawn_far_mobs(
+    mut commands: Commands,
+    clients: Res<JServerClients>,
+    registry: Res<ChunkRegistry>,
+    mobs: Query<(Entity, &Transform), With<Mob>>,
+) {
+    for (entity, transform) in mobs.iter() {
+        let position = transform.translation;
+        let near_someone = clients.players.values().any(|p| p.position.distance(position) <= DESPAWN_DISTANCE);
+        let loaded = registry.chunks.contains_key(&spot_to_chunk_pos(&position.floor().as_ivec3()));
+        if !near_someone || !loaded || position.y < -50.0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Picks what a mob wants to do next, and where it's heading if anywhere.
+fn think(mob: &mut Mob, position: Vec3, now: f64, clients: &JServerClients, rng: &mut impl Rng) -> Option<(Vec3, f32)> {
+    if mob.kind.hostile() {
+        let chasing = match mob.behavior {
+            MobBehavior::Chase { client_id } => clients.players.get(&client_id).filter(|p| p.position.distance(position) <= GIVE_UP_DISTANCE),
+            _ => None,
+        };
+        if chasing.is_none() {
+            let nearest = clients.players.iter()
+                .map(|(id, p)| (*id, p.position.distance(position)))
+                .filter(|(_, distance)| *distance <= CHASE_DISTANCE)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            mob.behavior = match nearest {
+                Some((client_id, _)) => MobBehavior::Chase { client_id },
+                None if matches!(mob.behavior, MobBehavior::Chase { .. }) => MobBehavior::Idle { until: now + 2.0 },
+                None => mob.behavior,
+            };
+        }
+    }
+
+    match mob.behavior {
+        MobBehavior::Idle { until } => {
+            if now >= until {
+                let offset = Vec2::from_angle(rng.gen_range(0.0..TAU)) * rng.gen_range(2.0..WANDER_RADIUS);
+                mob.behavior = MobBehavior::Wander {
+                    target: position + Vec3::new(offset.x, 0.0, offset.y),
+                    until: now + 10.0,
+                };
+            }
+            None
+        }
+        MobBehavior::Wander { target, until } => {
+            if now >= until || target.xz().distance(position.xz()) < 0.5 {
+                mob.behavior = MobBehavior::Idle { until: now + rng.gen_range(2.0..6.0) };
+                return None;
+            }
+            Some((target, mob.kind.walk_speed()))
+        }
+        MobBehavior::Chase { client_id } => {
+            let target = clients.players.get(&client_id)?.position;
+            if target.xz().distance(position.xz()) < CHASE_STOP_DISTANCE {
+                // Close enough; just face them.
+                mob.yaw = yaw_towards(position, target).unwrap_or(mob.yaw);
+                return None;
+            }
+            Some((target, mob.kind.chase_speed()))
+        }
+    }
+}
+
+/// The yaw that faces `to` from `from`, matching `yaw_rotation` (forward is +z).
+fn yaw_towards(from: Vec3, to: Vec3) -> Option<f32> {
+    let direction = (to - from).xz();
+    (direction.length_squared() > 0.0001).then(|| direction.x.atan2(direction.y).to_degrees())
+}
+
+/// The block a character's feet are in.
+fn feet_spot(position: Vec3, half_height: f32) -> IVec3 {
+    (position - Vec3::Y * (half_height - 0.1)).floor().as_ivec3()
+}
+
+pub fn plan_mob_paths(
+    mut commands: Commands,
+    time: Res<Time>,
+    clients: Res<JServerClients>,
+    mut mobs: Query<(Entity, &Transform, &mut Mob, Option<&NavPath>), (Without<FindPath>, Without<PathSearch>)>,
+) {
+    let now = time.elapsed_seconds_f64();
+    for (entity, transform, mut mob, path) in mobs.iter_mut() {
+        // Wandering is straight lines; only chasers need to find their way.
+        let MobBehavior::Chase { client_id } = mob.behavior else {
+            if path.is_some() {
+                commands.entity(entity).remove::<NavPath>();
+            }
+            continue;
+        };
+        let Some(player) = clients.players.get(&client_id) else {
+            continue;
+        };
+        if now < mob.next_plan {
+            continue;
+        }
+
+        let goal = feet_spot(player.position, PLAYER_HALF_EXTENTS.y);
+        let stale = path.map(|path| path.finished() || !path.complete || (path.goal - goal).abs().max_element() > 1).unwrap_or(true);
+        if !stale {
+            continue;
+        }
+        mob.next_plan = now + REPATH_INTERVAL;
+        // A despawn may already be queued for it, by `despawn_far_mobs` or a kill.
+        commands.entity(entity).try_insert(FindPath {
+            start: feet_spot(transform.translation, mob.kind.half_height()),
+            goal,
+            agent: mob.kind.path_agent(),
+        });
+    }
+}
+
+pub fn simulate_mobs(
+    time: Res<Time>,
+    mut context: ResMut<RapierContext>,
+    registry: Res<ChunkRegistry>,
+    clients: Res<JServerClients>,
+    mut rng: ResMut<MobRng>,
+    mut mobs: Query<(Entity, &mut Transform, &Collider, &mut MovementState, &mut Mob, Option<&mut NavPath>)>,
+) {
+    let now = time.elapsed_seconds_f64();
+    let dt = time.delta_seconds();
+    let rng = &mut rng.0;
+
+    for (entity, mut transform, collider, mut state, mut mob, mut path) in mobs.iter_mut() {
+        // Don't fall through ground that hasn't been built yet.
+        if !registry.is_ready(&spot_to_chunk_pos(&transform.translation.floor().as_ivec3())) {
+            continue;
+        }
+
+        let position = transform.translation;
+        let feet = position - Vec3::Y * mob.kind.half_height();
+        let mut heading = think(&mut mob, position, now, &clients, rng);
+        let mut path_jump = false;
+        // Chasers follow their path while it lasts, and head straight for the player without one.
+        if let (MobBehavior::Chase { .. }, Some((_, speed)), Some(path)) = (mob.behavior, heading, path.as_mut()) {
+            if let Some(step) = path.current(feet) {
+                let center = step.spot.as_vec3() + Vec3::new(0.5, 0.0, 0.5);
+                heading = Some((center, speed));
+                path_jump = step.action == PathAction::Jump && center.xz().distance(feet.xz()) < 1.0;
+            }
+        }
+        let speed = match heading {
+            Some((target, speed)) => {
+                mob.yaw = yaw_towards(position, target).unwrap_or(mob.yaw);
+                speed
+            }
+            None => 0.0,
+        };
+
+        let jump = (mob.blocked || path_jump) && state.grounded;
+        let moved = step_character(&mut context, collider, entity, position, yaw_rotation(mob.yaw), &mut state, Vec3::Z * speed, jump, dt);
+
+        mob.moving = speed > 0.0;
+        mob.blocked = mob.moving && (moved - position).xz().length() < speed * dt * 0.25;
+        transform.translation = moved;
+        transform.rotation = yaw_rotation(mob.yaw);
+    }
+}
+
+pub fn forget_mob_viewers(mut viewers: ResMut<MobViewers>, mut left: EventReader<PlayerLeft>) {
+    for event in left.read() {
+        viewers.0.remove(&event.client_id);
+    }
+}
+
+pub fn replicate_mobs(
+    time: Res<Time>,
+    mut timer: ResMut<MobSendTimer>,
+    mut net: ServerNet,
+    settings: Res<JServerSettings>,
+    clients: Res<JServerClients>,
+    mut viewers: ResMut<MobViewers>,
+    mobs: Query<(&Mob, &Transform)>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let range = (settings.view_distance * CW) as f32;
+
+    for (client_id, player) in clients.players.iter() {
+        let visible: Vec<(&Mob, &Transform)> = mobs.iter()
+            .filter(|(_, t)| t.translation.xz().distance(player.position.xz()) <= range)
+            .collect();
+        let visible_ids: HashSet<u32> = visible.iter().map(|(mob, _)| mob.id).collect();
+        let seen = viewers.0.entry(*client_id).or_default();
+
+        for id in seen.difference(&visible_ids) {
+            send_to_client(&mut net, *client_id, &ServerMessage::MobDespawned { id: *id });
+        }
+        for (mob, transform) in visible.iter().filter(|(mob, _)| !seen.contains(&mob.id)) {
+            send_to_client(&mut net, *client_id, &ServerMessage::MobSpawned {
+                id: mob.id,
+                kind: mob.kind,
+                position: transform.translation.to_array(),
+                yaw: mob.yaw,
+            });
+        }
+        *seen = visible_ids;
+
+        let updates: Vec<MobUpdate> = visible.iter()
+            .map(|(mob, transform)| MobUpdate {
+                id: mob.id,
+                position: transform.translation.to_array(),
+                yaw: mob.yaw,
+                moving: mob.moving,
+            })
+            .collect();
+        for batch in updates.chunks(MOB_UPDATES_PER_MESSAGE) {
+            send_to_client(&mut net, *client_id, &ServerMessage::MobUpdates {
+                server_time: time.elapsed_seconds_f64(),
+                mobs: batch.to_vec(),
+            });
+        }
+    }
+}
+
+/// Client side: draws the mobs the server tells us about, interpolated like remote players.
+pub struct MobPlugin;
+
+impl Plugin for MobPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClientMobs>()
+        .init_resource::<ServerClock>()
+        .add_systems(Update, (receive_mobs, interpolate_mobs, animate_mobs, clear_mobs).chain())
+        ;
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ClientMobs {
+    pub entities: HashMap<u32, Entity>,
+}
+
+#[derive(Component)]
+pub struct ClientMob {
+    pub id: u32,
+    pub moving: bool,
+}
+
+/// On the AnimationPlayer entity deep in a mob's glTF scene, see `setup_scene_once_loaded`.
+#[derive(Component)]
+pub struct ChildMob {
+    pub id: u32,
+}
+
+pub fn receive_mobs(
+    mut commands: Commands,
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    mut clock: ResMut<ServerClock>,
+    mut client_mobs: ResMut<ClientMobs>,
+    mut messages: EventReader<FromServer>,
+    mut mobs: Query<(&mut ClientMob, &mut SnapshotBuffer)>,
+) {
+    for FromServer(message) in messages.read() {
+        match message {
+            ServerMessage::MobSpawned { id, kind, position, yaw } => {
+                if client_mobs.entities.contains_key(id) {
+                    continue;
+                }
+                let transform = Transform::from_translation(Vec3::from_array(*position)).with_rotation(yaw_rotation(*yaw));
+                let entity = commands
+                    .spawn((
+                        SpatialBundle::from_transform(transform),
+                        ClientMob { id: *id, moving: false },
+                        SnapshotBuffer::default(),
+                    ))
+                    .with_children(|b| {
+                        b.spawn(SceneBundle {
+                            scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset(kind.model())),
+                            transform: Transform::from_scale(Vec3::ONE * kind.model_scale()),
+                            ..default()
+                        });
+                    })
+                    .id();
+                client_mobs.entities.insert(*id, entity);
+            }
+            ServerMessage::MobDespawned { id } => {
+                if let Some(entity) = client_mobs.entities.remove(id) {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+            ServerMessage::MobUpdates { server_time, mobs: updates } => {
+                clock.observe(*server_time, time.elapsed_seconds_f64());
+                for update in updates.iter() {
+                    let Some((mut mob, mut buffer)) = client_mobs.entities.get(&update.id).and_then(|e| mobs.get_mut(*e).ok()) else {
+                        continue;
+                    };
+                    mob.moving = update.moving;
+                    buffer.push(Snapshot {
+                        time: *server_time,
+                        position: Vec3::from_array(update.position),
+                        yaw: update.yaw,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn interpolate_mobs(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    mut mobs: Query<(&mut Transform, &mut SnapshotBuffer), With<ClientMob>>,
+) {
+    let Some(render_time) = clock.render_time(time.elapsed_seconds_f64()) else {
+        return;
+    };
+
+    for (mut transform, mut buffer) in mobs.iter_mut() {
+        if let Some(snapshot) = buffer.sample(render_time) {
+            transform.translation = snapshot.position;
+            transform.rotation = yaw_rotation(snapshot.yaw);
+        }
+    }
+}
+
+pub fn animate_mobs(
+    client_mobs: Res<ClientMobs>,
+    mobs: Query<&ClientMob>,
+    mut movestates: Query<(&mut JMoveState, &ChildMob)>,
+) {
+    for (mut movestate, child) in movestates.iter_mut() {
+        if let Some(mob) = client_mobs.entities.get(&child.id).and_then(|e| mobs.get(*e).ok()) {
+            movestate.moving = mob.moving;
+        }
+    }
+}
+
+pub fn clear_mobs(
+    mut commands: Commands,
+    mut client_mobs: ResMut<ClientMobs>,
+    mut clock: ResMut<ServerClock>,
+    mut events: EventReader<JClientEvent>,
+) {
+    for event in events.read() {
+        if let JClientEvent::Disconnected = event {
+            for (_, entity) in client_mobs.entities.drain() {
+                commands.entity(entity).despawn_recursive();
+            }
+            *clock = ServerClock::default();
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+
+pub const GROUND_TIMER: f32 = 0.5;
+pub const MOVEMENT_SPEED: f32 = 4.0;
+pub const JUMP_SPEED: f32 = 14.0;
+pub const GRAVITY: f32 = -9.81;
+pub const PLAYER_MASS: f32 = 5.0;
+pub const SPAWN_POSITION: Vec3 = Vec3::new(0.0, 1000.0, 0.0);
+/// Where the head sits above the player's origin.
+pub const EYE_HEIGHT: f32 = 0.8;
+/// Half the extents of the player collider's bounding box.
+pub const PLAYER_HALF_EXTENTS: Vec3 = Vec3::new(0.5, 1.19, 0.5);
+/// Client prediction and the server both step movement at this rate.
+pub const MOVEMENT_TICK_RATE: f64 = 60.0;
+
+/// One tick of player input: `JControls` plus where we were facing.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MovementInput {
+    pub seq: u32,
+    pub forward: bool,
+    pub back: bool,
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+    pub sprinting: bool,
+    pub yaw: f32,
+}
+
+impl MovementInput {
+    pub fn moving(&self) -> bool {
+        self.forward || self.back || self.left || self.right
+    }
+}
+
+/// What `player_movement` keeps in `Local`s, made explicit so it can be sent and replayed.
+#[derive(Component, BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MovementState {
+    pub vertical_movement: f32,
+    pub grounded_timer: f32,
+    pub grounded: bool,
+}
+
+pub fn player_collider() -> Collider {
+    Collider::round_cylinder(0.99, 0.3, 0.2)
+}
+
+pub fn player_controller() -> KinematicCharacterController {
+    KinematicCharacterController {
+        custom_mass: Some(PLAYER_MASS),
+        up: Vec3::Y,
+        offset: CharacterLength::Absolute(0.01),
+        slide: true,
+        autostep: Some(CharacterAutostep {
+            max_height: CharacterLength::Relative(0.3),
+            min_width: CharacterLength::Relative(0.5),
+            include_dynamic_bodies: false,
+        }),
+        // Don’t allow climbing slopes larger than 45 degrees.
+        max_slope_climb_angle: 45.0_f32.to_radians(),
+        // Automatically slide down on slopes smaller than 30 degrees.
+        min_slope_slide_angle: 30.0_f32.to_radians(),
+        apply_impulse_to_dynamic_bodies: true,
+        snap_to_ground: None,
+        ..default()
+    }
+}
+
+/// The same settings as `player_controller`, for moving the shape by hand.
+pub fn player_move_options() -> MoveShapeOptions {
+    let controller = player_controller();
+    MoveShapeOptions {
+        up: controller.up,
+        offset: controller.offset,
+        slide: controller.slide,
+        autostep: controller.autostep,
+        max_slope_climb_angle: controller.max_slope_climb_angle,
+        min_slope_slide_angle: controller.min_slope_slide_angle,
+        apply_impulse_to_dynamic_bodies: controller.apply_impulse_to_dynamic_bodies,
+        snap_to_ground: controller.snap_to_ground,
+        ..default()
+    }
+}
+
+/// Whether a player standing at `position` would be inside a block at `spot`.
+pub fn player_overlaps_block(position: Vec3, spot: IVec3) -> bool {
+    let min = position - PLAYER_HALF_EXTENTS;
+    let max = position + PLAYER_HALF_EXTENTS;
+    let bmin = spot.as_vec3();
+    let bmax = bmin + Vec3::ONE;
+    min.cmplt(bmax).all() && max.cmpgt(bmin).all()
+}
+
+pub fn yaw_rotation(yaw: f32) -> Quat {
+    Quat::from_euler(EulerRot::YXZ, yaw.to_radians(), 0.0, 0.0)
+}
+
+/// Steps one input through the character controller immediately and returns the new position.
+/// This is `player_movement` without the ECS, so client prediction, replay and the server agree.
+pub fn simulate_input(
+    context: &mut RapierContext,
+    collider: &Collider,
+    exclude: Entity,
+    position: Vec3,
+    state: &mut MovementState,
+    input: &MovementInput,
+    dt: f32,
+) -> Vec3 {
+    let mut wish = Vec3::ZERO;
+    if input.left {
+        wish.x += 1.0;
+    }
+    if input.right {
+        wish.x -= 1.0;
+    }
+    if input.forward {
+        wish.z += 1.0;
+    }
+    if input.back {
+        wish.z -= 1.0;
+    }
+
+    let velocity = wish * MOVEMENT_SPEED * if input.sprinting { 1.8 } else { 1.0 };
+    step_character(context, collider, exclude, position, yaw_rotation(input.yaw), state, velocity, input.jump, dt)
+}
+
+/// One tick of walking, gravity, jumping and collision for anything with a character collider.
+/// `velocity` is horizontal and in the character's own frame, so +z is straight ahead.
+#[allow(clippy::too_many_arguments)]
+pub fn step_character(
+    context: &mut RapierContext,
+    collider: &Collider,
+    exclude: Entity,
+    position: Vec3,
+    rotation: Quat,
+    state: &mut MovementState,
+    velocity: Vec3,
+    jump: bool,
+    dt: f32,
+) -> Vec3 {
+    let mut movement = velocity;
+    let jump_speed = if jump { JUMP_SPEED } else { 0.0 };
+
+    if state.grounded {
+        state.grounded_timer = GROUND_TIMER;
+        state.vertical_movement = 0.0;
+    }
+    if state.grounded_timer > 0.0 {
+        state.grounded_timer -= dt;
+        if jump_speed > 0.0 {
+            state.vertical_movement = jump_speed;
+            state.grounded_timer = 0.0;
+        }
+    }
+    movement.y = state.vertical_movement;
+    state.vertical_movement += GRAVITY * dt * PLAYER_MASS;
+
+    let output = context.move_shape(
+        rotation * (movement * dt),
+        collider,
+        position,
+        rotation,
+        PLAYER_MASS,
+        &player_move_options(),
+        QueryFilter::default().exclude_collider(exclude),
+        |_| {},
+    );
+
+    state.grounded = output.grounded;
+    position + output.effective_translation
+}
@@ -0,0 +1,599 @@
+use std::{cmp::Ordering, collections::BinaryHeap, f32::consts::SQRT_2};
+
+use bevy::{prelude::*, tasks::{futures_lite::future, poll_once, AsyncComputeTaskPool, Task}, utils::HashMap};
+use bevy_rapier3d::prelude::CharacterLength;
+use noise::Perlin;
+
+use crate::{
+    blockedit::is_solid,
+    blockinfo::Blocks,
+    chunk::{natural_blockat, spot_to_chunk_pos, JPerlin, NonUserDataMap, CH},
+    movement::{player_controller, GRAVITY, JUMP_SPEED, PLAYER_MASS},
+    serverworld::{BlockEdited, BlocksChanged},
+    traffic::{ReplayLink, TrafficRecorder},
+    UserDataMap,
+};
+
+/// A search gives up after expanding this many nodes and returns the best partial path.
+pub static MAX_SEARCH_NODES: usize = 6000;
+/// How far around the start and goal a search may wander, sideways and up or down.
+pub static SEARCH_MARGIN: i32 = 16;
+pub static SEARCH_MARGIN_Y: i32 = 8;
+/// Searches started per tick; each copies the edits around it before going off-thread.
+pub static MAX_SEARCHES_PER_TICK: usize = 8;
+/// Cached paths are dropped after this long even if nothing around them changed.
+pub static PATH_CACHE_TTL: f64 = 10.0;
+pub static MAX_CACHED_PATHS: usize = 512;
+/// A jump that only just clears a ledge usually doesn't, so leave this much spare.
+static JUMP_MARGIN: f32 = 0.5;
+static WATER: u32 = 2;
+static JUMP_COST: f32 = 0.5;
+static DROP_COST: f32 = 0.25;
+static CLIMB_COST: f32 = 1.5;
+static SWIM_COST: f32 = 3.0;
+
+/// What a walker can do, in whole blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PathAgent {
+    /// Blocks of headroom the body needs.
+    pub height: i32,
+    /// Rises the character controller's autostep takes in stride.
+    pub step: i32,
+    /// Rises that need a jump.
+    pub jump: i32,
+    pub max_drop: i32,
+    pub can_climb: bool,
+    pub can_swim: bool,
+}
+
+impl PathAgent {
+    /// Something moved by `step_character` whose collider reaches `half_height` above and below its origin.
+    pub fn character(half_height: f32) -> Self {
+        let height = half_height * 2.0;
+        // Same reading of the autostep as rapier: relative lengths scale with the shape's height.
+        let step = match player_controller().autostep.map(|autostep| autostep.max_height) {
+            Some(CharacterLength::Relative(fraction)) => fraction * height,
+            Some(CharacterLength::Absolute(length)) => length,
+            None => 0.0,
+        };
+        let jump = JUMP_SPEED * JUMP_SPEED / (2.0 * -GRAVITY * PLAYER_MASS) - JUMP_MARGIN;
+        Self {
+            height: height.ceil() as i32,
+            step: step.floor() as i32,
+            jump: jump.max(step).floor() as i32,
+            max_drop: 3,
+            can_climb: true,
+            can_swim: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathAction {
+    Walk,
+    Jump,
+    Drop,
+    Climb,
+    Swim,
+}
+
+/// Stand with your feet in `spot`'s block, getting there by `action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathStep {
+    pub spot: IVec3,
+    pub action: PathAction,
+}
+
+/// A found path, or as close as the search got. Replaces `FindPath` on the entity that asked.
+#[derive(Component, Debug, Clone, Default)]
+pub struct NavPath {
+    pub goal: IVec3,
+    /// Not including where the search started.
+    pub steps: Vec<PathStep>,
+    /// False when the goal couldn't be reached and this only gets closer.
+    pub complete: bool,
+    pub next: usize,
+}
+
+impl NavPath {
+    /// The step to head for with feet at `feet`, skipping any already reached.
+    pub fn current(&mut self, feet: Vec3) -> Option<PathStep> {
+        while let Some(step) = self.steps.get(self.next) {
+            let center = step.spot.as_vec3() + Vec3::new(0.5, 0.0, 0.5);
+            if center.xz().distance(feet.xz()) < 0.35 && (feet.y - center.y).abs() < 0.6 {
+                self.next += 1;
+            } else {
+                return Some(*step);
+            }
+        }
+        None
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.steps.len()
+    }
+}
+
+/// Where the search reads blocks from: the terrain generator plus a copy of the edits in its box,
+/// so it can run off the main thread.
+pub struct BlockSource {
+    perlin: Perlin,
+    placed: HashMap<IVec3, u32>,
+    memo: HashMap<IVec3, u32>,
+}
+
+impl BlockSource {
+    pub fn capture(perlin: &Perlin, udm: &UserDataMap, nudm: &NonUserDataMap, min: IVec3, max: IVec3) -> Self {
+        let inside = |spot: &IVec3| spot.cmpge(min).all() && spot.cmple(max).all();
+        let mut placed = HashMap::new();
+        // Only the chunks the box touches; player edits go in last so they win over structures, as in `blockat`.
+        let (mincpos, maxcpos) = (spot_to_chunk_pos(&min), spot_to_chunk_pos(&max));
+        let cposes: Vec<IVec2> = (mincpos.x..=maxcpos.x).flat_map(|x| (mincpos.y..=maxcpos.y).map(move |z| IVec2::new(x, z))).collect();
+        let structures = cposes.iter().filter_map(|cpos| nudm.chunk(cpos));
+        let edits = cposes.iter().filter_map(|cpos| udm.chunk(cpos));
+        for (spot, block) in structures.chain(edits).flat_map(|blocks| blocks.iter()) {
+            if inside(spot) {
+                placed.insert(*spot, *block & Blocks::block_id_bits());
+            }
+        }
+        Self { perlin: *perlin, placed, memo: HashMap::new() }
+    }
+
+    pub fn block(&mut self, spot: IVec3) -> u32 {
+        if spot.y < 0 {
+            return 15;
+        }
+        if spot.y >= CH {
+            return 0;
+        }
+        if let Some(block) = self.placed.get(&spot) {
+            return *block;
+        }
+        let perlin = &self.perlin;
+        *self.memo.entry(spot).or_insert_with(|| natural_blockat(perlin, &spot) & Blocks::block_id_bits())
+    }
+}
+
+struct Search<'a> {
+    source: &'a mut BlockSource,
+    agent: PathAgent,
+    min: IVec3,
+    max: IVec3,
+}
+
+impl Search<'_> {
+    /// Whether a body can be in this block.
+    fn open(&mut self, spot: IVec3) -> bool {
+        let block = self.source.block(spot);
+        match block {
+            0 => true,
+            _ if block == WATER => self.agent.can_swim,
+            _ if Blocks::is_climbable(block) => self.agent.can_climb,
+            // Doors included: a door has no open state and collides like any other block.
+            _ => !is_solid(block),
+        }
+    }
+
+    fn body_clear(&mut self, feet: IVec3) -> bool {
+        (0..self.agent.height).all(|y| self.open(feet + IVec3::Y * y))
+    }
+
+    fn in_water(&mut self, feet: IVec3) -> bool {
+        self.source.block(feet) == WATER || self.source.block(feet - IVec3::Y) == WATER
+    }
+
+    fn on_ladder(&mut self, feet: IVec3) -> bool {
+        self.agent.can_climb && Blocks::is_climbable(self.source.block(feet))
+    }
+
+    fn standable(&mut self, feet: IVec3) -> bool {
+        if feet.cmplt(self.min).any() || feet.cmpgt(self.max).any() || !self.body_clear(feet) {
+            return false;
+        }
+        let below = self.source.block(feet - IVec3::Y);
+        is_solid(below) || self.on_ladder(feet) || (self.agent.can_swim && self.in_water(feet))
+    }
+
+    /// Every move out of `from`, with its cost.
+    fn moves(&mut self, from: IVec3) -> Vec<(PathStep, f32)> {
+        let mut moves = Vec::new();
+        let height = self.agent.height;
+
+        for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)] {
+            let diagonal = dx != 0 && dz != 0;
+            // No cutting corners: both sides of a diagonal have to be clear.
+            if diagonal && !(self.body_clear(from + IVec3::new(dx, 0, 0)) && self.body_clear(from + IVec3::new(0, 0, dz))) {
+                continue;
+            }
+
+            // The highest place to stand in the next column that we can reach.
+            for y in (from.y - self.agent.max_drop..=from.y + self.agent.jump).rev() {
+                let to = IVec3::new(from.x + dx, y, from.z + dz);
+                if !self.standable(to) {
+                    continue;
+                }
+                let clear = if y > from.y {
+                    // Room overhead to jump up before moving across.
+                    (from.y + height..y + height).all(|cy| self.open(IVec3::new(from.x, cy, from.z)))
+                } else {
+                    // Room in the next column to move across and fall down it.
+                    (y..from.y + height).all(|cy| self.open(IVec3::new(to.x, cy, to.z)))
+                };
+                if !clear {
+                    continue;
+                }
+
+                let rise = y - from.y;
+                let mut cost = if diagonal { SQRT_2 } else { 1.0 };
+                let mut action = PathAction::Walk;
+                if rise > self.agent.step {
+                    action = PathAction::Jump;
+                    cost += JUMP_COST;
+                } else if rise < -self.agent.step {
+                    action = PathAction::Drop;
+                    cost += DROP_COST * -rise as f32;
+                }
+                if self.in_water(to) {
+                    action = PathAction::Swim;
+                    cost *= SWIM_COST;
+                }
+                moves.push((PathStep { spot: to, action }, cost));
+                break;
+            }
+        }
+
+        if self.agent.can_climb {
+            let up = from + IVec3::Y;
+            if (self.on_ladder(from) || self.on_ladder(up)) && self.standable(up) {
+                moves.push((PathStep { spot: up, action: PathAction::Climb }, CLIMB_COST));
+            }
+            let down = from - IVec3::Y;
+            if self.on_ladder(down) && self.standable(down) {
+                moves.push((PathStep { spot: down, action: PathAction::Climb }, CLIMB_COST));
+            }
+        }
+        moves
+    }
+}
+
+/// Octile distance across the ground. Height is left out: drops can be nearly free.
+fn heuristic(a: IVec3, b: IVec3) -> f32 {
+    let dx = (a.x - b.x).abs() as f32;
+    let dz = (a.z - b.z).abs() as f32;
+    dx.max(dz) + (SQRT_2 - 1.0) * dx.min(dz)
+}
+
+#[derive(PartialEq)]
+struct Frontier {
+    estimate: f32,
+    spot: IVec3,
+}
+
+impl Eq for Frontier {}
+
+impl Ord for Frontier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap pops the largest, so the cheapest estimate has to compare greatest.
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+impl PartialOrd for Frontier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The box a search between these two spots stays inside.
+pub fn search_bounds(start: IVec3, goal: IVec3) -> (IVec3, IVec3) {
+    let margin = IVec3::new(SEARCH_MARGIN, SEARCH_MARGIN_Y, SEARCH_MARGIN);
+    let min = (start.min(goal) - margin).max(IVec3::new(i32::MIN, 0, i32::MIN));
+    let max = (start.max(goal) + margin).min(IVec3::new(i32::MAX, CH - 1, i32::MAX));
+    (min, max)
+}
+
+/// A* from feet at `start` to feet at `goal`. When the goal can't be reached, returns the way to
+/// the closest spot found instead, with `complete` false.
+pub fn find_path(source: &mut BlockSource, start: IVec3, goal: IVec3, agent: PathAgent) -> NavPath {
+    let (min, max) = search_bounds(start, goal);
+    let mut search = Search { source, agent, min, max };
+
+    let mut frontier = BinaryHeap::new();
+    let mut cost_so_far: HashMap<IVec3, f32> = HashMap::new();
+    let mut came_from: HashMap<IVec3, PathStep> = HashMap::new();
+    let mut closest = (heuristic(start, goal), start);
+
+    frontier.push(Frontier { estimate: heuristic(start, goal), spot: start });
+    cost_so_far.insert(start, 0.0);
+
+    let mut expanded = 0;
+    while let Some(Frontier { spot, .. }) = frontier.pop() {
+        if spot == goal {
+            closest = (0.0, spot);
+            break;
+        }
+        expanded += 1;
+        if expanded > MAX_SEARCH_NODES {
+            break;
+        }
+
+        let cost = cost_so_far[&spot];
+        for (step, step_cost) in search.moves(spot) {
+            let next_cost = cost + step_cost;
+            if cost_so_far.get(&step.spot).map(|c| next_cost >= *c).unwrap_or(false) {
+                continue;
+            }
+            cost_so_far.insert(step.spot, next_cost);
+            came_from.insert(step.spot, PathStep { spot, action: step.action });
+
+            let remaining = heuristic(step.spot, goal);
+            if remaining < closest.0 {
+                closest = (remaining, step.spot);
+            }
+            frontier.push(Frontier { estimate: next_cost + remaining, spot: step.spot });
+        }
+    }
+
+    // Walk back from the end; each entry says where we came from and how we got here.
+    let mut steps = Vec::new();
+    let mut spot = closest.1;
+    while let Some(previous) = came_from.get(&spot) {
+        steps.push(PathStep { spot, action: previous.action });
+        spot = previous.spot;
+    }
+    steps.reverse();
+
+    NavPath { goal, steps, complete: closest.1 == goal, next: 0 }
+}
+
+/// Insert on an entity to have a path found for it off-thread; it's swapped for a `NavPath`.
+#[derive(Component, Debug, Clone)]
+pub struct FindPath {
+    pub start: IVec3,
+    pub goal: IVec3,
+    pub agent: PathAgent,
+}
+
+#[derive(Component)]
+pub struct PathSearch {
+    key: (IVec3, IVec3, PathAgent),
+    task: Task<NavPath>,
+}
+
+struct CachedPath {
+    path: NavPath,
+    found: f64,
+    min: IVec3,
+    max: IVec3,
+}
+
+/// Recent answers by start, goal and agent, until a block in their search box changes.
+#[derive(Resource, Default)]
+pub struct PathCache {
+    paths: HashMap<(IVec3, IVec3, PathAgent), CachedPath>,
+}
+
+impl PathCache {
+    fn get(&self, key: &(IVec3, IVec3, PathAgent), now: f64) -> Option<&NavPath> {
+        self.paths.get(key).filter(|cached| now - cached.found <= PATH_CACHE_TTL).map(|cached| &cached.path)
+    }
+
+    fn invalidate(&mut self, min: IVec3, max: IVec3) {
+        self.paths.retain(|_, cached| cached.max.cmplt(min).any() || cached.min.cmpgt(max).any());
+    }
+}
+
+/// Server side: runs `FindPath` requests on the compute task pool.
+pub struct PathfindingPlugin;
+
+impl Plugin for PathfindingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PathCache>()
+        .add_systems(Update, (invalidate_paths, start_path_searches, finish_path_searches).chain())
+        ;
+    }
+}
+
+pub fn invalidate_paths(
+    time: Res<Time>,
+    mut cache: ResMut<PathCache>,
+    mut changes: EventReader<BlocksChanged>,
+    mut edits: EventReader<BlockEdited>,
+) {
+    for change in changes.read() {
+        cache.invalidate(change.min, change.max);
+    }
+    for edit in edits.read() {
+        cache.invalidate(edit.spot, edit.spot);
+    }
+    let now = time.elapsed_seconds_f64();
+    cache.paths.retain(|_, cached| now - cached.found <= PATH_CACHE_TTL);
+}
+
+pub fn start_path_searches(
+    mut commands: Commands,
+    time: Res<Time>,
+    perlin: Res<JPerlin>,
+    udm: Res<UserDataMap>,
+    nudm: Res<NonUserDataMap>,
+    cache: Res<PathCache>,
+    requests: Query<(Entity, &FindPath), Without<PathSearch>>,
+) {
+    let task_pool = AsyncComputeTaskPool::get();
+    let now = time.elapsed_seconds_f64();
+
+    for (entity, request) in requests.iter().take(MAX_SEARCHES_PER_TICK) {
+        let key = (request.start, request.goal, request.agent);
+        if let Some(path) = cache.get(&key, now) {
+            commands.entity(entity).remove::<FindPath>().try_insert(path.clone());
+            continue;
+        }
+
+        let (min, max) = search_bounds(request.start, request.goal);
+        let mut source = BlockSource::capture(&perlin.perlin, &udm, &nudm, min, max);
+        let (start, goal, agent) = key;
+        let task = task_pool.spawn(async move { find_path(&mut source, start, goal, agent) });
+        commands.entity(entity).remove::<FindPath>().try_insert(PathSearch { key, task });
+    }
+}
+
+/// Searches land whenever they're done, except while recording or replaying, when each one is
+/// waited for on the frame after it started so a replay gets its path on the same frame.
+pub fn finish_path_searches(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut cache: ResMut<PathCache>,
+    recorder: Option<Res<TrafficRecorder>>,
+    replay: Option<Res<ReplayLink>>,
+    mut searches: Query<(Entity, &mut PathSearch)>,
+) {
+    let wait = recorder.is_some() || replay.is_some();
+    for (entity, mut search) in searches.iter_mut() {
+        let found = if wait {
+            Some(future::block_on(&mut search.task))
+        } else {
+            future::block_on(poll_once(&mut search.task))
+        };
+        let Some(path) = found else {
+            continue;
+        };
+        // Whoever asked may be despawned by a command queued ahead of this one.
+        commands.entity(entity).remove::<PathSearch>().try_insert(path.clone());
+
+        if cache.paths.len() >= MAX_CACHED_PATHS {
+            cache.paths.clear();
+        }
+        let (min, max) = search_bounds(search.key.0, search.key.1);
+        cache.paths.insert(search.key, CachedPath { path, found: time.elapsed_seconds_f64(), min, max });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static STONE: u32 = 1;
+    static LADDER: u32 = 20;
+    static DOOR: u32 = 19;
+
+    fn walker() -> PathAgent {
+        PathAgent { height: 2, step: 0, jump: 1, max_drop: 3, can_climb: false, can_swim: false }
+    }
+
+    /// Air over a stone floor at y = 0 across the whole search box, then `blocks` on top,
+    /// filled across every z so the way round is never shorter.
+    fn world(start: IVec3, goal: IVec3, blocks: &[(i32, i32, u32)]) -> BlockSource {
+        let (min, max) = search_bounds(start, goal);
+        let mut placed = HashMap::new();
+        for x in min.x..=max.x {
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    placed.insert(IVec3::new(x, y, z), if y == 0 { STONE } else { 0 });
+                }
+                for (bx, by, block) in blocks {
+                    if *bx == x {
+                        placed.insert(IVec3::new(x, *by, z), *block);
+                    }
+                }
+            }
+        }
+        BlockSource { perlin: Perlin::new(0), placed, memo: HashMap::new() }
+    }
+
+    /// A wall at x = 3 with a doorway at z = 6, and a door in it at z = 0.
+    fn walled(start: IVec3, goal: IVec3) -> BlockSource {
+        let wall: Vec<(i32, i32, u32)> = (1..=4).map(|y| (3, y, STONE)).collect();
+        let mut source = world(start, goal, &wall);
+        for y in 1..=2 {
+            source.placed.insert(IVec3::new(3, y, 6), 0);
+            source.placed.insert(IVec3::new(3, y, 0), DOOR);
+        }
+        source
+    }
+
+    fn actions(path: &NavPath) -> Vec<PathAction> {
+        path.steps.iter().map(|step| step.action).collect()
+    }
+
+    #[test]
+    fn walks_across_flat_ground() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(5, 1, 0));
+        let path = find_path(&mut world(start, goal, &[]), start, goal, walker());
+        assert!(path.complete);
+        assert_eq!(path.steps.len(), 5);
+        assert_eq!(path.steps.last().unwrap().spot, goal);
+        assert!(actions(&path).iter().all(|action| *action == PathAction::Walk));
+    }
+
+    #[test]
+    fn steps_up_what_it_can_and_jumps_the_rest() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(4, 2, 0));
+        let ledge: Vec<(i32, i32, u32)> = (2..=20).map(|x| (x, 1, STONE)).collect();
+
+        let path = find_path(&mut world(start, goal, &ledge), start, goal, PathAgent { step: 1, ..walker() });
+        assert!(path.complete);
+        assert!(actions(&path).iter().all(|action| *action == PathAction::Walk));
+
+        let path = find_path(&mut world(start, goal, &ledge), start, goal, walker());
+        assert!(path.complete);
+        assert_eq!(actions(&path).iter().filter(|action| **action == PathAction::Jump).count(), 1);
+    }
+
+    #[test]
+    fn wont_jump_higher_than_it_can() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(4, 3, 0));
+        let wall: Vec<(i32, i32, u32)> = (2..=20).flat_map(|x| [(x, 1, STONE), (x, 2, STONE)]).collect();
+        let path = find_path(&mut world(start, goal, &wall), start, goal, walker());
+        assert!(!path.complete);
+    }
+
+    #[test]
+    fn drops_down_as_far_as_it_may() {
+        let cliff = |height: i32| -> Vec<(i32, i32, u32)> { (-20..=0).flat_map(|x| (1..=height).map(move |y| (x, y, STONE))).collect() };
+
+        let (start, goal) = (IVec3::new(0, 4, 0), IVec3::new(3, 1, 0));
+        let path = find_path(&mut world(start, goal, &cliff(3)), start, goal, walker());
+        assert!(path.complete);
+        assert!(actions(&path).contains(&PathAction::Drop));
+
+        let (start, goal) = (IVec3::new(0, 5, 0), IVec3::new(3, 1, 0));
+        let path = find_path(&mut world(start, goal, &cliff(4)), start, goal, walker());
+        assert!(!path.complete);
+    }
+
+    #[test]
+    fn climbs_ladders_if_it_can() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(2, 5, 0));
+        let mut wall: Vec<(i32, i32, u32)> = (2..=20).flat_map(|x| (1..=4).map(move |y| (x, y, STONE))).collect();
+        wall.extend((1..=4).map(|y| (1, y, LADDER)));
+
+        let path = find_path(&mut world(start, goal, &wall), start, goal, PathAgent { can_climb: true, ..walker() });
+        assert!(path.complete);
+        assert!(actions(&path).contains(&PathAction::Climb));
+
+        let path = find_path(&mut world(start, goal, &wall), start, goal, walker());
+        assert!(!path.complete);
+    }
+
+    #[test]
+    fn swims_only_if_it_can() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(5, 1, 0));
+        let pool: Vec<(i32, i32, u32)> = (1..=3).map(|x| (x, 1, WATER)).collect();
+
+        let path = find_path(&mut world(start, goal, &pool), start, goal, PathAgent { can_swim: true, ..walker() });
+        assert!(path.complete);
+        assert!(actions(&path).contains(&PathAction::Swim));
+
+        let path = find_path(&mut world(start, goal, &pool), start, goal, walker());
+        assert!(!path.complete);
+    }
+
+    #[test]
+    fn goes_through_a_doorway_and_around_a_door() {
+        let (start, goal) = (IVec3::new(0, 1, 0), IVec3::new(6, 1, 0));
+        let path = find_path(&mut walled(start, goal), start, goal, walker());
+        assert!(path.complete);
+        let crossing: Vec<IVec3> = path.steps.iter().map(|step| step.spot).filter(|spot| spot.x == 3).collect();
+        assert_eq!(crossing, vec![IVec3::new(3, 1, 6)]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fs, io, path::PathBuf};
+
+use bevy::prelude::*;
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+use uuid::Uuid;
+
+use crate::{
+    blockedit::is_solid,
+    blockinfo::Blocks,
+    jserver::{ConnectedPlayer, JServerClients, PlayerJoined, PlayerLeft},
+    protocol::{send_to_client, ClientMessage, FromClient, ServerMessage},
+    serverworld::WorldPath,
+    transport::ServerNet,
+};
+
+pub static MAX_HEALTH: f32 = 20.0;
+
+/// Server side: remembers each player between visits, keyed on their identity.
+pub struct PlayerDatabasePlugin;
+
+impl Plugin for PlayerDatabasePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (send_player_data, track_held_blocks, save_leaving_players))
+        ;
+    }
+}
+
+/// What's kept of a player while they're offline.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    /// The token their client sent us the first time they joined.
+    pub token: [u8; 32],
+    pub name: String,
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub held_block: u32,
+    /// Blocks and how many of each.
+    pub inventory: Vec<(u32, u32)>,
+    pub health: f32,
+}
+
+impl PlayerRecord {
+    pub fn from_player(token: [u8; 32], player: &ConnectedPlayer) -> Self {
+        Self {
+            token,
+            name: player.name.clone(),
+            position: player.position.to_array(),
+            yaw: player.yaw,
+            pitch: player.pitch,
+            held_block: player.held_block,
+            inventory: player.inventory.clone(),
+            health: player.health,
+        }
+    }
+
+    pub fn restore(&self, player: &mut ConnectedPlayer) {
+        player.position = Vec3::from_array(self.position);
+        player.yaw = self.yaw;
+        player.pitch = self.pitch;
+        player.held_block = self.held_block;
+        player.inventory = self.inventory.clone();
+        player.health = self.health;
+    }
+
+    pub fn dir(world: &WorldPath) -> PathBuf {
+        world.0.join("players")
+    }
+
+    pub fn file(world: &WorldPath, uuid: Uuid) -> PathBuf {
+        Self::dir(world).join(format!("{}.jplayer", uuid))
+    }
+
+    pub fn save(&self, world: &WorldPath, uuid: Uuid) -> io::Result<()> {
+        fs::create_dir_all(Self::dir(world))?;
+        let tmp = Self::dir(world).join(format!("{}.jplayer.tmp", uuid));
+        fs::write(&tmp, borsh::to_vec(self)?)?;
+        fs::rename(tmp, Self::file(world, uuid))
+    }
+
+    /// None for someone who's never joined.
+    pub fn load(world: &WorldPath, uuid: Uuid) -> io::Result<Option<Self>> {
+        match fs::read(Self::file(world, uuid)) {
+            Ok(bytes) => Ok(Some(borsh::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Checks a joining player's token against their record, or registers them if they're new.
+/// On success the player is restored from the record.
+pub fn authenticate(world: &WorldPath, token: [u8; 32], player: &mut ConnectedPlayer) -> Result<(), String> {
+    match PlayerRecord::load(world, player.uuid) {
+        Ok(Some(record)) => {
+            if record.token != token {
+                return Err(String::from("Your identity doesn't match the one this server knows. Was identity.txt replaced?"));
+            }
+            record.restore(player);
+            Ok(())
+        }
+        Ok(None) => {
+            info!("New player {} ({})", player.name, player.uuid);
+            PlayerRecord::from_player(token, player).save(world, player.uuid).map_err(|e| {
+                warn!("Couldn't register {}: {}", player.uuid, e);
+                String::from("The server couldn't save your player data")
+            })
+        }
+        Err(e) => {
+            warn!("Couldn't read player {}: {}", player.uuid, e);
+            Err(String::from("The server couldn't read your player data"))
+        }
+    }
+}
+
+pub fn save_player(world: &WorldPath, player: &ConnectedPlayer) -> io::Result<()> {
+    PlayerRecord::from_player(player.token, player).save(world, player.uuid)
+}
+
+/// Saves everyone online, for `/save`.
+pub fn save_online_players(world: &WorldPath, clients: &JServerClients) -> io::Result<()> {
+    for player in clients.players.values() {
+        save_player(world, player)?;
+    }
+    Ok(())
+}
+
+pub fn send_player_data(mut net: ServerNet, clients: Res<JServerClients>, mut joined: EventReader<PlayerJoined>) {
+    for event in joined.read() {
+        let Some(player) = clients.players.get(&event.client_id) else {
+            continue;
+        };
+        send_to_client(&mut net, event.client_id, &ServerMessage::PlayerData {
+            position: player.position.to_array(),
+            yaw: player.yaw,
+            pitch: player.pitch,
+            held_block: player.held_block,
+            inventory: player.inventory.clone(),
+            health: player.health,
+        });
+    }
+}
+
+pub fn track_held_blocks(mut clients: ResMut<JServerClients>, mut messages: EventReader<FromClient>) {
+    for FromClient { client_id, message } in messages.read() {
+        if let ClientMessage::HoldBlock { block } = message {
+            let valid = (*block as usize) < Blocks::get_texs_length() && is_solid(*block) && !Blocks::is_non_placeable(*block);
+            if let (true, Some(player)) = (valid, clients.players.get_mut(client_id)) {
+                player.held_block = *block;
+            }
+        }
+    }
+}
+
+pub fn save_leaving_players(path: Res<WorldPath>, mut left: EventReader<PlayerLeft>) {
+    for event in left.read() {
+        if let Err(e) = save_player(&path, &event.player) {
+            warn!("Couldn't save player {}: {}", event.player.name, e);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    camera::JCamera,
+    jclient::{JConnection, JConnectionState},
+    movement::{simulate_input, MovementInput, MovementState, MOVEMENT_TICK_RATE},
+    protocol::{send_to_server, ClientMessage, FromServer, ServerMessage},
+    transport::ClientNet,
+    JControls,
+};
+
+/// Each input packet repeats this many of the most recent inputs.
+pub static INPUT_REDUNDANCY: usize = 3;
+/// Stop remembering inputs the server never acknowledged after this many ticks.
+pub static MAX_PENDING_INPUTS: usize = 120;
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Prediction>()
+        .insert_resource(Time::<Fixed>::from_hz(MOVEMENT_TICK_RATE))
+        .add_systems(Update, (toggle_prediction, receive_teleports, reconcile_movement).chain())
+        .add_systems(FixedUpdate, predict_movement.run_if(prediction_enabled))
+        ;
+    }
+}
+
+/// Inputs we've simulated locally but the server hasn't confirmed yet.
+#[derive(Resource, Default)]
+pub struct Prediction {
+    pub enabled: bool,
+    pub next_seq: u32,
+    pub last_ack: Option<u32>,
+    pub pending: VecDeque<MovementInput>,
+    pub state: MovementState,
+    /// Acks up to and including this input are from before a teleport.
+    pub teleported_after: Option<u32>,
+}
+
+pub fn prediction_enabled(prediction: Res<Prediction>) -> bool {
+    prediction.enabled
+}
+
+/// For `player_movement`: true unless the server is driving our movement.
+pub fn moving_locally(prediction: Option<Res<Prediction>>) -> bool {
+    prediction.map(|p| !p.enabled).unwrap_or(true)
+}
+
+/// `b` is `a` or comes after it, allowing for wraparound.
+fn seq_at_or_after(b: u32, a: u32) -> bool {
+    b.wrapping_sub(a) < u32::MAX / 2
+}
+
+/// Goes wherever the server put us, predicting or not.
+pub fn receive_teleports(
+    mut prediction: ResMut<Prediction>,
+    mut messages: EventReader<FromServer>,
+    mut player: Query<&mut Transform, With<KinematicCharacterController>>,
+) {
+    for FromServer(message) in messages.read() {
+        let ServerMessage::Teleport { position, after_seq } = message else {
+            continue;
+        };
+        if let Ok(mut transform) = player.get_single_mut() {
+            transform.translation = Vec3::from_array(*position);
+        }
+        // What we predicted since was from the old position.
+        prediction.pending.clear();
+        prediction.state = MovementState::default();
+        if let Some(after_seq) = after_seq {
+            prediction.last_ack = Some(*after_seq);
+            prediction.teleported_after = Some(*after_seq);
+        }
+    }
+}
+
+pub fn toggle_prediction(connection: Res<JConnection>, mut prediction: ResMut<Prediction>) {
+    let enabled = connection.state == JConnectionState::Joined && connection.authoritative_movement;
+    if enabled != prediction.enabled {
+        *prediction = Prediction {
+            enabled,
+            ..default()
+        };
+    }
+}
+
+pub fn predict_movement(
+    time: Res<Time>,
+    mut context: ResMut<RapierContext>,
+    controls: Res<JControls>,
+    mut camera: ResMut<JCamera>,
+    mut prediction: ResMut<Prediction>,
+    mut net: ClientNet,
+    mut player: Query<(Entity, &mut Transform, &Collider), With<KinematicCharacterController>>,
+) {
+    let Ok((entity, mut transform, collider)) = player.get_single_mut() else {
+        return;
+    };
+
+    // move_from_controls leaves a jump in the camera velocity; player_movement isn't running to clear it.
+    let input = MovementInput {
+        seq: prediction.next_seq,
+        forward: controls.f,
+        back: controls.b,
+        left: controls.l,
+        right: controls.r,
+        jump: controls.jump || camera.velocity.y > 0.0,
+        sprinting: controls.sprinting,
+        yaw: camera.yaw,
+    };
+    camera.velocity = Vec3::ZERO;
+    prediction.next_seq = prediction.next_seq.wrapping_add(1);
+
+    let mut state = prediction.state;
+    transform.translation = simulate_input(&mut context, collider, entity, transform.translation, &mut state, &input, time.delta_seconds());
+    prediction.state = state;
+
+    prediction.pending.push_back(input);
+    while prediction.pending.len() > MAX_PENDING_INPUTS {
+        prediction.pending.pop_front();
+    }
+
+    let skip = prediction.pending.len().saturating_sub(INPUT_REDUNDANCY);
+    send_to_server(&mut net, &ClientMessage::PlayerInput {
+        inputs: prediction.pending.iter().skip(skip).copied().collect(),
+        pitch: camera.pitch,
+    });
+}
+
+/// Snap to the server's answer for the newest acknowledged input, then replay everything after it.
+pub fn reconcile_movement(
+    mut context: ResMut<RapierContext>,
+    mut prediction: ResMut<Prediction>,
+    mut messages: EventReader<FromServer>,
+    mut player: Query<(Entity, &mut Transform, &Collider), With<KinematicCharacterController>>,
+) {
+    let mut latest = None;
+    for FromServer(message) in messages.read() {
+        if let ServerMessage::PlayerState { seq, position, state } = message {
+            // Unreliable channel: acks can arrive out of order.
+            if latest.map(|(s, _, _)| seq_at_or_after(*seq, s)).unwrap_or(true) {
+                latest = Some((*seq, Vec3::from_array(*position), *state));
+            }
+        }
+    }
+
+    let Some((seq, position, state)) = latest else {
+        return;
+    };
+    if !prediction.enabled || prediction.last_ack.map(|last| !seq_at_or_after(seq, last)).unwrap_or(false) {
+        return;
+    }
+    if prediction.teleported_after.map(|after| seq == after).unwrap_or(false) {
+        return;
+    }
+    let Ok((entity, mut transform, collider)) = player.get_single_mut() else {
+        return;
+    };
+    prediction.last_ack = Some(seq);
+
+    while prediction.pending.front().map(|input| seq_at_or_after(seq, input.seq)).unwrap_or(false) {
+        prediction.pending.pop_front();
+    }
+
+    let dt = (1.0 / MOVEMENT_TICK_RATE) as f32;
+    let mut position = position;
+    let mut state = state;
+    for input in prediction.pending.iter() {
+        position = simulate_input(&mut context, collider, entity, position, &mut state, input, dt);
+    }
+
+    if position.distance(transform.translation) > 0.01 {
+        debug!("Reconciled {} to the server", transform.translation.distance(position));
+    }
+    transform.translation = position;
+    prediction.state = state;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    fn input(seq: u32) -> MovementInput {
+        MovementInput { seq, forward: true, yaw: 0.0, ..default() }
+    }
+
+    /// A predicting client with inputs `first..first + count` pending, standing in empty space.
+    fn predicting(first: u32, count: u32) -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(RapierContext::default());
+        world.init_resource::<Events<FromServer>>();
+        world.insert_resource(Prediction {
+            enabled: true,
+            pending: (0..count).map(|i| input(first.wrapping_add(i))).collect(),
+            ..default()
+        });
+        let player = world.spawn((Transform::default(), Collider::cuboid(0.4, 0.9, 0.4), KinematicCharacterController::default())).id();
+        (world, player)
+    }
+
+    fn ack(world: &mut World, seq: u32, position: Vec3) {
+        world.send_event(FromServer(ServerMessage::PlayerState { seq, position: position.to_array(), state: MovementState::default() }));
+    }
+
+    fn pending_seqs(world: &World) -> Vec<u32> {
+        world.resource::<Prediction>().pending.iter().map(|input| input.seq).collect()
+    }
+
+    #[test]
+    fn replays_unacknowledged_inputs_from_the_server_position() {
+        let (mut world, player) = predicting(u32::MAX - 1, 5);
+        let acked = Vec3::new(10.0, 0.0, 0.0);
+        ack(&mut world, u32::MAX, acked);
+        world.run_system_once(reconcile_movement);
+
+        // Acks wrap too: everything up to u32::MAX is done, 0 to 2 are replayed.
+        assert_eq!(pending_seqs(&world), vec![0, 1, 2]);
+        assert_eq!(world.resource::<Prediction>().last_ack, Some(u32::MAX));
+
+        let collider = Collider::cuboid(0.4, 0.9, 0.4);
+        let mut context = RapierContext::default();
+        let mut state = MovementState::default();
+        let dt = (1.0 / MOVEMENT_TICK_RATE) as f32;
+        let mut expected = acked;
+        for seq in [0, 1, 2] {
+            expected = simulate_input(&mut context, &collider, player, expected, &mut state, &input(seq), dt);
+        }
+        let position = world.get::<Transform>(player).unwrap().translation;
+        assert!(position.distance(expected) < 1e-4, "{} vs {}", position, expected);
+        assert!(position.z > acked.z);
+    }
+
+    #[test]
+    fn ignores_acks_older_than_the_last() {
+        let (mut world, player) = predicting(5, 5);
+        ack(&mut world, 7, Vec3::ZERO);
+        world.run_system_once(reconcile_movement);
+        let after_ack = world.get::<Transform>(player).unwrap().translation;
+
+        // Unreliable: an older ack turns up late, in the same frame as or after a newer one.
+        ack(&mut world, 6, Vec3::new(50.0, 0.0, 0.0));
+        world.run_system_once(reconcile_movement);
+        assert_eq!(world.get::<Transform>(player).unwrap().translation, after_ack);
+        assert_eq!(pending_seqs(&world), vec![8, 9]);
+    }
+
+    #[test]
+    fn teleports_drop_predictions_and_older_acks() {
+        let (mut world, player) = predicting(5, 5);
+        world.send_event(FromServer(ServerMessage::Teleport { position: [0.0, 40.0, 0.0], after_seq: Some(7) }));
+        world.run_system_once(receive_teleports);
+        assert_eq!(world.get::<Transform>(player).unwrap().translation, Vec3::new(0.0, 40.0, 0.0));
+        assert!(pending_seqs(&world).is_empty());
+
+        // The ack for 7 was sent before the move. Each run reads every buffered event, so start afresh.
+        world.resource_mut::<Events<FromServer>>().clear();
+        ack(&mut world, 7, Vec3::ZERO);
+        world.run_system_once(reconcile_movement);
+        assert_eq!(world.get::<Transform>(player).unwrap().translation, Vec3::new(0.0, 40.0, 0.0));
+
+        ack(&mut world, 8, Vec3::new(0.0, 39.0, 0.0));
+        world.run_system_once(reconcile_movement);
+        assert_eq!(world.get::<Transform>(player).unwrap().translation, Vec3::new(0.0, 39.0, 0.0));
+    }
+}
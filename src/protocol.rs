@@ -0,0 +1,303 @@
+use bevy::prelude::*;
+use bevy_quinnet::shared::{
+    channels::{ChannelId, ChannelType, ChannelsConfiguration},
+    ClientId,
+};
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+
+use crate::{mobs::MobKind, movement::{MovementInput, MovementState}, transport::{ClientNet, ServerNet}};
+
+/// Bump whenever a message is added, removed or changes shape.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+pub const PLAYER_CHANNEL: ChannelId = 0;
+pub const MOB_CHANNEL: ChannelId = 1;
+pub const INVENTORY_CHANNEL: ChannelId = 2;
+/// Handshake, world and everything else that must arrive, in order.
+pub const WORLD_CHANNEL: ChannelId = 3;
+
+/// Both ends must declare the same channels in the same order.
+pub fn channels_configuration() -> ChannelsConfiguration {
+    ChannelsConfiguration::from_types(vec![
+        ChannelType::Unreliable, //Player updates
+        ChannelType::Unreliable, //Mob updates
+        ChannelType::OrderedReliable,  //Inventory updates
+        ChannelType::OrderedReliable,  //World
+    ])
+    .unwrap()
+}
+
+// Hello and Welcome stay first and start with the protocol, so `peek_protocol` reads it from any
+// version, and Rejected stays second and never changes, so any version can read why it was refused.
+// The tests below pin those bytes.
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Hello {
+        protocol: u32,
+        uuid: [u8; 16],
+        name: String,
+        /// Derived from the secret in our identity file for this server alone, see `server_token`.
+        /// The server ties it to `uuid` on first join.
+        token: [u8; 32],
+    },
+    PlayerUpdate {
+        position: [f32; 3],
+        yaw: f32,
+        pitch: f32,
+        moving: bool,
+    },
+    /// The last few ticks of input, so one lost packet doesn't lose an input.
+    PlayerInput {
+        inputs: Vec<MovementInput>,
+        pitch: f32,
+    },
+    /// Started holding break on this block; the server times the break from here.
+    StartBreaking {
+        spot: [i32; 3],
+    },
+    BreakBlock {
+        spot: [i32; 3],
+    },
+    PlaceBlock {
+        spot: [i32; 3],
+        block: u32,
+    },
+    ChatMessage {
+        text: String,
+    },
+    HoldBlock {
+        block: u32,
+    },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    Welcome {
+        protocol: u32,
+        client_id: u64,
+        /// When set, send `PlayerInput` instead of `PlayerUpdate` and predict locally.
+        authoritative_movement: bool,
+    },
+    Rejected {
+        reason: String,
+    },
+    PlayerJoined {
+        uuid: [u8; 16],
+        name: String,
+    },
+    PlayerLeft {
+        uuid: [u8; 16],
+    },
+    PlayerUpdate {
+        uuid: [u8; 16],
+        /// Seconds since the server started, for snapshot interpolation.
+        server_time: f64,
+        position: [f32; 3],
+        yaw: f32,
+        pitch: f32,
+        moving: bool,
+    },
+    /// Everything needed to generate the same base terrain as the server.
+    WorldInfo {
+        seed: u32,
+    },
+    /// Every player edit in a chunk, replacing whatever the client had there.
+    /// Each edit is a `chunk_block_index` and the block; the rest comes from the seed.
+    ChunkEdits {
+        cpos: [i32; 2],
+        edits: Vec<(u16, u32)>,
+    },
+    /// A single accepted edit, sent to everyone who has the chunk.
+    BlockSet {
+        spot: [i32; 3],
+        block: u32,
+    },
+    /// Our edit was refused; `block` is what's really there, to roll back to.
+    BlockEditRejected {
+        spot: [i32; 3],
+        block: u32,
+        reason: String,
+    },
+    /// `sender` is None for messages from the server itself.
+    ChatMessage {
+        sender: Option<String>,
+        text: String,
+    },
+    /// Seconds into the day, see `DAY_LENGTH`.
+    TimeOfDay {
+        time: f64,
+    },
+    /// Sent just before the server disconnects us; don't reconnect.
+    Kicked {
+        reason: String,
+    },
+    GiveBlock {
+        block: u32,
+        count: u32,
+    },
+    /// Where the server put us after simulating every input up to and including `seq`.
+    PlayerState {
+        seq: u32,
+        position: [f32; 3],
+        state: MovementState,
+    },
+    /// Where we were and what we had when we last left, sent on join.
+    PlayerData {
+        position: [f32; 3],
+        yaw: f32,
+        pitch: f32,
+        held_block: u32,
+        inventory: Vec<(u32, u32)>,
+        health: f32,
+    },
+    /// A mob came into view.
+    MobSpawned {
+        id: u32,
+        kind: MobKind,
+        position: [f32; 3],
+        yaw: f32,
+    },
+    /// A mob died, despawned or went out of view.
+    MobDespawned {
+        id: u32,
+    },
+    /// Where the mobs we can see are, batched to fit a datagram.
+    MobUpdates {
+        server_time: f64,
+        mobs: Vec<MobUpdate>,
+    },
+    /// We were moved, by `/tp` or the like; go there whatever we were doing. `after_seq` is the
+    /// last of our inputs the server had simulated, so a `PlayerState` from before the move is ignored.
+    Teleport {
+        position: [f32; 3],
+        after_seq: Option<u32>,
+    },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MobUpdate {
+    pub id: u32,
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub moving: bool,
+}
+
+impl ClientMessage {
+    pub fn channel(&self) -> ChannelId {
+        match self {
+            ClientMessage::Hello { .. } => WORLD_CHANNEL,
+            ClientMessage::PlayerUpdate { .. } => PLAYER_CHANNEL,
+            ClientMessage::PlayerInput { .. } => PLAYER_CHANNEL,
+            ClientMessage::StartBreaking { .. } => WORLD_CHANNEL,
+            ClientMessage::BreakBlock { .. } => WORLD_CHANNEL,
+            ClientMessage::PlaceBlock { .. } => WORLD_CHANNEL,
+            ClientMessage::ChatMessage { .. } => WORLD_CHANNEL,
+            ClientMessage::HoldBlock { .. } => INVENTORY_CHANNEL,
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn channel(&self) -> ChannelId {
+        match self {
+            ServerMessage::Welcome { .. } => WORLD_CHANNEL,
+            ServerMessage::Rejected { .. } => WORLD_CHANNEL,
+            ServerMessage::PlayerJoined { .. } => WORLD_CHANNEL,
+            ServerMessage::PlayerLeft { .. } => WORLD_CHANNEL,
+            ServerMessage::PlayerUpdate { .. } => PLAYER_CHANNEL,
+            ServerMessage::PlayerState { .. } => PLAYER_CHANNEL,
+            ServerMessage::WorldInfo { .. } => WORLD_CHANNEL,
+            ServerMessage::ChunkEdits { .. } => WORLD_CHANNEL,
+            ServerMessage::BlockSet { .. } => WORLD_CHANNEL,
+            ServerMessage::BlockEditRejected { .. } => WORLD_CHANNEL,
+            ServerMessage::ChatMessage { .. } => WORLD_CHANNEL,
+            ServerMessage::TimeOfDay { .. } => WORLD_CHANNEL,
+            ServerMessage::Kicked { .. } => WORLD_CHANNEL,
+            ServerMessage::GiveBlock { .. } => INVENTORY_CHANNEL,
+            ServerMessage::PlayerData { .. } => WORLD_CHANNEL,
+            ServerMessage::MobSpawned { .. } => WORLD_CHANNEL,
+            ServerMessage::MobDespawned { .. } => WORLD_CHANNEL,
+            ServerMessage::MobUpdates { .. } => MOB_CHANNEL,
+            ServerMessage::Teleport { .. } => WORLD_CHANNEL,
+        }
+    }
+}
+
+/// A decoded message from the server, for client systems to read.
+#[derive(Event, Debug, Clone)]
+pub struct FromServer(pub ServerMessage);
+
+/// A decoded message from a client that has completed the handshake.
+#[derive(Event, Debug, Clone)]
+pub struct FromClient {
+    pub client_id: ClientId,
+    pub message: ClientMessage,
+}
+
+pub fn encode<T: borsh::BorshSerialize>(message: &T) -> Vec<u8> {
+    borsh::to_vec(message).unwrap()
+}
+
+pub fn decode<T: borsh::BorshDeserialize>(payload: &[u8]) -> Option<T> {
+    borsh::from_slice(payload).ok()
+}
+
+/// The protocol of a `Hello` or `Welcome` from any version, for when the rest won't decode.
+pub fn peek_protocol(payload: &[u8]) -> Option<u32> {
+    match payload {
+        [0, a, b, c, d, ..] => Some(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+pub fn send_to_server(net: &mut ClientNet, message: &ClientMessage) {
+    if let Err(e) = net.send(message.channel(), encode(message)) {
+        warn!("Couldn't send {:?}: {}", message, e);
+    }
+}
+
+pub fn send_to_client(net: &mut ServerNet, client_id: ClientId, message: &ServerMessage) {
+    if let Err(e) = net.send(client_id, message.channel(), encode(message)) {
+        warn!("Couldn't send to client {}: {}", client_id, e);
+    }
+}
+
+pub fn send_to_clients<'a>(net: &mut ServerNet, client_ids: impl Iterator<Item = &'a ClientId>, message: &ServerMessage) {
+    let payload = encode(message);
+    for client_id in client_ids {
+        if let Err(e) = net.send(*client_id, message.channel(), payload.clone()) {
+            warn!("Couldn't send to client {}: {}", client_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_bytes_are_pinned() {
+        let hello = encode(&ClientMessage::Hello { protocol: 7, uuid: [1; 16], name: String::from("a"), token: [2; 32] });
+        assert_eq!(&hello[..5], &[0, 7, 0, 0, 0]);
+
+        let welcome = encode(&ServerMessage::Welcome { protocol: 7, client_id: 3, authoritative_movement: true });
+        assert_eq!(&welcome[..5], &[0, 7, 0, 0, 0]);
+
+        let rejected = encode(&ServerMessage::Rejected { reason: String::from("no") });
+        assert_eq!(rejected, vec![1, 2, 0, 0, 0, b'n', b'o']);
+    }
+
+    #[test]
+    fn peek_protocol_reads_any_hello() {
+        // A Hello from some other version, with fields we don't know after the protocol.
+        let foreign = [0, 9, 0, 0, 0, 0xff, 0xff];
+        assert!(decode::<ClientMessage>(&foreign).is_none());
+        assert_eq!(peek_protocol(&foreign), Some(9));
+
+        let hello = encode(&ClientMessage::Hello { protocol: PROTOCOL_VERSION, uuid: [0; 16], name: String::new(), token: [0; 32] });
+        assert_eq!(peek_protocol(&hello), Some(PROTOCOL_VERSION));
+        assert_eq!(peek_protocol(&encode(&ClientMessage::HoldBlock { block: 1 })), None);
+        assert_eq!(peek_protocol(&[0, 1]), None);
+    }
+}
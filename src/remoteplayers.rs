@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_rapier3d::prelude::KinematicCharacterController;
+use uuid::Uuid;
+
+use crate::{
+    camera::JCamera,
+    jclient::{JClientEvent, JConnection, JConnectionState},
+    movement::yaw_rotation,
+    protocol::{send_to_server, ClientMessage, FromServer, ServerMessage},
+    snapshot::{ServerClock, Snapshot, SnapshotBuffer},
+    transport::ClientNet,
+    ChildJId, JControls, JId, JMoveState,
+};
+
+/// How often we tell the server where we are, when it trusts us to say.
+pub static PLAYER_SEND_RATE: f32 = 20.0;
+
+pub struct RemotePlayersPlugin;
+
+impl Plugin for RemotePlayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RemotePlayers>()
+        .init_resource::<ServerClock>()
+        .insert_resource(PlayerSendTimer(Timer::new(Duration::from_secs_f32(1.0 / PLAYER_SEND_RATE), TimerMode::Repeating)))
+        .add_systems(Update, (send_my_player, spawn_remote_players, update_remote_players, interpolate_remote_players, clear_remote_players).chain())
+        ;
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct RemotePlayers {
+    pub entities: HashMap<Uuid, Entity>,
+    pub names: HashMap<Uuid, String>,
+}
+
+#[derive(Resource)]
+pub struct PlayerSendTimer(Timer);
+
+pub fn send_my_player(
+    time: Res<Time>,
+    mut timer: ResMut<PlayerSendTimer>,
+    mut net: ClientNet,
+    connection: Res<JConnection>,
+    camera: Res<JCamera>,
+    controls: Res<JControls>,
+    me: Query<&Transform, With<KinematicCharacterController>>,
+) {
+    // An authoritative server moves us from our inputs and ignores where we say we are.
+    if !timer.0.tick(time.delta()).just_finished() || connection.state != JConnectionState::Joined || connection.authoritative_movement {
+        return;
+    }
+    let Ok(transform) = me.get_single() else {
+        return;
+    };
+
+    send_to_server(&mut net, &ClientMessage::PlayerUpdate {
+        position: transform.translation.to_array(),
+        yaw: camera.yaw,
+        pitch: camera.pitch,
+        moving: controls.moving,
+    });
+}
+
+pub fn spawn_remote_players(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut remotes: ResMut<RemotePlayers>,
+    mut messages: EventReader<FromServer>,
+) {
+    for FromServer(message) in messages.read() {
+        match message {
+            ServerMessage::PlayerJoined { uuid, name } => {
+                let uuid = Uuid::from_bytes(*uuid);
+                if remotes.entities.contains_key(&uuid) {
+                    continue;
+                }
+                info!("{} is here", name);
+
+                let entity = commands
+                    .spawn((SpatialBundle::default(), JId { uuid }, SnapshotBuffer::default()))
+                    .with_children(|b| {
+                        b.spawn(SceneBundle {
+                            scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset("models/player.glb")),
+                            transform: Transform::from_scale(Vec3::ONE * 0.3),
+                            ..default()
+                        });
+                    })
+                    .id();
+
+                remotes.entities.insert(uuid, entity);
+                remotes.names.insert(uuid, name.clone());
+            }
+            ServerMessage::PlayerLeft { uuid } => {
+                let uuid = Uuid::from_bytes(*uuid);
+                if let Some(entity) = remotes.entities.remove(&uuid) {
+                    commands.entity(entity).despawn_recursive();
+                }
+                if let Some(name) = remotes.names.remove(&uuid) {
+                    info!("{} left", name);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn update_remote_players(
+    time: Res<Time>,
+    remotes: Res<RemotePlayers>,
+    mut clock: ResMut<ServerClock>,
+    mut messages: EventReader<FromServer>,
+    mut buffers: Query<&mut SnapshotBuffer, With<JId>>,
+    mut movestates: Query<(&mut JMoveState, &ChildJId)>,
+) {
+    for FromServer(message) in messages.read() {
+        let ServerMessage::PlayerUpdate { uuid, server_time, position, yaw, pitch: _, moving } = message else {
+            continue;
+        };
+        let uuid = Uuid::from_bytes(*uuid);
+
+        clock.observe(*server_time, time.elapsed_seconds_f64());
+
+        if let Some(entity) = remotes.entities.get(&uuid) {
+            if let Ok(mut buffer) = buffers.get_mut(*entity) {
+                buffer.push(Snapshot {
+                    time: *server_time,
+                    position: Vec3::from_array(*position),
+                    yaw: *yaw,
+                });
+            }
+        }
+
+        // The walk animation is driven from the AnimationPlayer entity deep in the glTF scene.
+        for (mut movestate, childjid) in movestates.iter_mut() {
+            if childjid.uuid == uuid {
+                movestate.moving = *moving;
+            }
+        }
+    }
+}
+
+pub fn interpolate_remote_players(
+    time: Res<Time>,
+    clock: Res<ServerClock>,
+    mut players: Query<(&mut Transform, &mut SnapshotBuffer), With<JId>>,
+) {
+    let Some(render_time) = clock.render_time(time.elapsed_seconds_f64()) else {
+        return;
+    };
+
+    for (mut transform, mut buffer) in players.iter_mut() {
+        if let Some(snapshot) = buffer.sample(render_time) {
+            transform.translation = snapshot.position;
+            transform.rotation = yaw_rotation(snapshot.yaw);
+        }
+    }
+}
+
+pub fn clear_remote_players(
+    mut commands: Commands,
+    mut remotes: ResMut<RemotePlayers>,
+    mut clock: ResMut<ServerClock>,
+    mut events: EventReader<JClientEvent>,
+) {
+    for event in events.read() {
+        if let JClientEvent::Disconnected = event {
+            for (_, entity) in remotes.entities.drain() {
+                commands.entity(entity).despawn_recursive();
+            }
+            remotes.names.clear();
+            // The next server's clock has nothing to do with this one's.
+            *clock = ServerClock::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::snapshot::INTERPOLATION_DELAY;
+
+    #[test]
+    fn disconnecting_forgets_the_server_clock() {
+        let mut world = World::new();
+        world.init_resource::<RemotePlayers>();
+        world.init_resource::<Events<JClientEvent>>();
+        let mut clock = ServerClock::default();
+        clock.observe(5000.0, 10.0);
+        world.insert_resource(clock);
+
+        world.send_event(JClientEvent::Disconnected);
+        world.run_system_once(clear_remote_players);
+        assert_eq!(world.resource::<ServerClock>().offset, None);
+
+        // A restarted server's clock starts again from zero and is taken as it is.
+        let mut clock = world.resource_mut::<ServerClock>();
+        clock.observe(1.0, 20.0);
+        assert_eq!(clock.offset, Some(-19.0));
+        assert!((clock.render_time(21.0).unwrap() - (2.0 - INTERPOLATION_DELAY)).abs() < 1e-9);
+    }
+}
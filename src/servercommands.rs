@@ -0,0 +1,657 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    io::{BufRead, IsTerminal},
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc::{self, Receiver}, Mutex},
+    thread,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_quinnet::shared::ClientId;
+use noise::Seedable;
+use uuid::Uuid;
+
+use crate::{
+    blockinfo::Blocks,
+    chat::{relay_chat, ChatCommand},
+    chunk::JPerlin,
+    access::{banned_message, AccessLists},
+    jserver::{kick_client, listen, save_everything, JServerClients, JServerSettings, PendingDisconnects, StopServer},
+    mobs::{mob_bundle, MobKind, Mobs},
+    movement::MovementState,
+    protocol::{send_to_client, send_to_clients, ServerMessage},
+    serverconfig::ServerConfig,
+    serverplayers::ServerPlayer,
+    serverworld::{BlockEdited, WorldPath, WorldTime, DAY_LENGTH},
+    structure::{PasteStructure, StructureTransform},
+    transport::{with_server_net, LOOPBACK_CLIENT_ID},
+    UserDataMap,
+};
+
+/// Operator input from stdin and `/` commands from chat, checked against per-player permissions.
+pub struct ServerCommandsPlugin;
+
+impl Plugin for ServerCommandsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CommandRegistry::with_builtins())
+        .init_resource::<Permissions>()
+        .init_resource::<PendingCommands>()
+        .add_systems(Startup, (start_console, load_permissions))
+        .add_systems(Update, (read_console, read_chat_commands, run_pending_commands).chain().after(relay_chat))
+        ;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum PermissionLevel {
+    #[default]
+    Player,
+    Moderator,
+    Operator,
+}
+
+impl FromStr for PermissionLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "0" | "player" => Ok(PermissionLevel::Player),
+            "1" | "moderator" => Ok(PermissionLevel::Moderator),
+            "2" | "operator" => Ok(PermissionLevel::Operator),
+            _ => Err(format!("{} isn't a permission level (player, moderator, operator)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSource {
+    Console,
+    Player(ClientId),
+}
+
+/// Runs a command with its arguments, returning the reply or an error for whoever ran it.
+pub type CommandFn = fn(&mut World, CommandSource, &[&str]) -> Result<String, String>;
+
+pub struct ServerCommand {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub permission: PermissionLevel,
+    pub run: CommandFn,
+}
+
+#[derive(Resource, Default)]
+pub struct CommandRegistry {
+    pub commands: BTreeMap<&'static str, ServerCommand>,
+}
+
+impl CommandRegistry {
+    pub fn register(&mut self, command: ServerCommand) {
+        self.commands.insert(command.name, command);
+    }
+
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry.register(ServerCommand { name: "list", usage: "/list", help: "Who's online", permission: PermissionLevel::Player, run: list_command });
+        registry.register(ServerCommand { name: "seed", usage: "/seed", help: "The world seed", permission: PermissionLevel::Player, run: seed_command });
+        registry.register(ServerCommand { name: "tp", usage: "/tp [player] <x y z | target>", help: "Teleport a player", permission: PermissionLevel::Moderator, run: tp_command });
+        registry.register(ServerCommand { name: "kick", usage: "/kick <player> [reason]", help: "Disconnect a player", permission: PermissionLevel::Moderator, run: kick_command });
+        registry.register(ServerCommand { name: "ban", usage: "/ban <player | uuid> [reason]", help: "Ban a player by identity", permission: PermissionLevel::Moderator, run: ban_command });
+        registry.register(ServerCommand { name: "pardon", usage: "/pardon <uuid>", help: "Lift a ban", permission: PermissionLevel::Operator, run: pardon_command });
+        registry.register(ServerCommand { name: "whitelist", usage: "/whitelist <on | off | list | add <player | uuid> | remove <player | uuid>>", help: "Manage the whitelist", permission: PermissionLevel::Operator, run: whitelist_command });
+        registry.register(ServerCommand { name: "time", usage: "/time [set <seconds | day | noon | night | midnight>]", help: "Show or set the time of day", permission: PermissionLevel::Moderator, run: time_command });
+        registry.register(ServerCommand { name: "give", usage: "/give <player> <block> [count]", help: "Give a player blocks", permission: PermissionLevel::Operator, run: give_command });
+        registry.register(ServerCommand { name: "setblock", usage: "/setblock <x y z> <block>", help: "Set one block", permission: PermissionLevel::Operator, run: setblock_command });
+        registry.register(ServerCommand { name: "paste", usage: "/paste <structure> [x y z] [rotation] [mirror]", help: "Paste a saved structure", permission: PermissionLevel::Operator, run: paste_command });
+        registry.register(ServerCommand { name: "summon", usage: "/summon <deer | crab | lurker> [x y z]", help: "Spawn a mob", permission: PermissionLevel::Operator, run: summon_command });
+        registry.register(ServerCommand { name: "save", usage: "/save", help: "Save the world now", permission: PermissionLevel::Operator, run: save_command });
+        registry.register(ServerCommand { name: "stop", usage: "/stop", help: "Save and stop the server", permission: PermissionLevel::Operator, run: stop_command });
+        registry.register(ServerCommand { name: "lan", usage: "/lan [port]", help: "Open a singleplayer world to the local network", permission: PermissionLevel::Operator, run: lan_command });
+        registry.register(ServerCommand { name: "op", usage: "/op <player> <player | moderator | operator>", help: "Set a player's permission level", permission: PermissionLevel::Operator, run: op_command });
+        registry
+    }
+}
+
+/// Permission levels by player identity; everyone else is a `Player`.
+#[derive(Resource, Default)]
+pub struct Permissions {
+    pub levels: HashMap<Uuid, PermissionLevel>,
+}
+
+impl Permissions {
+    pub fn file(world: &WorldPath) -> PathBuf {
+        world.0.join("permissions.txt")
+    }
+
+    /// One `<uuid> <level>` per line.
+    pub fn load(world: &WorldPath) -> io::Result<Self> {
+        let mut permissions = Self::default();
+        for line in fs::read_to_string(Self::file(world))?.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(uuid), Some(level)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            match (Uuid::parse_str(uuid), level.parse::<PermissionLevel>()) {
+                (Ok(uuid), Ok(level)) => {
+                    permissions.levels.insert(uuid, level);
+                }
+                _ => warn!("Ignoring bad permissions line: {}", line),
+            }
+        }
+        Ok(permissions)
+    }
+
+    pub fn save(&self, world: &WorldPath) -> io::Result<()> {
+        fs::create_dir_all(&world.0)?;
+        let lines: Vec<String> = self.levels.iter()
+            .map(|(uuid, level)| format!("{} {:?}", uuid, level).to_lowercase())
+            .collect();
+        fs::write(Self::file(world), lines.join("\n") + "\n")
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct PendingCommands(pub Vec<(CommandSource, String)>);
+
+#[derive(Resource)]
+pub struct ConsoleInput(Mutex<Receiver<String>>);
+
+/// Ctrl-D at the console stops the server like `stop`. Stdin that isn't a terminal just stops
+/// being read when it runs out, so a server started without one keeps running.
+pub fn start_console(mut commands: Commands) {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+        if io::stdin().is_terminal() {
+            let _ = sender.send(String::from("stop"));
+        }
+    });
+    commands.insert_resource(ConsoleInput(Mutex::new(receiver)));
+}
+
+pub fn load_permissions(path: Res<WorldPath>, mut permissions: ResMut<Permissions>) {
+    match Permissions::load(&path) {
+        Ok(loaded) => *permissions = loaded,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Couldn't read {}: {}", Permissions::file(&path).display(), e),
+    }
+}
+
+pub fn read_console(console: Option<Res<ConsoleInput>>, mut pending: ResMut<PendingCommands>) {
+    let Some(console) = console else {
+        return;
+    };
+    let receiver = console.0.lock().unwrap();
+    while let Ok(line) = receiver.try_recv() {
+        let line = line.trim();
+        if !line.is_empty() {
+            pending.0.push((CommandSource::Console, line.to_string()));
+        }
+    }
+}
+
+pub fn read_chat_commands(mut commands: EventReader<ChatCommand>, mut pending: ResMut<PendingCommands>) {
+    for ChatCommand { client_id, text } in commands.read() {
+        pending.0.push((CommandSource::Player(*client_id), text.clone()));
+    }
+}
+
+pub fn permission_of(world: &World, source: CommandSource) -> PermissionLevel {
+    match source {
+        CommandSource::Console => PermissionLevel::Operator,
+        // Singleplayer: it's their world.
+        CommandSource::Player(LOOPBACK_CLIENT_ID) => PermissionLevel::Operator,
+        CommandSource::Player(client_id) => world
+            .resource::<JServerClients>()
+            .players
+            .get(&client_id)
+            .and_then(|player| world.resource::<Permissions>().levels.get(&player.uuid).copied())
+            .unwrap_or_default(),
+    }
+}
+
+fn execute(world: &mut World, registry: &CommandRegistry, source: CommandSource, line: &str) -> Result<String, String> {
+    let mut words = line.trim_start_matches('/').split_whitespace();
+    let Some(name) = words.next() else {
+        return Err(String::from("Type /help for a list of commands"));
+    };
+    let args: Vec<&str> = words.collect();
+    let level = permission_of(world, source);
+
+    if name == "help" {
+        return Ok(registry.commands.values()
+            .filter(|command| command.permission <= level)
+            .map(|command| format!("{} - {}", command.usage, command.help))
+            .collect::<Vec<_>>()
+            .join("\n"));
+    }
+
+    let Some(command) = registry.commands.get(name) else {
+        return Err(format!("Unknown command /{}. Type /help for a list", name));
+    };
+    if command.permission > level {
+        return Err(format!("You need to be a {:?} to use /{}", command.permission, name).to_lowercase());
+    }
+
+    (command.run)(world, source, &args).map_err(|e| format!("{}\nUsage: {}", e, command.usage))
+}
+
+fn reply(world: &mut World, source: CommandSource, text: String) {
+    match source {
+        CommandSource::Console => println!("{}", text),
+        CommandSource::Player(client_id) => {
+            with_server_net(world, |net| send_to_client(net, client_id, &ServerMessage::ChatMessage {
+                sender: None,
+                text,
+            }));
+        }
+    }
+}
+
+pub fn run_pending_commands(world: &mut World) {
+    let pending = std::mem::take(&mut world.resource_mut::<PendingCommands>().0);
+
+    for (source, line) in pending {
+        info!("{:?} ran {}", source, line);
+        let result = world.resource_scope(|world, registry: Mut<CommandRegistry>| execute(world, &registry, source, &line));
+        let text = result.unwrap_or_else(|e| e);
+        if !text.is_empty() {
+            reply(world, source, text);
+        }
+    }
+}
+
+fn parse<T: FromStr>(arg: Option<&&str>, what: &str) -> Result<T, String> {
+    let arg = arg.ok_or_else(|| format!("Missing {}", what))?;
+    arg.parse::<T>().map_err(|_| format!("{} isn't a valid {}", arg, what))
+}
+
+/// A coordinate, or `~` / `~offset` relative to `base`.
+fn parse_coord(arg: Option<&&str>, base: Option<f32>, what: &str) -> Result<f32, String> {
+    let arg = arg.ok_or_else(|| format!("Missing {}", what))?;
+    match arg.strip_prefix('~') {
+        Some(offset) => {
+            let base = base.ok_or_else(|| String::from("~ only works for players"))?;
+            let offset = if offset.is_empty() { 0.0 } else { offset.parse::<f32>().map_err(|_| format!("{} isn't a valid {}", arg, what))? };
+            Ok(base + offset)
+        }
+        None => arg.parse::<f32>().map_err(|_| format!("{} isn't a valid {}", arg, what)),
+    }
+}
+
+fn parse_position(args: &[&str], base: Option<Vec3>) -> Result<Vec3, String> {
+    Ok(Vec3::new(
+        parse_coord(args.first(), base.map(|b| b.x), "x")?,
+        parse_coord(args.get(1), base.map(|b| b.y), "y")?,
+        parse_coord(args.get(2), base.map(|b| b.z), "z")?,
+    ))
+}
+
+/// A block by id or by name.
+fn parse_block(arg: Option<&&str>) -> Result<u32, String> {
+    let arg = arg.ok_or_else(|| String::from("Missing block"))?;
+    let count = Blocks::get_texs_length() as u32;
+    if let Ok(id) = arg.parse::<u32>() {
+        if id < count {
+            return Ok(id);
+        }
+    }
+    (0..count)
+        .find(|id| Blocks::get_name(*id).eq_ignore_ascii_case(&arg.replace('_', " ")))
+        .ok_or_else(|| format!("No block called {}", arg))
+}
+
+fn find_player(world: &World, name: &str) -> Result<ClientId, String> {
+    world.resource::<JServerClients>().players.iter()
+        .find(|(_, player)| player.name.eq_ignore_ascii_case(name))
+        .map(|(id, _)| *id)
+        .ok_or_else(|| format!("No player called {} is online", name))
+}
+
+fn source_position(world: &World, source: CommandSource) -> Option<Vec3> {
+    match source {
+        CommandSource::Console => None,
+        CommandSource::Player(client_id) => world.resource::<JServerClients>().players.get(&client_id).map(|p| p.position),
+    }
+}
+
+fn player_name(world: &World, client_id: ClientId) -> String {
+    world.resource::<JServerClients>().players.get(&client_id).map(|p| p.name.clone()).unwrap_or_default()
+}
+
+/// Moves a player on the server and tells their client, which goes there however it moves.
+pub fn teleport(world: &mut World, client_id: ClientId, position: Vec3) {
+    let Some(player) = world.resource_mut::<JServerClients>().players.get_mut(&client_id).map(|player| {
+        player.position = position;
+        player.entity
+    }) else {
+        return;
+    };
+
+    let mut after_seq = None;
+    if let Some(entity) = player {
+        if let Some(mut transform) = world.get_mut::<Transform>(entity) {
+            transform.translation = position;
+        }
+        if let Some(mut state) = world.get_mut::<MovementState>(entity) {
+            *state = MovementState::default();
+        }
+        // Inputs already queued were meant for the old position.
+        after_seq = world.get_mut::<ServerPlayer>(entity).and_then(|mut server_player| {
+            server_player.inputs.clear();
+            server_player.last_seq
+        });
+    }
+
+    with_server_net(world, |net| send_to_client(net, client_id, &ServerMessage::Teleport {
+        position: position.to_array(),
+        after_seq,
+    }));
+}
+
+fn list_command(world: &mut World, _: CommandSource, _: &[&str]) -> Result<String, String> {
+    let names: Vec<String> = world.resource::<JServerClients>().players.values().map(|p| p.name.clone()).collect();
+    Ok(format!("{} online: {}", names.len(), names.join(", ")))
+}
+
+fn seed_command(world: &mut World, _: CommandSource, _: &[&str]) -> Result<String, String> {
+    Ok(format!("Seed: {}", world.resource::<JPerlin>().perlin.seed()))
+}
+
+fn tp_command(world: &mut World, source: CommandSource, args: &[&str]) -> Result<String, String> {
+    let (target, rest) = match (args.len(), source) {
+        (3, CommandSource::Player(client_id)) => (client_id, args),
+        (1, CommandSource::Player(client_id)) => (client_id, args),
+        (2 | 4, _) => (find_player(world, args[0])?, &args[1..]),
+        _ => return Err(String::from("Wrong number of arguments")),
+    };
+
+    let position = if rest.len() == 1 {
+        let destination = find_player(world, rest[0])?;
+        world.resource::<JServerClients>().players[&destination].position
+    } else {
+        parse_position(rest, world.resource::<JServerClients>().players.get(&target).map(|p| p.position))?
+    };
+
+    teleport(world, target, position);
+    Ok(format!("Teleported {} to {:.1} {:.1} {:.1}", player_name(world, target), position.x, position.y, position.z))
+}
+
+fn kick_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    let target = find_player(world, args.first().ok_or_else(|| String::from("Missing player"))?)?;
+    let reason = if args.len() > 1 { args[1..].join(" ") } else { String::from("Kicked by an operator") };
+    let name = player_name(world, target);
+
+    kick(world, target, reason.clone());
+    Ok(format!("Kicked {}: {}", name, reason))
+}
+
+fn kick(world: &mut World, client_id: ClientId, reason: String) {
+    world.resource_scope(|world, mut disconnects: Mut<PendingDisconnects>| {
+        with_server_net(world, |net| kick_client(net, &mut disconnects, client_id, reason));
+    });
+}
+
+/// An online player by name, or anyone by uuid.
+fn find_identity(world: &World, arg: Option<&&str>) -> Result<(Uuid, String), String> {
+    let arg = arg.ok_or_else(|| String::from("Missing player"))?;
+    if let Ok(client_id) = find_player(world, arg) {
+        let player = &world.resource::<JServerClients>().players[&client_id];
+        return Ok((player.uuid, player.name.clone()));
+    }
+    Uuid::parse_str(arg)
+        .map(|uuid| (uuid, String::new()))
+        .map_err(|_| format!("No player called {} is online; use their uuid instead", arg))
+}
+
+fn save_access_lists(world: &World) {
+    if let Err(e) = world.resource::<AccessLists>().save(world.resource::<WorldPath>()) {
+        warn!("Couldn't save the whitelist and bans: {}", e);
+    }
+}
+
+fn ban_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    let (uuid, name) = find_identity(world, args.first())?;
+    let reason = args.get(1..).map(|rest| rest.join(" ")).unwrap_or_default();
+
+    world.resource_mut::<AccessLists>().banned_players.insert(uuid, reason.clone());
+    save_access_lists(world);
+
+    let online: Vec<ClientId> = world.resource::<JServerClients>().players.iter().filter(|(_, p)| p.uuid == uuid).map(|(id, _)| *id).collect();
+    for client_id in online {
+        kick(world, client_id, banned_message(&reason));
+    }
+    Ok(format!("Banned {}", if name.is_empty() { uuid.to_string() } else { name }))
+}
+
+fn pardon_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    let arg = args.first().ok_or_else(|| String::from("Missing uuid"))?;
+    let uuid = Uuid::parse_str(arg).map_err(|_| format!("{} isn't a uuid", arg))?;
+    if world.resource_mut::<AccessLists>().banned_players.remove(&uuid).is_none() {
+        return Err(format!("{} isn't banned", arg));
+    }
+    save_access_lists(world);
+    Ok(format!("Unbanned {}", arg))
+}
+
+fn whitelist_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    match args.first() {
+        Some(&"on") | Some(&"off") => {
+            let on = args[0] == "on";
+            world.resource_mut::<JServerSettings>().whitelist = on;
+            Ok(format!("Whitelist {}", args[0]))
+        }
+        Some(&"list") => {
+            let mut names: Vec<String> = world.resource::<AccessLists>().whitelist.iter()
+                .map(|(uuid, name)| if name.is_empty() { uuid.to_string() } else { name.clone() })
+                .collect();
+            names.sort();
+            Ok(format!("{} whitelisted: {}", names.len(), names.join(", ")))
+        }
+        Some(&"add") => {
+            let (uuid, name) = find_identity(world, args.get(1))?;
+            world.resource_mut::<AccessLists>().whitelist.insert(uuid, name.clone());
+            save_access_lists(world);
+            Ok(format!("Whitelisted {}", if name.is_empty() { uuid.to_string() } else { name }))
+        }
+        Some(&"remove") => {
+            let (uuid, _) = find_identity(world, args.get(1))?;
+            let Some(name) = world.resource_mut::<AccessLists>().whitelist.remove(&uuid) else {
+                return Err(format!("{} isn't whitelisted", args[1]));
+            };
+            save_access_lists(world);
+
+            if world.resource::<JServerSettings>().whitelist {
+                let online: Vec<ClientId> = world.resource::<JServerClients>().players.iter().filter(|(_, p)| p.uuid == uuid).map(|(id, _)| *id).collect();
+                for client_id in online {
+                    kick(world, client_id, String::from("You were removed from the whitelist"));
+                }
+            }
+            Ok(format!("Removed {} from the whitelist", if name.is_empty() { uuid.to_string() } else { name }))
+        }
+        _ => Err(String::from("Expected on, off, list, add or remove")),
+    }
+}
+
+fn time_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    match args.first() {
+        None => Ok(format!("Time is {:.0} of {:.0}", world.resource::<WorldTime>().time, DAY_LENGTH)),
+        Some(&"set") => {
+            let time = match args.get(1) {
+                Some(&"day") => DAY_LENGTH / 8.0,
+                Some(&"noon") => DAY_LENGTH / 4.0,
+                Some(&"night") => DAY_LENGTH * 5.0 / 8.0,
+                Some(&"midnight") => DAY_LENGTH * 3.0 / 4.0,
+                other => parse::<f64>(other, "time")?.rem_euclid(DAY_LENGTH),
+            };
+            world.resource_mut::<WorldTime>().time = time;
+
+            let ids: Vec<ClientId> = world.resource::<JServerClients>().players.keys().copied().collect();
+            with_server_net(world, |net| send_to_clients(net, ids.iter(), &ServerMessage::TimeOfDay { time }));
+            Ok(format!("Time set to {:.0}", time))
+        }
+        Some(other) => Err(format!("Unknown option {}", other)),
+    }
+}
+
+fn give_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    let target = find_player(world, args.first().ok_or_else(|| String::from("Missing player"))?)?;
+    let block = parse_block(args.get(1))?;
+    let count = if args.len() > 2 { parse::<u32>(args.get(2), "count")? } else { 1 };
+
+    if let Some(player) = world.resource_mut::<JServerClients>().players.get_mut(&target) {
+        player.held_block = block;
+        match player.inventory.iter_mut().find(|(b, _)| *b == block) {
+            Some((_, have)) => *have += count,
+            None => player.inventory.push((block, count)),
+        }
+    }
+    with_server_net(world, |net| send_to_client(net, target, &ServerMessage::GiveBlock { block, count }));
+    Ok(format!("Gave {} {} {}", player_name(world, target), count, Blocks::get_name(block)))
+}
+
+fn setblock_command(world: &mut World, source: CommandSource, args: &[&str]) -> Result<String, String> {
+    let spot = parse_position(args, source_position(world, source))?.floor().as_ivec3();
+    let block = parse_block(args.get(3))?;
+
+    world.resource_mut::<UserDataMap>().insert(spot, block);
+    world.send_event(BlockEdited { spot, block });
+    Ok(format!("Set {} to {}", spot, Blocks::get_name(block)))
+}
+
+fn paste_command(world: &mut World, source: CommandSource, args: &[&str]) -> Result<String, String> {
+    let name = args.first().ok_or_else(|| String::from("Missing structure name"))?.to_string();
+    let origin = if args.len() >= 4 {
+        parse_position(&args[1..], source_position(world, source))?
+    } else {
+        source_position(world, source).ok_or_else(|| String::from("Give a position to paste at"))?
+    }
+    .floor()
+    .as_ivec3();
+    let transform = StructureTransform {
+        rotation: if args.len() >= 5 { parse::<u32>(args.get(4), "rotation")? % 4 } else { 0 },
+        mirror: args.get(5).map(|m| *m == "mirror" || *m == "true").unwrap_or(false),
+    };
+
+    world.send_event(PasteStructure { name: name.clone(), origin, transform });
+    Ok(format!("Pasting {} at {}", name, origin))
+}
+
+fn summon_command(world: &mut World, source: CommandSource, args: &[&str]) -> Result<String, String> {
+    let kind = parse::<MobKind>(args.first(), "mob")?;
+    let position = if args.len() >= 4 {
+        parse_position(&args[1..4], source_position(world, source))?
+    } else {
+        source_position(world, source).ok_or_else(|| String::from("Give a position to summon at"))?
+    };
+
+    let id = world.resource_mut::<Mobs>().allocate_id();
+    world.spawn(mob_bundle(id, kind, position));
+    Ok(format!("Summoned a {} at {:.1} {:.1} {:.1}", format!("{:?}", kind).to_lowercase(), position.x, position.y, position.z))
+}
+
+fn save_command(world: &mut World, _: CommandSource, _: &[&str]) -> Result<String, String> {
+    match save_everything(world) {
+        Ok(()) => Ok(format!("Saved to {}", world.resource::<WorldPath>().0.display())),
+        Err(e) => Err(format!("Couldn't save: {}", e)),
+    }
+}
+
+fn stop_command(world: &mut World, _: CommandSource, _: &[&str]) -> Result<String, String> {
+    world.send_event(StopServer);
+    Ok(String::from("Stopping the server"))
+}
+
+fn op_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    let target = find_player(world, args.first().ok_or_else(|| String::from("Missing player"))?)?;
+    let level = parse::<PermissionLevel>(args.get(1), "permission level")?;
+    let uuid = world.resource::<JServerClients>().players[&target].uuid;
+
+    world.resource_mut::<Permissions>().levels.insert(uuid, level);
+    if let Err(e) = world.resource::<Permissions>().save(world.resource::<WorldPath>()) {
+        warn!("Couldn't save permissions: {}", e);
+    }
+    Ok(format!("{} is now a {:?}", player_name(world, target), level).to_lowercase())
+}
+
+fn lan_command(world: &mut World, _: CommandSource, args: &[&str]) -> Result<String, String> {
+    if with_server_net(world, |net| net.is_listening()) {
+        return Err(String::from("Already open to the network"));
+    }
+    let mut config = world.resource::<ServerConfig>().clone();
+    if !args.is_empty() {
+        config.port = parse::<u16>(args.first(), "port")?;
+    }
+
+    let address = with_server_net(world, |net| listen(net.quinnet_mut(), &config))?;
+    *world.resource_mut::<ServerConfig>() = config;
+    Ok(format!("Open to LAN on port {}", address.port()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jserver::ConnectedPlayer;
+
+    #[test]
+    fn parses_absolute_and_relative_coords() {
+        assert_eq!(parse_coord(Some(&"12.5"), None, "x"), Ok(12.5));
+        assert_eq!(parse_coord(Some(&"-3"), Some(10.0), "x"), Ok(-3.0));
+        assert_eq!(parse_coord(Some(&"~"), Some(10.0), "x"), Ok(10.0));
+        assert_eq!(parse_coord(Some(&"~-2.5"), Some(10.0), "x"), Ok(7.5));
+
+        assert!(parse_coord(Some(&"~"), None, "x").is_err());
+        assert!(parse_coord(Some(&"~up"), Some(10.0), "x").is_err());
+        assert!(parse_coord(Some(&"north"), None, "x").is_err());
+        assert_eq!(parse_coord(None, None, "y"), Err(String::from("Missing y")));
+    }
+
+    fn ping(_: &mut World, _: CommandSource, _: &[&str]) -> Result<String, String> {
+        Ok(String::from("pong"))
+    }
+
+    fn world_with_player(client_id: ClientId, uuid: Uuid) -> (World, CommandRegistry) {
+        let mut world = World::new();
+        let mut clients = JServerClients::default();
+        clients.players.insert(client_id, ConnectedPlayer::new(uuid, [0; 32], String::from("Ann")));
+        world.insert_resource(clients);
+        world.insert_resource(Permissions::default());
+
+        let mut registry = CommandRegistry::default();
+        registry.register(ServerCommand { name: "ping", usage: "/ping", help: "Pong", permission: PermissionLevel::Moderator, run: ping });
+        (world, registry)
+    }
+
+    #[test]
+    fn execute_checks_permissions() {
+        let uuid = Uuid::from_bytes([1; 16]);
+        let (mut world, registry) = world_with_player(5, uuid);
+        let player = CommandSource::Player(5);
+
+        assert_eq!(execute(&mut world, &registry, player, "/ping"), Err(String::from("you need to be a moderator to use /ping")));
+        assert_eq!(execute(&mut world, &registry, CommandSource::Console, "ping"), Ok(String::from("pong")));
+        assert_eq!(execute(&mut world, &registry, CommandSource::Player(LOOPBACK_CLIENT_ID), "/ping"), Ok(String::from("pong")));
+
+        world.resource_mut::<Permissions>().levels.insert(uuid, PermissionLevel::Moderator);
+        assert_eq!(execute(&mut world, &registry, player, "/ping"), Ok(String::from("pong")));
+    }
+
+    #[test]
+    fn help_and_unknown_commands() {
+        let (mut world, registry) = world_with_player(5, Uuid::from_bytes([1; 16]));
+
+        assert_eq!(execute(&mut world, &registry, CommandSource::Player(5), "/help"), Ok(String::new()));
+        assert_eq!(execute(&mut world, &registry, CommandSource::Console, "/help"), Ok(String::from("/ping - Pong")));
+        assert!(execute(&mut world, &registry, CommandSource::Console, "/nope").unwrap_err().starts_with("Unknown command /nope"));
+        assert!(execute(&mut world, &registry, CommandSource::Console, "/").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fmt, fs, io, net::{IpAddr, Ipv4Addr}, path::{Path, PathBuf}};
+
+use bevy::prelude::*;
+use noise::Perlin;
+use serde::{Deserialize, Serialize};
+
+use crate::{chunk::JPerlin, chat::MAX_CHAT_LENGTH, jserver::JServerSettings, serverworld::WorldPath};
+
+pub static DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// Everything a server operator can set, from `server.toml` and then the command line (see `cli::ServerArgs`).
+/// Keys are the field names; anything left out keeps its default.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub name: String,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// UDP port for `status` queries, 0 for none.
+    pub status_port: u16,
+    pub world: PathBuf,
+    /// Only used when the world is created; a saved world keeps its own.
+    pub seed: Option<u32>,
+    /// How many chunks around each player get streamed to them.
+    pub view_distance: i32,
+    pub tick_rate: f64,
+    pub motd: String,
+    pub max_players: usize,
+    pub whitelist: bool,
+    /// Tell the local network about this server, see `discovery`.
+    pub lan_announce: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from("Distant Garden Server"),
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 6000,
+            status_port: 6001,
+            world: PathBuf::from("world"),
+            seed: None,
+            view_distance: 7,
+            tick_rate: 60.0,
+            motd: String::new(),
+            max_players: 20,
+            whitelist: false,
+            lan_announce: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    /// Toml's own message names the key and line.
+    Parse(PathBuf, toml::de::Error),
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Couldn't read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Bad config in {}: {}", path.display(), e),
+            ConfigError::Invalid { key, message } => write!(f, "Bad config value for `{}`: {}", key, message),
+        }
+    }
+}
+
+fn invalid(key: &str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid { key: key.to_string(), message: message.into() }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse::<T>().map_err(|_| invalid(key, format!("couldn't understand {:?}", value)))
+}
+
+impl ServerConfig {
+    /// A missing file just means defaults. Nothing's checked yet, since the command line may
+    /// change it; `validate` before use.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No {}, using the default server config", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(ConfigError::Read(path.to_path_buf(), e)),
+        };
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Overrides one key, as given on the command line. Dashes and underscores are the same.
+    /// Only the value itself is checked; `validate` once everything's set.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let key = key.replace('-', "_");
+        match key.as_str() {
+            "name" => self.name = value.to_string(),
+            "bind_address" => self.bind_address = parse_value(&key, value)?,
+            "port" => self.port = parse_value(&key, value)?,
+            "status_port" => self.status_port = parse_value(&key, value)?,
+            "world" => self.world = PathBuf::from(value),
+            "seed" => self.seed = Some(parse_value(&key, value)?),
+            "view_distance" => self.view_distance = parse_value(&key, value)?,
+            "tick_rate" => self.tick_rate = parse_value(&key, value)?,
+            "motd" => self.motd = value.to_string(),
+            "max_players" => self.max_players = parse_value(&key, value)?,
+            "whitelist" => self.whitelist = parse_value(&key, value)?,
+            "lan_announce" => self.lan_announce = parse_value(&key, value)?,
+            _ => return Err(invalid(&key, "no such setting")),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.name.trim().is_empty() {
+            return Err(invalid("name", "can't be empty"));
+        }
+        if self.port == 0 {
+            return Err(invalid("port", "must be between 1 and 65535"));
+        }
+        if self.status_port == self.port {
+            return Err(invalid("status_port", "must be different from port"));
+        }
+        if !(2..=32).contains(&self.view_distance) {
+            return Err(invalid("view_distance", format!("must be between 2 and 32, got {}", self.view_distance)));
+        }
+        if !self.tick_rate.is_finite() || self.tick_rate < 1.0 || self.tick_rate > 1000.0 {
+            return Err(invalid("tick_rate", format!("must be between 1 and 1000, got {}", self.tick_rate)));
+        }
+        if self.motd.chars().count() > MAX_CHAT_LENGTH {
+            return Err(invalid("motd", format!("can't be longer than {} characters", MAX_CHAT_LENGTH)));
+        }
+        if self.max_players == 0 {
+            return Err(invalid("max_players", "must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// The config as it would be written in `server.toml`.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    /// Sets up the server's resources from this config; call before adding `JServerPlugin`.
+    pub fn apply(&self, app: &mut App) {
+        app.insert_resource(self.clone())
+        .insert_resource(WorldPath(self.world.clone()))
+        .insert_resource(JServerSettings {
+            view_distance: self.view_distance,
+            motd: self.motd.clone(),
+            max_players: self.max_players,
+            whitelist: self.whitelist,
+            ..default()
+        });
+        if let Some(seed) = self.seed {
+            app.insert_resource(JPerlin { perlin: Perlin::new(seed) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_parses_values_and_names() {
+        let mut config = ServerConfig::default();
+        config.set("max-players", "5").unwrap();
+        config.set("lan_announce", "false").unwrap();
+        config.set("seed", "42").unwrap();
+        assert_eq!(config.max_players, 5);
+        assert!(!config.lan_announce);
+        assert_eq!(config.seed, Some(42));
+
+        assert!(matches!(config.set("port", "lots"), Err(ConfigError::Invalid { .. })));
+        assert!(matches!(config.set("colour", "red"), Err(ConfigError::Invalid { .. })));
+    }
+
+    #[test]
+    fn validates_once_everything_is_set() {
+        let mut config = ServerConfig::default();
+        // Invalid on its own, fine once the status port moves too.
+        config.set("port", "6001").unwrap();
+        assert!(config.validate().is_err());
+        config.set("status_port", "6002").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_values() {
+        for (key, value) in [("name", " "), ("port", "0"), ("view_distance", "1"), ("view_distance", "33"), ("tick_rate", "0.5"), ("tick_rate", "NaN"), ("max_players", "0")] {
+            let mut config = ServerConfig::default();
+            config.set(key, value).unwrap();
+            assert!(config.validate().is_err(), "{} = {} should be refused", key, value);
+        }
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        let config = ServerConfig { seed: Some(7), motd: String::from("hi"), ..default() };
+        assert_eq!(toml::from_str::<ServerConfig>(&config.to_toml()).unwrap(), config);
+    }
+}
@@ -0,0 +1,270 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_quinnet::shared::ClientId;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    chunk::spot_to_chunk_pos,
+    jserver::{JServerClients, JServerSettings, PlayerJoined, PlayerLeft},
+    movement::{player_collider, simulate_input, MovementInput, MovementState, yaw_rotation, MOVEMENT_TICK_RATE},
+    protocol::{send_to_client, ClientMessage, FromClient, ServerMessage},
+    serverworld::{build_chunk_colliders, ChunkRegistry},
+    transport::ServerNet,
+};
+
+/// Each tick earns a player one input. Ticks they had none for are saved up to this many,
+/// so inputs the network held back catch up, but sending faster than the tick rate gets nothing.
+pub static MAX_INPUTS_PER_TICK: usize = 4;
+/// Inputs queued beyond this are from a client that's far behind or lying; drop the oldest.
+pub static MAX_QUEUED_INPUTS: usize = 60;
+
+/// Simulates each player's movement from their inputs when the server is authoritative.
+pub struct ServerPlayersPlugin;
+
+impl Plugin for ServerPlayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Time::<Fixed>::from_hz(MOVEMENT_TICK_RATE))
+        .add_systems(Update, (spawn_server_players, despawn_server_players, queue_player_inputs).chain())
+        .add_systems(FixedUpdate, simulate_server_players.after(build_chunk_colliders))
+        ;
+    }
+}
+
+#[derive(Component)]
+pub struct ServerPlayer {
+    pub client_id: ClientId,
+    /// By seq, which wraps, so take them in order with `pop_next`.
+    pub inputs: HashMap<u32, MovementInput>,
+    pub last_seq: Option<u32>,
+    /// Inputs we'll simulate before the next tick earns another.
+    pub budget: usize,
+}
+
+/// `b` comes after `a`, allowing for wraparound.
+pub fn seq_after(b: u32, a: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+impl ServerPlayer {
+    pub fn new(client_id: ClientId) -> Self {
+        Self { client_id, inputs: HashMap::new(), last_seq: None, budget: 0 }
+    }
+
+    /// Inputs are repeated across packets; only takes ones we haven't simulated.
+    pub fn queue(&mut self, input: MovementInput) {
+        if self.last_seq.map(|last| seq_after(input.seq, last)).unwrap_or(true) {
+            self.inputs.insert(input.seq, input);
+        }
+        while self.inputs.len() > MAX_QUEUED_INPUTS {
+            self.pop_next();
+        }
+    }
+
+    /// The earliest queued input. Everything queued is within a few seconds of everything else,
+    /// so earliest is the same measured from any of them.
+    pub fn pop_next(&mut self) -> Option<(u32, MovementInput)> {
+        let any = *self.inputs.keys().next()?;
+        let seq = *self.inputs.keys().min_by_key(|seq| seq.wrapping_sub(any) as i32)?;
+        self.inputs.remove(&seq).map(|input| (seq, input))
+    }
+}
+
+pub fn spawn_server_players(
+    mut commands: Commands,
+    mut clients: ResMut<JServerClients>,
+    mut joined: EventReader<PlayerJoined>,
+) {
+    for event in joined.read() {
+        let Some(player) = clients.players.get_mut(&event.client_id) else {
+            continue;
+        };
+        player.entity = Some(
+            commands
+                .spawn((
+                    TransformBundle::from_transform(Transform::from_translation(player.position).with_rotation(yaw_rotation(player.yaw))),
+                    player_collider(),
+                    MovementState::default(),
+                    ServerPlayer::new(event.client_id),
+                ))
+                .id(),
+        );
+    }
+}
+
+pub fn despawn_server_players(mut commands: Commands, mut left: EventReader<PlayerLeft>) {
+    for event in left.read() {
+        if let Some(entity) = event.player.entity {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+pub fn queue_player_inputs(
+    settings: Res<JServerSettings>,
+    mut clients: ResMut<JServerClients>,
+    mut messages: EventReader<FromClient>,
+    mut players: Query<&mut ServerPlayer>,
+) {
+    for FromClient { client_id, message } in messages.read() {
+        let ClientMessage::PlayerInput { inputs, pitch } = message else {
+            continue;
+        };
+        if !settings.authoritative_movement {
+            continue;
+        }
+        let Some(player) = clients.players.get_mut(client_id) else {
+            continue;
+        };
+        player.pitch = *pitch;
+        let Some(mut server_player) = player.entity.and_then(|e| players.get_mut(e).ok()) else {
+            continue;
+        };
+
+        for input in inputs.iter() {
+            server_player.queue(*input);
+        }
+    }
+}
+
+pub fn simulate_server_players(
+    time: Res<Time>,
+    mut context: ResMut<RapierContext>,
+    registry: Res<ChunkRegistry>,
+    mut net: ServerNet,
+    mut clients: ResMut<JServerClients>,
+    mut players: Query<(Entity, &mut Transform, &Collider, &mut MovementState, &mut ServerPlayer)>,
+) {
+    for (entity, mut transform, collider, mut state, mut server_player) in players.iter_mut() {
+        server_player.budget = (server_player.budget + 1).min(MAX_INPUTS_PER_TICK);
+        if server_player.inputs.is_empty() {
+            continue;
+        }
+        // Hold inputs until there's ground to stand on; the client's prediction catches up on the ack.
+        if !registry.is_ready(&spot_to_chunk_pos(&transform.translation.floor().as_ivec3())) {
+            continue;
+        }
+
+        let mut last = None;
+        while server_player.budget > 0 {
+            let Some((seq, input)) = server_player.pop_next() else {
+                break;
+            };
+            server_player.budget -= 1;
+            transform.translation = simulate_input(&mut context, collider, entity, transform.translation, &mut state, &input, time.delta_seconds());
+            transform.rotation = yaw_rotation(input.yaw);
+            last = Some((seq, input));
+        }
+        let Some((seq, input)) = last else {
+            continue;
+        };
+        server_player.last_seq = Some(seq);
+
+        if let Some(player) = clients.players.get_mut(&server_player.client_id) {
+            player.position = transform.translation;
+            player.yaw = input.yaw;
+            player.moving = input.moving();
+        }
+
+        send_to_client(&mut net, server_player.client_id, &ServerMessage::PlayerState {
+            seq,
+            position: transform.translation.to_array(),
+            state: *state,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy_quinnet::server::QuinnetServer;
+
+    use super::*;
+
+    fn input(seq: u32) -> MovementInput {
+        MovementInput { seq, ..default() }
+    }
+
+    fn drain(player: &mut ServerPlayer) -> Vec<u32> {
+        std::iter::from_fn(|| player.pop_next().map(|(seq, _)| seq)).collect()
+    }
+
+    #[test]
+    fn takes_inputs_in_order_across_wraparound() {
+        let mut player = ServerPlayer::new(1);
+        for seq in [1, u32::MAX, 0, u32::MAX - 1] {
+            player.queue(input(seq));
+        }
+        assert_eq!(drain(&mut player), vec![u32::MAX - 1, u32::MAX, 0, 1]);
+    }
+
+    #[test]
+    fn ignores_repeats_and_inputs_already_simulated() {
+        let mut player = ServerPlayer::new(1);
+        player.last_seq = Some(u32::MAX - 1);
+        for seq in [u32::MAX - 2, u32::MAX - 1, u32::MAX, 0, 0] {
+            player.queue(input(seq));
+        }
+        assert_eq!(drain(&mut player), vec![u32::MAX, 0]);
+
+        assert!(seq_after(0, u32::MAX));
+        assert!(!seq_after(u32::MAX, 0));
+        assert!(!seq_after(5, 5));
+    }
+
+    /// Runs `ticks` server ticks for a player walking forward in empty space, with `sent` inputs
+    /// arriving before each, and returns how far they got.
+    fn walk(ticks: usize, sent: impl Fn(usize) -> u32) -> f32 {
+        let mut world = World::new();
+        world.insert_resource(RapierContext::default());
+        world.init_resource::<QuinnetServer>();
+        world.init_resource::<JServerClients>();
+        let mut time = Time::<()>::default();
+        time.advance_by(std::time::Duration::from_secs_f64(1.0 / MOVEMENT_TICK_RATE));
+        world.insert_resource(time);
+        let mut registry = ChunkRegistry::default();
+        registry.built.insert(IVec2::ZERO);
+        world.insert_resource(registry);
+
+        let start = Vec3::new(0.5, 10.0, 0.5);
+        let player = world.spawn((Transform::from_translation(start), player_collider(), MovementState::default(), ServerPlayer::new(1))).id();
+
+        let mut seq = 0;
+        for tick in 0..ticks {
+            for _ in 0..sent(tick) {
+                world.get_mut::<ServerPlayer>(player).unwrap().queue(MovementInput { seq, forward: true, ..default() });
+                seq += 1;
+            }
+            world.run_system_once(simulate_server_players);
+        }
+        let end = world.get::<Transform>(player).unwrap().translation;
+        (end - start).xz().length()
+    }
+
+    #[test]
+    fn sending_inputs_faster_than_the_tick_rate_gets_no_further() {
+        let steady = walk(20, |_| 1);
+        assert!(steady > 0.0);
+        // Four inputs a tick: the extra ones queue up instead of being walked.
+        let burst = walk(20, |_| 4);
+        assert!((burst - steady).abs() < 1e-3, "{} vs {}", burst, steady);
+    }
+
+    #[test]
+    fn inputs_held_back_by_the_network_catch_up() {
+        let steady = walk(20, |_| 1);
+        // Nothing for three ticks, then all of them at once.
+        let bunched = walk(20, |tick| if tick % 4 == 3 { 4 } else { 0 });
+        assert!((bunched - steady).abs() < 1e-3, "{} vs {}", bunched, steady);
+    }
+
+    #[test]
+    fn drops_the_oldest_beyond_the_cap() {
+        let mut player = ServerPlayer::new(1);
+        let start = u32::MAX - 10;
+        for i in 0..MAX_QUEUED_INPUTS as u32 + 5 {
+            player.queue(input(start.wrapping_add(i)));
+        }
+        let seqs = drain(&mut player);
+        assert_eq!(seqs.len(), MAX_QUEUED_INPUTS);
+        assert_eq!(seqs[0], start.wrapping_add(5));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{fs, io, path::{Path, PathBuf}, time::Duration};
+
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy_rapier3d::prelude::*;
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+use noise::{Perlin, Seedable};
+
+use crate::{
+    chunk::{build_chunk_mesh, ensure_region_structures, spot_to_chunk_pos, JPerlin, NonUserDataMap, CW},
+    city::{city_module_for_chunk, load_module_collider, module_transform},
+    jserver::{JServerClients, JServerSettings, PlayerJoined},
+    protocol::{send_to_client, send_to_clients, ServerMessage},
+    serverconfig::ServerConfig,
+    structure::{PasteStructure, StructureTemplates},
+    transport::ServerNet,
+    UserDataMap,
+};
+
+/// Building chunk colliders is slow; spread it over ticks so movement keeps up.
+pub static MAX_CHUNK_BUILDS_PER_TICK: usize = 4;
+/// Seconds in a full day/night cycle.
+pub static DAY_LENGTH: f64 = 1200.0;
+/// How often clients are told the time, to correct drift.
+pub static TIME_SYNC_INTERVAL: f32 = 10.0;
+
+/// The authoritative world: terrain, structures and player edits, with colliders around players.
+pub struct ServerWorldPlugin;
+
+impl Plugin for ServerWorldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<JPerlin>()
+        .init_resource::<UserDataMap>()
+        .init_resource::<NonUserDataMap>()
+        .init_resource::<StructureTemplates>()
+        .init_resource::<ChunkRegistry>()
+        .init_resource::<CityColliders>()
+        .init_resource::<WorldPath>()
+        .init_resource::<WorldTime>()
+        .insert_resource(TimeSyncTimer(Timer::new(Duration::from_secs_f32(TIME_SYNC_INTERVAL), TimerMode::Repeating)))
+        .add_event::<BlocksChanged>()
+        .add_event::<BlockEdited>()
+        .add_event::<PasteStructure>()
+        .add_systems(Startup, load_world)
+        .add_systems(Update, (paste_structures_server, rebuild_changed_chunks).chain())
+        .add_systems(Update, (advance_time, sync_time).chain())
+        .add_systems(FixedUpdate, (load_chunks_near_players, unload_far_chunks, build_chunk_colliders).chain())
+        ;
+    }
+}
+
+/// Chunks the server has loaded, the entity carrying each one's collider, and which still need building.
+#[derive(Resource, Default)]
+pub struct ChunkRegistry {
+    pub chunks: HashMap<IVec2, Entity>,
+    pub dirty: HashSet<IVec2>,
+    pub built: HashSet<IVec2>,
+}
+
+impl ChunkRegistry {
+    /// A chunk is ready once its collider has been built at least once.
+    pub fn is_ready(&self, cpos: &IVec2) -> bool {
+        self.built.contains(cpos)
+    }
+
+    pub fn rebuild_touching(&mut self, min: &IVec3, max: &IVec3) {
+        // Same widening as rebuild_chunks_touching: border faces depend on the neighbor.
+        let mincpos = spot_to_chunk_pos(&(*min - IVec3::ONE));
+        let maxcpos = spot_to_chunk_pos(&(*max + IVec3::ONE));
+
+        for x in mincpos.x..=maxcpos.x {
+            for z in mincpos.y..=maxcpos.y {
+                let cpos = IVec2::new(x, z);
+                if self.chunks.contains_key(&cpos) {
+                    self.dirty.insert(cpos);
+                }
+            }
+        }
+    }
+}
+
+/// Sent whenever blocks in this box (inclusive) change on the server, so colliders
+/// are rebuilt and clients get the chunks again.
+#[derive(Event, Debug, Clone)]
+pub struct BlocksChanged {
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+/// One block changed through a validated player edit.
+#[derive(Event, Debug, Clone)]
+pub struct BlockEdited {
+    pub spot: IVec3,
+    pub block: u32,
+}
+
+#[derive(Component)]
+pub struct ServerChunk;
+
+/// Where the world is saved.
+#[derive(Resource)]
+pub struct WorldPath(pub PathBuf);
+
+impl Default for WorldPath {
+    fn default() -> Self {
+        Self(PathBuf::from("world"))
+    }
+}
+
+/// Seconds into the current day; 0 is dawn.
+#[derive(Resource, Default)]
+pub struct WorldTime {
+    pub time: f64,
+}
+
+#[derive(Resource)]
+pub struct TimeSyncTimer(Timer);
+
+/// Everything about a world that isn't regenerated from the seed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct WorldSave {
+    pub seed: u32,
+    pub time: f64,
+    pub edits: Vec<([i32; 3], u32)>,
+}
+
+impl WorldSave {
+    pub fn file(dir: &Path) -> PathBuf {
+        dir.join("world.jworld")
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        // Write then rename, so a crash mid-save doesn't lose the world.
+        let tmp = dir.join("world.jworld.tmp");
+        fs::write(&tmp, borsh::to_vec(self)?)?;
+        fs::rename(tmp, Self::file(dir))
+    }
+
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        borsh::from_slice(&fs::read(Self::file(dir))?)
+    }
+}
+
+pub fn save_world(path: &WorldPath, perlin: &JPerlin, time: &WorldTime, udm: &UserDataMap) -> io::Result<()> {
+    WorldSave {
+        seed: perlin.perlin.seed(),
+        time: time.time,
+        edits: udm.iter().map(|(spot, block)| (spot.to_array(), *block)).collect(),
+    }
+    .save(&path.0)
+}
+
+pub fn load_world(
+    path: Res<WorldPath>,
+    config: Option<Res<ServerConfig>>,
+    mut perlin: ResMut<JPerlin>,
+    mut time: ResMut<WorldTime>,
+    mut udm: ResMut<UserDataMap>,
+) {
+    match WorldSave::load(&path.0) {
+        Ok(save) => {
+            info!("Loaded {} with {} edits", path.0.display(), save.edits.len());
+            if let Some(seed) = config.and_then(|c| c.seed).filter(|seed| *seed != save.seed) {
+                warn!("Ignoring seed {} from the config, {} already has seed {}", seed, path.0.display(), save.seed);
+            }
+            perlin.perlin = Perlin::new(save.seed);
+            time.time = save.time;
+            *udm = save.edits.into_iter().map(|(spot, block)| (IVec3::from_array(spot), block)).collect();
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("No world at {}, starting a new one with seed {}", path.0.display(), perlin.perlin.seed());
+        }
+        Err(e) => {
+            // Don't carry on and overwrite it on the next save.
+            eprintln!("Couldn't load the world at {}: {}", path.0.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn advance_time(time: Res<Time>, mut world_time: ResMut<WorldTime>) {
+    world_time.time = (world_time.time + time.delta_seconds_f64()).rem_euclid(DAY_LENGTH);
+}
+
+pub fn sync_time(
+    time: Res<Time>,
+    mut timer: ResMut<TimeSyncTimer>,
+    mut net: ServerNet,
+    clients: Res<JServerClients>,
+    world_time: Res<WorldTime>,
+    mut joined: EventReader<PlayerJoined>,
+) {
+    let message = ServerMessage::TimeOfDay { time: world_time.time };
+
+    for event in joined.read() {
+        send_to_client(&mut net, event.client_id, &message);
+    }
+    if timer.0.tick(time.delta()).just_finished() {
+        send_to_clients(&mut net, clients.players.keys(), &message);
+    }
+}
+
+/// The server's side of `PasteStructure`: stamp into the edit layer and let colliders and clients catch up.
+pub fn paste_structures_server(
+    mut events: EventReader<PasteStructure>,
+    templates: Res<StructureTemplates>,
+    mut udm: ResMut<UserDataMap>,
+    mut changed: EventWriter<BlocksChanged>,
+) {
+    for event in events.read() {
+        let Some(template) = templates.by_name.get(&event.name) else {
+            warn!("No structure named {}", event.name);
+            continue;
+        };
+
+        template.stamp(event.origin, event.transform, |spot, block| {
+            udm.insert(spot, block);
+        });
+
+        let max = event.origin + event.transform.size(template.size()) - IVec3::ONE;
+        changed.send(BlocksChanged { min: event.origin, max });
+    }
+}
+
+/// City module colliders, loaded once per asset.
+#[derive(Resource, Default)]
+pub struct CityColliders(HashMap<&'static str, Option<Collider>>);
+
+fn player_chunks(clients: &JServerClients) -> Vec<IVec2> {
+    clients.players.values().map(|p| spot_to_chunk_pos(&p.position.floor().as_ivec3())).collect()
+}
+
+pub fn load_chunks_near_players(
+    mut commands: Commands,
+    clients: Res<JServerClients>,
+    settings: Res<JServerSettings>,
+    perlin: Res<JPerlin>,
+    templates: Res<StructureTemplates>,
+    mut nudm: ResMut<NonUserDataMap>,
+    mut registry: ResMut<ChunkRegistry>,
+    mut city_colliders: ResMut<CityColliders>,
+) {
+    let view = settings.view_distance;
+    for headcpos in player_chunks(&clients) {
+        for i in -view..=view {
+            for j in -view..=view {
+                let cpos = headcpos + IVec2::new(i, j);
+                if registry.chunks.contains_key(&cpos) {
+                    continue;
+                }
+
+                ensure_region_structures(&perlin.perlin, &templates, &mut nudm, &cpos);
+
+                let offset = Vec3::new(cpos.x as f32 * CW as f32, 0.0, cpos.y as f32 * CW as f32);
+                let chunkentity = commands
+                    .spawn((TransformBundle::from_transform(Transform::from_translation(offset)), ServerChunk))
+                    .with_children(|b| {
+                        let Some(module) = city_module_for_chunk(&perlin.perlin, &cpos) else {
+                            return;
+                        };
+                        let collider = city_colliders.0.entry(module.asset).or_insert_with(|| load_module_collider(module.asset));
+                        if let Some(collider) = collider {
+                            b.spawn((TransformBundle::from_transform(module_transform(&module, offset)), collider.clone()));
+                        }
+                    })
+                    .id();
+
+                registry.chunks.insert(cpos, chunkentity);
+                registry.dirty.insert(cpos);
+            }
+        }
+    }
+}
+
+pub fn rebuild_changed_chunks(mut registry: ResMut<ChunkRegistry>, mut changes: EventReader<BlocksChanged>, mut edits: EventReader<BlockEdited>) {
+    for change in changes.read() {
+        registry.rebuild_touching(&change.min, &change.max);
+    }
+    for edit in edits.read() {
+        registry.rebuild_touching(&edit.spot, &edit.spot);
+    }
+}
+
+pub fn unload_far_chunks(mut commands: Commands, clients: Res<JServerClients>, settings: Res<JServerSettings>, mut registry: ResMut<ChunkRegistry>) {
+    let heads = player_chunks(&clients);
+    let unload_distance = settings.unload_distance();
+
+    let faraway: Vec<IVec2> = registry.chunks.keys()
+        .filter(|cpos| heads.iter().all(|head| (**cpos - *head).abs().max_element() > unload_distance))
+        .copied()
+        .collect();
+
+    for cpos in faraway {
+        if let Some(chunkentity) = registry.chunks.remove(&cpos) {
+            commands.entity(chunkentity).despawn_recursive();
+        }
+        registry.dirty.remove(&cpos);
+        registry.built.remove(&cpos);
+    }
+}
+
+pub fn build_chunk_colliders(
+    mut commands: Commands,
+    clients: Res<JServerClients>,
+    perlin: Res<JPerlin>,
+    udm: Res<UserDataMap>,
+    nudm: Res<NonUserDataMap>,
+    mut registry: ResMut<ChunkRegistry>,
+) {
+    let heads = player_chunks(&clients);
+    let distance = |cpos: &IVec2| heads.iter().map(|head| (*cpos - *head).abs().max_element()).min().unwrap_or(0);
+
+    // Closest first, so the ground under a player is ready before the horizon.
+    let mut dirty: Vec<IVec2> = registry.dirty.iter().copied().collect();
+    dirty.sort_by_key(|cpos| distance(cpos));
+
+    for cpos in dirty.into_iter().take(MAX_CHUNK_BUILDS_PER_TICK) {
+        registry.dirty.remove(&cpos);
+        let Some(chunkentity) = registry.chunks.get(&cpos).copied() else {
+            continue;
+        };
+        registry.built.insert(cpos);
+
+        let mesh = build_chunk_mesh(&perlin.perlin, &udm, &nudm, &cpos);
+        match Collider::from_bevy_mesh(&mesh, &ComputedColliderShape::TriMesh) {
+            Some(collider) => {
+                commands.entity(chunkentity).try_insert(collider);
+            }
+            None => {
+                // All air: nothing to stand on.
+                commands.entity(chunkentity).remove::<Collider>();
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+/// Remote entities are drawn this far in the past so there's usually a snapshot on either side.
+pub static INTERPOLATION_DELAY: f64 = 0.1;
+/// How long to keep moving an entity along its last velocity when snapshots stop arriving.
+pub static MAX_EXTRAPOLATION: f64 = 0.25;
+/// A jump between consecutive snapshots bigger than this is a teleport, not movement.
+pub static TELEPORT_DISTANCE: f32 = 8.0;
+static MAX_SNAPSHOTS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub time: f64,
+    pub position: Vec3,
+    pub yaw: f32,
+}
+
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    pub snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if let Some(last) = self.snapshots.back() {
+            if snapshot.time <= last.time {
+                // Unreliable channel: late or duplicate, drop it.
+                return;
+            }
+            if snapshot.position.distance(last.position) > TELEPORT_DISTANCE {
+                self.snapshots.clear();
+            }
+        }
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The interpolated (or briefly extrapolated) state at `render_time`.
+    pub fn sample(&mut self, render_time: f64) -> Option<Snapshot> {
+        // Keep one snapshot at or before render_time to interpolate from.
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let first = *self.snapshots.front()?;
+        if render_time <= first.time || self.snapshots.len() == 1 {
+            return Some(first);
+        }
+
+        let (a, b) = (self.snapshots[0], self.snapshots[1]);
+        let span = (b.time - a.time).max(f64::EPSILON);
+
+        let t = if render_time <= b.time {
+            (render_time - a.time) / span
+        } else {
+            // Ran out of snapshots; carry on along the last velocity for a little while.
+            1.0 + (render_time - b.time).min(MAX_EXTRAPOLATION) / span
+        };
+
+        Some(Snapshot {
+            time: render_time,
+            position: a.position.lerp(b.position, t as f32),
+            yaw: lerp_angle(a.yaw, b.yaw, t as f32),
+        })
+    }
+}
+
+/// Lerps between two angles in degrees the short way round.
+pub fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    let delta = (b - a + 180.0).rem_euclid(360.0) - 180.0;
+    a + delta * t
+}
+
+/// Our estimate of the server's clock, from the timestamps it puts on snapshots.
+#[derive(Resource, Default)]
+pub struct ServerClock {
+    pub offset: Option<f64>,
+}
+
+impl ServerClock {
+    pub fn observe(&mut self, server_time: f64, local_time: f64) {
+        let sample = server_time - local_time;
+        self.offset = Some(match self.offset {
+            // A delayed packet makes the sample too small, so trust the largest quickly
+            // and only drift down slowly in case the server clock really did slip.
+            Some(offset) if sample < offset => offset + (sample - offset) * 0.01,
+            _ => sample,
+        });
+    }
+
+    pub fn render_time(&self, local_time: f64) -> Option<f64> {
+        Some(local_time + self.offset? - INTERPOLATION_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(time: f64, x: f32, yaw: f32) -> Snapshot {
+        Snapshot { time, position: Vec3::new(x, 0.0, 0.0), yaw }
+    }
+
+    fn buffer(snapshots: &[Snapshot]) -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::default();
+        for s in snapshots {
+            buffer.push(*s);
+        }
+        buffer
+    }
+
+    #[test]
+    fn sample_interpolates_between_snapshots() {
+        let mut buffer = buffer(&[snapshot(1.0, 0.0, 0.0), snapshot(1.1, 1.0, 10.0), snapshot(1.2, 3.0, 20.0)]);
+        let s = buffer.sample(1.05).unwrap();
+        assert!((s.position.x - 0.5).abs() < 1e-4);
+        assert!((s.yaw - 5.0).abs() < 1e-4);
+
+        // Moving on drops the snapshots that are behind us.
+        let s = buffer.sample(1.15).unwrap();
+        assert!((s.position.x - 2.0).abs() < 1e-4);
+        assert_eq!(buffer.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn sample_holds_before_the_first_and_extrapolates_briefly() {
+        let mut buffer = buffer(&[snapshot(1.0, 0.0, 0.0), snapshot(1.1, 1.0, 0.0)]);
+        assert_eq!(buffer.sample(0.5).unwrap().position.x, 0.0);
+
+        let s = buffer.sample(1.15).unwrap();
+        assert!((s.position.x - 1.5).abs() < 1e-4);
+        // Extrapolation stops after MAX_EXTRAPOLATION.
+        let far = buffer.sample(10.0).unwrap();
+        assert!((far.position.x - (1.0 + (MAX_EXTRAPOLATION / 0.1) as f32)).abs() < 1e-3);
+
+        assert_eq!(SnapshotBuffer::default().sample(1.0), None);
+    }
+
+    #[test]
+    fn push_drops_late_snapshots_and_snaps_on_teleport() {
+        let mut buffer = buffer(&[snapshot(1.0, 0.0, 0.0), snapshot(1.1, 1.0, 0.0), snapshot(1.05, 5.0, 0.0)]);
+        assert_eq!(buffer.snapshots.len(), 2);
+
+        buffer.push(snapshot(1.2, 100.0, 0.0));
+        assert_eq!(buffer.snapshots.len(), 1);
+        assert_eq!(buffer.sample(1.15).unwrap().position.x, 100.0);
+    }
+
+    #[test]
+    fn lerp_angle_goes_the_short_way() {
+        assert!((lerp_angle(350.0, 10.0, 0.5) - 360.0).abs() < 1e-4);
+        assert!((lerp_angle(10.0, 350.0, 0.5) - 0.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn server_clock_trusts_the_largest_offset() {
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.render_time(5.0), None);
+
+        clock.observe(105.0, 5.0);
+        assert_eq!(clock.offset, Some(100.0));
+        // A late packet only pulls the offset down a little.
+        clock.observe(104.0, 5.0);
+        assert!((clock.offset.unwrap() - 99.99).abs() < 1e-9);
+        // A faster one is trusted straight away.
+        clock.observe(106.5, 6.0);
+        assert_eq!(clock.offset, Some(100.5));
+        assert!((clock.render_time(7.0).unwrap() - (107.5 - INTERPOLATION_DELAY)).abs() < 1e-9);
+    }
+}
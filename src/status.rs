@@ -0,0 +1,250 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    jclient::split_address,
+    jserver::JServerClients,
+    protocol::{decode, encode, PROTOCOL_VERSION},
+    serverconfig::ServerConfig,
+    serverworld::ChunkRegistry,
+    transport::ServerNet,
+};
+
+static MAGIC: [u8; 4] = *b"DGSQ";
+/// Ticks per second are averaged over this many seconds.
+pub static TPS_WINDOW: f64 = 5.0;
+/// Keeps the reply inside one datagram; `online` still has the full count.
+pub static MAX_STATUS_PLAYERS: usize = 100;
+/// Queries are padded to this many bytes and answers are never bigger, so spoofing a query
+/// from someone else's address can't send them more than was sent to us.
+pub static STATUS_PACKET_SIZE: usize = 1200;
+/// Player names and the server's name are cut to this many characters in an answer, and the motd
+/// to `MAX_STATUS_MOTD`, so with no players at all it still fits.
+pub static MAX_STATUS_NAME: usize = 32;
+pub static MAX_STATUS_MOTD: usize = 128;
+/// Answers sent per tick at most, so a flood of queries can't take over the server.
+pub static MAX_STATUS_REPLIES: usize = 16;
+static STATUS_ATTEMPTS: u32 = 3;
+static STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct StatusRequest {
+    pub magic: [u8; 4],
+    /// Echoed back so a late answer to an earlier attempt is still matched.
+    pub nonce: u64,
+    /// Makes the query as big as the answer can be, see `STATUS_PACKET_SIZE`.
+    pub padding: Vec<u8>,
+}
+
+impl StatusRequest {
+    pub fn new(nonce: u64) -> Self {
+        let mut request = Self { magic: MAGIC, nonce, padding: Vec::new() };
+        request.padding = vec![0; STATUS_PACKET_SIZE.saturating_sub(encode(&request).len())];
+        request
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ServerStatus {
+    pub nonce: u64,
+    pub version: String,
+    pub protocol: u32,
+    pub name: String,
+    pub motd: String,
+    pub players: Vec<String>,
+    pub online: u32,
+    pub max_players: u32,
+    pub tps: f32,
+    pub tick_rate: f32,
+    pub loaded_chunks: u32,
+    /// Seconds.
+    pub uptime: f64,
+}
+
+/// Server side: answers status queries on a UDP port of their own, see `run_status_cli`.
+pub struct StatusPlugin;
+
+impl Plugin for StatusPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TickTimes>()
+        .add_systems(Update, (measure_ticks, open_status_socket, answer_status_queries.run_if(resource_exists::<StatusSocket>)).chain())
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct StatusSocket(pub UdpSocket);
+
+/// When each recent tick ran, by the wall clock.
+#[derive(Resource, Default)]
+pub struct TickTimes(pub VecDeque<f64>);
+
+impl TickTimes {
+    pub fn tps(&self) -> f32 {
+        match (self.0.front(), self.0.back()) {
+            (Some(first), Some(last)) if last > first => ((self.0.len() - 1) as f64 / (last - first)) as f32,
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn measure_ticks(time: Res<Time<Real>>, mut ticks: ResMut<TickTimes>) {
+    let now = time.elapsed_seconds_f64();
+    ticks.0.push_back(now);
+    while ticks.0.front().map(|t| now - t > TPS_WINDOW).unwrap_or(false) {
+        ticks.0.pop_front();
+    }
+}
+
+/// Opens once the server is listening, so a singleplayer world only answers after `/lan`.
+pub fn open_status_socket(mut commands: Commands, net: ServerNet, config: Res<ServerConfig>, mut tried: Local<bool>) {
+    if *tried || config.status_port == 0 || !net.is_listening() {
+        return;
+    }
+    *tried = true;
+
+    let address = SocketAddr::new(config.bind_address, config.status_port);
+    match UdpSocket::bind(address).and_then(|socket| socket.set_nonblocking(true).map(|_| socket)) {
+        Ok(socket) => {
+            info!("Answering status queries on {}", address);
+            commands.insert_resource(StatusSocket(socket));
+        }
+        Err(e) => warn!("Couldn't open the status port {}: {}", address, e),
+    }
+}
+
+pub fn answer_status_queries(
+    time: Res<Time<Real>>,
+    socket: Res<StatusSocket>,
+    config: Res<ServerConfig>,
+    clients: Res<JServerClients>,
+    registry: Res<ChunkRegistry>,
+    ticks: Res<TickTimes>,
+) {
+    let mut buf = vec![0u8; 2 * STATUS_PACKET_SIZE];
+    for _ in 0..MAX_STATUS_REPLIES {
+        let (len, from) = match socket.0.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                debug!("Couldn't read a status query: {}", e);
+                continue;
+            }
+        };
+        if len < STATUS_PACKET_SIZE {
+            continue;
+        }
+        let Some(request) = decode::<StatusRequest>(&buf[..len]).filter(|r| r.magic == MAGIC) else {
+            continue;
+        };
+
+        let mut players: Vec<String> = clients.players.values().map(|p| truncate_chars(&p.name, MAX_STATUS_NAME)).collect();
+        players.sort();
+        players.truncate(MAX_STATUS_PLAYERS);
+
+        let mut status = ServerStatus {
+            nonce: request.nonce,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            name: truncate_chars(&config.name, MAX_STATUS_NAME),
+            motd: truncate_chars(&config.motd, MAX_STATUS_MOTD),
+            players,
+            online: clients.players.len() as u32,
+            max_players: config.max_players as u32,
+            tps: ticks.tps(),
+            tick_rate: config.tick_rate as f32,
+            loaded_chunks: registry.chunks.len() as u32,
+            uptime: time.elapsed_seconds_f64(),
+        };
+        let mut reply = encode(&status);
+        while reply.len() > STATUS_PACKET_SIZE && status.players.pop().is_some() {
+            reply = encode(&status);
+        }
+        if let Err(e) = socket.0.send_to(&reply, from) {
+            debug!("Couldn't answer a status query from {}: {}", from, e);
+        }
+    }
+}
+
+fn truncate_chars(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+fn format_uptime(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Asks a server how it's doing, retrying a couple of times since this is plain UDP.
+pub fn query_status(server: SocketAddr) -> io::Result<(ServerStatus, Duration)> {
+    let local = match server.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    socket.set_read_timeout(Some(STATUS_TIMEOUT))?;
+
+    let nonce: u64 = rand::random();
+    let request = encode(&StatusRequest::new(nonce));
+    let mut buf = vec![0u8; 65536];
+
+    for _ in 0..STATUS_ATTEMPTS {
+        let sent = Instant::now();
+        socket.send_to(&request, server)?;
+        while sent.elapsed() < STATUS_TIMEOUT {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == server => {
+                    if let Some(status) = decode::<ServerStatus>(&buf[..len]).filter(|s| s.nonce == nonce) {
+                        return Ok((status, sent.elapsed()));
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+}
+
+/// `status host:port`, where the port is the server's `status_port`.
+pub fn run_status_cli(address: &str) {
+    let (host, port) = split_address(address, ServerConfig::default().status_port);
+    let Some(server) = (host.as_str(), port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) else {
+        eprintln!("Couldn't resolve {}", host);
+        std::process::exit(1);
+    };
+
+    let (status, ping) = match query_status(server) {
+        Ok(answer) => answer,
+        Err(e) => {
+            eprintln!("{} didn't answer: {}", server, e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("{} at {}", status.name, server);
+    if !status.motd.is_empty() {
+        println!("  {}", status.motd);
+    }
+    let compatible = if status.protocol == PROTOCOL_VERSION { "" } else { ", not compatible with this client" };
+    println!("Version:   {} (protocol {}{})", status.version, status.protocol, compatible);
+    println!("Players:   {}/{}{}", status.online, status.max_players, if status.players.is_empty() { String::new() } else { format!(": {}", status.players.join(", ")) });
+    println!("TPS:       {:.1} of {:.0}", status.tps, status.tick_rate);
+    println!("Chunks:    {} loaded", status.loaded_chunks);
+    println!("Uptime:    {}", format_uptime(status.uptime));
+    println!("Ping:      {} ms", ping.as_millis());
+}
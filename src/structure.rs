@@ -0,0 +1,490 @@
+use std::{fs, io, path::Path};
+
+use bevy::{prelude::*, utils::HashMap};
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+use noise::{Perlin, Seedable};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::{blockinfo::{Blocks, BLOCK_DIRECTION_BITS}, chunk::{rebuild_chunks_touching, NonUserDataMap, CW, STRUCTURE_REGION_CHUNKS}, city::cell_hash, UserDataMap};
+
+/// Horizontal facings in direction-bit order. One quarter turn takes a facing to the next one.
+pub static FACINGS: [IVec3; 4] = [
+    IVec3 { x: 0, y: 0, z: 1 },
+    IVec3 { x: 1, y: 0, z: 0 },
+    IVec3 { x: 0, y: 0, z: -1 },
+    IVec3 { x: -1, y: 0, z: 0 },
+];
+
+pub fn opposite_facing(facing: u32) -> u32 {
+    (facing + 2) % 4
+}
+
+/// A spot on the edge of a template where another template of the same `kind` may attach.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct Connector {
+    pub pos: [i32; 3],
+    pub facing: u32,
+    pub kind: String,
+}
+
+/// A prefab: a block palette, a dense box of palette indices and its connector markers.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct StructureTemplate {
+    pub name: String,
+    pub size: [i32; 3],
+    pub palette: Vec<u32>,
+    pub blocks: Vec<u16>,
+    pub connectors: Vec<Connector>,
+}
+
+impl StructureTemplate {
+    pub fn new(name: &str, size: IVec3) -> Self {
+        Self {
+            name: name.to_string(),
+            size: size.to_array(),
+            palette: vec![0],
+            blocks: vec![0; (size.x * size.y * size.z).max(0) as usize],
+            connectors: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> IVec3 {
+        IVec3::from_array(self.size)
+    }
+
+    pub fn index(&self, local: &IVec3) -> Option<usize> {
+        let size = self.size();
+        if local.cmplt(IVec3::ZERO).any() || local.cmpge(size).any() {
+            return None;
+        }
+        Some(((local.y * size.z + local.z) * size.x + local.x) as usize)
+    }
+
+    pub fn get(&self, local: &IVec3) -> u32 {
+        match self.index(local) {
+            Some(i) => self.palette[self.blocks[i] as usize],
+            None => 0,
+        }
+    }
+
+    pub fn set(&mut self, local: &IVec3, block: u32) {
+        if let Some(i) = self.index(local) {
+            let entry = match self.palette.iter().position(|b| *b == block) {
+                Some(entry) => entry,
+                None => {
+                    self.palette.push(block);
+                    self.palette.len() - 1
+                }
+            };
+            self.blocks[i] = entry as u16;
+        }
+    }
+
+    pub fn fill(&mut self, min: IVec3, max: IVec3, block: u32) {
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    self.set(&IVec3::new(x, y, z), block);
+                }
+            }
+        }
+    }
+
+    pub fn add_connector(&mut self, pos: IVec3, facing: u32, kind: &str) {
+        self.connectors.push(Connector {
+            pos: pos.to_array(),
+            facing,
+            kind: kind.to_string(),
+        });
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, borsh::to_vec(self)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        borsh::from_slice(&fs::read(path)?)
+    }
+
+    /// Calls `f` with the world position and block of every cell once placed with `transform` at `origin`.
+    pub fn stamp(&self, origin: IVec3, transform: StructureTransform, mut f: impl FnMut(IVec3, u32)) {
+        let size = self.size();
+        for y in 0..size.y {
+            for z in 0..size.z {
+                for x in 0..size.x {
+                    let local = IVec3::new(x, y, z);
+                    let block = self.get(&local);
+                    f(origin + transform.apply(local, size), transform.block(block));
+                }
+            }
+        }
+    }
+}
+
+/// Quarter turns about +Y, optionally mirrored across X first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StructureTransform {
+    pub rotation: u32,
+    pub mirror: bool,
+}
+
+impl StructureTransform {
+    pub fn all() -> impl Iterator<Item = StructureTransform> {
+        (0..8).map(|i| StructureTransform {
+            rotation: i % 4,
+            mirror: i >= 4,
+        })
+    }
+
+    pub fn facing(&self, facing: u32) -> u32 {
+        let facing = if self.mirror && facing % 2 == 1 {
+            opposite_facing(facing)
+        } else {
+            facing
+        };
+        (facing + self.rotation) % 4
+    }
+
+    pub fn size(&self, size: IVec3) -> IVec3 {
+        if self.rotation % 2 == 1 {
+            IVec3::new(size.z, size.y, size.x)
+        } else {
+            size
+        }
+    }
+
+    pub fn apply(&self, local: IVec3, size: IVec3) -> IVec3 {
+        let mut p = local;
+        let mut sx = size.x;
+        let mut sz = size.z;
+        if self.mirror {
+            p.x = sx - 1 - p.x;
+        }
+        for _ in 0..self.rotation % 4 {
+            p = IVec3::new(p.z, p.y, sx - 1 - p.x);
+            std::mem::swap(&mut sx, &mut sz);
+        }
+        p
+    }
+
+    pub fn block(&self, block: u32) -> u32 {
+        if block & Blocks::block_id_bits() == 0 {
+            return block;
+        }
+        let facing = self.facing(Blocks::get_direction_bits(block));
+        let mut block = block & !BLOCK_DIRECTION_BITS;
+        Blocks::set_direction_bits(&mut block, facing);
+        block
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlacedStructure {
+    pub template: usize,
+    pub origin: IVec3,
+    pub transform: StructureTransform,
+}
+
+impl PlacedStructure {
+    pub fn bounds(&self, pool: &[StructureTemplate]) -> (IVec3, IVec3) {
+        let size = self.transform.size(pool[self.template].size());
+        (self.origin, self.origin + size - IVec3::ONE)
+    }
+}
+
+struct OpenConnector {
+    pos: IVec3,
+    facing: u32,
+    kind: String,
+}
+
+fn overlaps(a: &(IVec3, IVec3), b: &(IVec3, IVec3)) -> bool {
+    a.0.cmple(b.1).all() && b.0.cmple(a.1).all()
+}
+
+/// Jigsaw assembly: starting from `start`, repeatedly attaches pool templates to open
+/// connectors of matching kind and opposite facing, skipping anything that would overlap.
+pub fn assemble(pool: &[StructureTemplate], start: usize, origin: IVec3, seed: u64, max_pieces: usize) -> Vec<PlacedStructure> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut placed = vec![PlacedStructure {
+        template: start,
+        origin,
+        transform: StructureTransform::default(),
+    }];
+    let mut bounds = vec![placed[0].bounds(pool)];
+    let mut open: Vec<OpenConnector> = Vec::new();
+
+    let push_connectors = |open: &mut Vec<OpenConnector>, piece: &PlacedStructure, skip: Option<usize>| {
+        let template = &pool[piece.template];
+        for (i, c) in template.connectors.iter().enumerate() {
+            if Some(i) == skip {
+                continue;
+            }
+            open.push(OpenConnector {
+                pos: piece.origin + piece.transform.apply(IVec3::from_array(c.pos), template.size()),
+                facing: piece.transform.facing(c.facing),
+                kind: c.kind.clone(),
+            });
+        }
+    };
+
+    push_connectors(&mut open, &placed[0], None);
+
+    let mut cursor = 0;
+    while cursor < open.len() && placed.len() < max_pieces {
+        let target = open[cursor].pos + FACINGS[open[cursor].facing as usize];
+        let wanted = opposite_facing(open[cursor].facing);
+
+        let mut candidates = Vec::new();
+        for (t, template) in pool.iter().enumerate() {
+            for (c, connector) in template.connectors.iter().enumerate() {
+                if connector.kind != open[cursor].kind {
+                    continue;
+                }
+                for transform in StructureTransform::all() {
+                    if transform.facing(connector.facing) == wanted {
+                        candidates.push((t, c, transform));
+                    }
+                }
+            }
+        }
+        candidates.shuffle(&mut rng);
+
+        for (t, c, transform) in candidates {
+            let template = &pool[t];
+            let local = transform.apply(IVec3::from_array(template.connectors[c].pos), template.size());
+            let piece = PlacedStructure {
+                template: t,
+                origin: target - local,
+                transform,
+            };
+            let piecebounds = piece.bounds(pool);
+            if bounds.iter().any(|b| overlaps(b, &piecebounds)) {
+                continue;
+            }
+
+            push_connectors(&mut open, &piece, Some(c));
+            bounds.push(piecebounds);
+            placed.push(piece);
+            break;
+        }
+
+        cursor += 1;
+    }
+
+    placed
+}
+
+/// Hand-built dungeon pieces so worldgen has something to assemble without asset files.
+pub fn builtin_dungeon_pool() -> Vec<StructureTemplate> {
+    let mut room = StructureTemplate::new("dungeon_room", IVec3::new(9, 6, 9));
+    room.fill(IVec3::ZERO, IVec3::new(8, 5, 8), 5);
+    room.fill(IVec3::ONE, IVec3::new(7, 4, 7), 0);
+    room.set(&IVec3::new(4, 4, 4), 18);
+    for (facing, pos) in [
+        (0, IVec3::new(4, 1, 8)),
+        (1, IVec3::new(8, 1, 4)),
+        (2, IVec3::new(4, 1, 0)),
+        (3, IVec3::new(0, 1, 4)),
+    ] {
+        room.fill(pos, pos + IVec3::Y, 0);
+        room.add_connector(pos, facing, "corridor");
+    }
+
+    let mut corridor = StructureTemplate::new("dungeon_corridor", IVec3::new(3, 4, 8));
+    corridor.fill(IVec3::ZERO, IVec3::new(2, 3, 7), 9);
+    corridor.fill(IVec3::new(1, 1, 0), IVec3::new(1, 2, 7), 0);
+    corridor.add_connector(IVec3::new(1, 1, 0), 2, "corridor");
+    corridor.add_connector(IVec3::new(1, 1, 7), 0, "corridor");
+
+    let mut bend = StructureTemplate::new("dungeon_bend", IVec3::new(3, 4, 3));
+    bend.fill(IVec3::ZERO, IVec3::new(2, 3, 2), 9);
+    bend.set(&IVec3::new(1, 1, 1), 0);
+    bend.set(&IVec3::new(1, 2, 1), 0);
+    bend.fill(IVec3::new(1, 1, 0), IVec3::new(1, 2, 0), 0);
+    bend.fill(IVec3::new(2, 1, 1), IVec3::new(2, 2, 1), 0);
+    bend.add_connector(IVec3::new(1, 1, 0), 2, "corridor");
+    bend.add_connector(IVec3::new(2, 1, 1), 1, "corridor");
+
+    vec![room, corridor, bend]
+}
+
+#[derive(Resource)]
+pub struct StructureTemplates {
+    pub dungeon_pool: Vec<StructureTemplate>,
+    pub by_name: HashMap<String, StructureTemplate>,
+}
+
+impl Default for StructureTemplates {
+    fn default() -> Self {
+        let dungeon_pool = builtin_dungeon_pool();
+        let mut by_name = HashMap::new();
+
+        for template in dungeon_pool.iter() {
+            by_name.insert(template.name.clone(), template.clone());
+        }
+
+        if let Ok(entries) = fs::read_dir("assets/structures") {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().map(|e| e == "jstruct").unwrap_or(false) {
+                    match StructureTemplate::load(&path) {
+                        Ok(template) => {
+                            by_name.insert(template.name.clone(), template);
+                        }
+                        Err(e) => warn!("Couldn't load structure {:?}: {}", path, e),
+                    }
+                }
+            }
+        }
+
+        Self { dungeon_pool, by_name }
+    }
+}
+
+/// Admin paste: stamps a named template into the user edit layer.
+#[derive(Event, Clone, Debug)]
+pub struct PasteStructure {
+    pub name: String,
+    pub origin: IVec3,
+    pub transform: StructureTransform,
+}
+
+/// One in this many structure regions gets a dungeon.
+pub static DUNGEON_RARITY: u32 = 3;
+pub static DUNGEON_MAX_PIECES: usize = 12;
+
+pub fn generate_region_dungeon(perlin: &Perlin, pool: &[StructureTemplate], region: &IVec2, out: &mut NonUserDataMap) {
+    if pool.is_empty() {
+        return;
+    }
+
+    let h = cell_hash(perlin.seed().wrapping_add(7), region.x, region.y);
+    if !h.is_multiple_of(DUNGEON_RARITY) {
+        return;
+    }
+
+    let regionblocks = STRUCTURE_REGION_CHUNKS * CW;
+    let origin = IVec3::new(
+        region.x * regionblocks + regionblocks / 4 + ((h >> 4) % (regionblocks as u32 / 2)) as i32,
+        12 + ((h >> 12) % 16) as i32,
+        region.y * regionblocks + regionblocks / 4 + ((h >> 20) % (regionblocks as u32 / 2)) as i32,
+    );
+
+    for piece in assemble(pool, 0, origin, h as u64, DUNGEON_MAX_PIECES) {
+        pool[piece.template].stamp(piece.origin, piece.transform, |spot, block| {
+            out.insert(spot, block);
+        });
+    }
+}
+
+pub fn paste_structures(
+    mut commands: Commands,
+    mut events: EventReader<PasteStructure>,
+    templates: Res<StructureTemplates>,
+    mut udm: ResMut<UserDataMap>,
+) {
+    for event in events.read() {
+        let Some(template) = templates.by_name.get(&event.name) else {
+            warn!("No structure named {}", event.name);
+            continue;
+        };
+
+        template.stamp(event.origin, event.transform, |spot, block| {
+            udm.insert(spot, block);
+        });
+
+        let max = event.origin + event.transform.size(template.size()) - IVec3::ONE;
+        rebuild_chunks_touching(&mut commands, &event.origin, &max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(size: IVec3) -> impl Iterator<Item = IVec3> {
+        (0..size.y).flat_map(move |y| (0..size.z).flat_map(move |z| (0..size.x).map(move |x| IVec3::new(x, y, z))))
+    }
+
+    #[test]
+    fn every_transform_maps_the_box_onto_itself() {
+        let size = IVec3::new(3, 2, 5);
+        for transform in StructureTransform::all() {
+            let turned = transform.size(size);
+            let mut seen = std::collections::HashSet::new();
+            for local in cells(size) {
+                let p = transform.apply(local, size);
+                assert!(p.cmpge(IVec3::ZERO).all() && p.cmplt(turned).all(), "{:?} put {} at {}", transform, local, p);
+                assert!(seen.insert(p), "{:?} put two cells at {}", transform, p);
+            }
+        }
+    }
+
+    #[test]
+    fn connectors_keep_facing_out_of_the_box() {
+        let size = IVec3::new(3, 4, 8);
+        let connectors = [(IVec3::new(1, 1, 0), 2), (IVec3::new(1, 1, 7), 0), (IVec3::new(0, 1, 3), 3), (IVec3::new(2, 1, 3), 1)];
+        for transform in StructureTransform::all() {
+            let turned = transform.size(size);
+            for (pos, facing) in connectors {
+                let outside = transform.apply(pos, size) + FACINGS[transform.facing(facing) as usize];
+                assert!(outside.cmplt(IVec3::ZERO).any() || outside.cmpge(turned).any(), "{:?} turned connector {} inwards", transform, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn quarter_turns_and_mirrors_compose() {
+        let size = IVec3::new(3, 1, 5);
+        let local = IVec3::new(0, 0, 1);
+        let once = StructureTransform { rotation: 1, mirror: false };
+        let twice = StructureTransform { rotation: 2, mirror: false };
+        assert_eq!(once.apply(once.apply(local, size), once.size(size)), twice.apply(local, size));
+        assert_eq!(twice.apply(local, size), IVec3::new(2, 0, 3));
+
+        let mirror = StructureTransform { rotation: 0, mirror: true };
+        assert_eq!(mirror.apply(local, size), IVec3::new(2, 0, 1));
+        assert_eq!(mirror.facing(1), 3);
+        assert_eq!(mirror.facing(0), 0);
+    }
+
+    #[test]
+    fn assemble_attaches_pieces_without_overlap() {
+        let pool = builtin_dungeon_pool();
+        let placed = assemble(&pool, 0, IVec3::new(0, 10, 0), 7, 12);
+        assert!(placed.len() > 1 && placed.len() <= 12);
+        assert_eq!(placed[0], PlacedStructure { template: 0, origin: IVec3::new(0, 10, 0), transform: StructureTransform::default() });
+
+        let bounds: Vec<_> = placed.iter().map(|piece| piece.bounds(&pool)).collect();
+        for (i, a) in bounds.iter().enumerate() {
+            for b in &bounds[i + 1..] {
+                assert!(!overlaps(a, b), "{:?} overlaps {:?}", a, b);
+            }
+        }
+
+        // Every piece after the first is attached: one of its connectors meets one from an earlier piece.
+        let ends = |piece: &PlacedStructure| -> Vec<(IVec3, u32)> {
+            let template = &pool[piece.template];
+            template.connectors.iter()
+                .map(|c| (piece.origin + piece.transform.apply(IVec3::from_array(c.pos), template.size()), piece.transform.facing(c.facing)))
+                .collect()
+        };
+        for (i, piece) in placed.iter().enumerate().skip(1) {
+            let attached = ends(piece).iter().any(|(pos, facing)| {
+                placed[..i].iter().flat_map(&ends).any(|(other_pos, other_facing)| {
+                    other_facing == opposite_facing(*facing) && other_pos + FACINGS[other_facing as usize] == *pos
+                })
+            });
+            assert!(attached, "piece {} ({:?}) isn't attached to anything", i, piece);
+        }
+    }
+
+    #[test]
+    fn assemble_is_repeatable_for_a_seed() {
+        let pool = builtin_dungeon_pool();
+        assert_eq!(assemble(&pool, 0, IVec3::ZERO, 42, 10), assemble(&pool, 0, IVec3::ZERO, 42, 10));
+        assert_eq!(assemble(&pool, 0, IVec3::ZERO, 42, 1).len(), 1);
+    }
+}
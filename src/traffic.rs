@@ -0,0 +1,492 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bevy::{prelude::*, time::{TimeSystem, TimeUpdateStrategy}, utils::{HashMap, HashSet}};
+use bevy_quinnet::shared::{channels::ChannelId, ClientId};
+use borsh_derive::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    protocol::{decode, encode, FromServer, PROTOCOL_VERSION},
+    serverconfig::ServerConfig,
+    transport::LinkEvent,
+};
+
+static MAGIC: [u8; 4] = *b"DGTR";
+/// Once a replay has run out, connections are closed after this many frames and the app stops after as many again.
+pub static REPLAY_LINGER_FRAMES: u64 = 60;
+/// Replayed messages are logged cut down to this many characters; chunks are big.
+pub static MAX_LOGGED_MESSAGE: usize = 200;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedSide {
+    Client,
+    Server,
+}
+
+/// The start of every recording.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct RecordingHeader {
+    pub magic: [u8; 4],
+    pub protocol: u32,
+    pub side: RecordedSide,
+    /// A server's config as toml, with the seed it was really using.
+    pub config: Option<String>,
+}
+
+/// One thing that happened on the wire. A client records everything under client id 0.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum TrafficEvent {
+    /// A frame started, `delta` seconds after the one before.
+    Frame {
+        delta: f64,
+    },
+    Connected {
+        client_id: ClientId,
+        /// So bans and per-address limits see the same thing on replay.
+        address: Option<String>,
+    },
+    Disconnected {
+        client_id: ClientId,
+    },
+    Sent {
+        client_id: ClientId,
+        channel: ChannelId,
+        payload: Vec<u8>,
+    },
+    Received {
+        client_id: ClientId,
+        channel: ChannelId,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TrafficRecord {
+    /// Updates since the app started; a replay hands the event over on the same one.
+    pub frame: u64,
+    /// Seconds since recording started, for reading along.
+    pub time: f64,
+    pub event: TrafficEvent,
+}
+
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Where a server recording keeps the world as it was when recording started.
+pub fn world_copy(recording: &Path) -> PathBuf {
+    let mut path = recording.as_os_str().to_owned();
+    path.push(".world");
+    PathBuf::from(path)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes every message sent and received to a file, see `ServerNet` and `ClientNet`.
+#[derive(Resource)]
+pub struct TrafficRecorder {
+    writer: Option<BufWriter<File>>,
+    path: PathBuf,
+    start: Instant,
+    frame: Option<u64>,
+}
+
+impl TrafficRecorder {
+    /// For a server, pass its config, and the world it's about to load is copied next to the recording.
+    /// Its seed should be set, or a new world can't be made again.
+    pub fn create(path: &Path, side: RecordedSide, config: Option<&ServerConfig>) -> io::Result<Self> {
+        let mut toml = None;
+        if let Some(config) = config {
+            toml = Some(config.to_toml());
+            let copy = world_copy(path);
+            if copy.exists() {
+                fs::remove_dir_all(&copy)?;
+            }
+            if config.world.exists() {
+                copy_dir(&config.world, &copy)?;
+            } else {
+                fs::create_dir_all(&copy)?;
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_frame(&mut writer, &encode(&RecordingHeader { magic: MAGIC, protocol: PROTOCOL_VERSION, side, config: toml }))?;
+        Ok(Self { writer: Some(writer), path: path.to_path_buf(), start: Instant::now(), frame: None })
+    }
+
+    pub fn record(&mut self, event: TrafficEvent) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let record = TrafficRecord { frame: self.frame.unwrap_or(0), time: self.start.elapsed().as_secs_f64(), event };
+        if let Err(e) = write_frame(writer, &encode(&record)) {
+            // Keep playing; a recording with a hole in it would only mislead.
+            warn!("Couldn't write to {}, stopped recording: {}", self.path.display(), e);
+            self.writer = None;
+        }
+    }
+
+    pub fn start_frame(&mut self, delta: Duration) {
+        self.frame = Some(self.frame.map(|frame| frame + 1).unwrap_or(0));
+        self.record(TrafficEvent::Frame { delta: delta.as_secs_f64() });
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(Err(e)) = self.writer.as_mut().map(|writer| writer.flush()) {
+            warn!("Couldn't write to {}, stopped recording: {}", self.path.display(), e);
+            self.writer = None;
+        }
+    }
+}
+
+/// Reads a whole recording. A file cut short by a crash gives everything up to the last full record.
+pub fn read_recording(path: &Path) -> io::Result<(RecordingHeader, Vec<TrafficRecord>)> {
+    let bytes = fs::read(path)?;
+    let mut frames = Vec::new();
+    let mut rest = bytes.as_slice();
+    while rest.len() >= 4 {
+        let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + len {
+            warn!("{} ends partway through a record; replaying what's there", path.display());
+            break;
+        }
+        frames.push(&rest[4..4 + len]);
+        rest = &rest[4 + len..];
+    }
+
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let header = frames.first()
+        .and_then(|frame| decode::<RecordingHeader>(frame))
+        .filter(|header| header.magic == MAGIC)
+        .ok_or_else(|| invalid("not a traffic recording"))?;
+    let records = frames[1..].iter()
+        .map(|frame| decode::<TrafficRecord>(frame).ok_or_else(|| invalid("a record couldn't be read")))
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok((header, records))
+}
+
+/// Plays the incoming side of a recording back frame by frame, with each frame as long as it
+/// was when recorded, for as many connections as it has. Whatever we send to them goes nowhere.
+#[derive(Resource)]
+pub struct ReplayLink {
+    pub header: RecordingHeader,
+    records: VecDeque<TrafficRecord>,
+    frame: Option<u64>,
+    last_delta: Duration,
+    inboxes: HashMap<ClientId, VecDeque<(ChannelId, Vec<u8>)>>,
+    addresses: HashMap<ClientId, IpAddr>,
+    connected: HashSet<ClientId>,
+    events: Vec<(ClientId, LinkEvent)>,
+    /// Incoming messages handed over so far.
+    pub replayed: usize,
+    finished_at: Option<u64>,
+    closed: bool,
+}
+
+impl ReplayLink {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (header, records) = read_recording(path)?;
+        if header.protocol != PROTOCOL_VERSION {
+            warn!("{} was recorded on protocol {}, this is {}; messages may not decode", path.display(), header.protocol, PROTOCOL_VERSION);
+        }
+        Ok(Self::new(header, records))
+    }
+
+    pub fn new(header: RecordingHeader, records: Vec<TrafficRecord>) -> Self {
+        Self {
+            header,
+            records: records.into(),
+            frame: None,
+            last_delta: Duration::from_secs_f64(1.0 / 60.0),
+            inboxes: HashMap::new(),
+            addresses: HashMap::new(),
+            connected: HashSet::new(),
+            events: Vec::new(),
+            replayed: 0,
+            finished_at: None,
+            closed: false,
+        }
+    }
+
+    pub fn side(&self) -> RecordedSide {
+        self.header.side
+    }
+
+    /// Moves on a frame and returns how long it lasted when it was recorded.
+    pub fn start_frame(&mut self) -> Duration {
+        let frame = self.frame.map(|frame| frame + 1).unwrap_or(0);
+        self.frame = Some(frame);
+        while let Some(record) = self.records.front() {
+            match record.event {
+                TrafficEvent::Frame { delta } if record.frame <= frame => {
+                    self.last_delta = Duration::from_secs_f64(delta);
+                    self.records.pop_front();
+                }
+                _ => break,
+            }
+        }
+        self.last_delta
+    }
+
+    /// Hands over everything recorded up to this frame, and returns how connections changed.
+    pub fn poll(&mut self) -> Vec<(ClientId, LinkEvent)> {
+        let frame = self.frame.unwrap_or(0);
+        while self.records.front().map(|record| record.frame <= frame).unwrap_or(false) {
+            let record = self.records.pop_front().unwrap();
+            match record.event {
+                TrafficEvent::Connected { client_id, address } => {
+                    if let Some(ip) = address.and_then(|a| a.parse::<IpAddr>().ok()) {
+                        self.addresses.insert(client_id, ip);
+                    }
+                    self.connected.insert(client_id);
+                    self.events.push((client_id, LinkEvent::Connected));
+                }
+                TrafficEvent::Disconnected { client_id } => self.close(client_id),
+                TrafficEvent::Received { client_id, channel, payload } => {
+                    // Like the network: nothing arrives on a connection that's closed.
+                    if self.connected.contains(&client_id) {
+                        self.inboxes.entry(client_id).or_default().push_back((channel, payload));
+                    }
+                }
+                // What was sent then is what the app under replay sends now, if it still behaves.
+                TrafficEvent::Sent { .. } | TrafficEvent::Frame { .. } => {}
+            }
+        }
+
+        if self.records.is_empty() && self.inboxes.values().all(|inbox| inbox.is_empty()) {
+            let finished_at = *self.finished_at.get_or_insert(frame);
+            if !self.closed && frame - finished_at >= REPLAY_LINGER_FRAMES {
+                self.closed = true;
+                let connected: Vec<ClientId> = self.connected.iter().copied().collect();
+                for client_id in connected {
+                    self.close(client_id);
+                }
+            }
+        }
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn is_connected(&self, client_id: ClientId) -> bool {
+        self.connected.contains(&client_id)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.connected.iter().copied()
+    }
+
+    pub fn try_receive(&mut self, client_id: ClientId) -> Option<(ChannelId, Vec<u8>)> {
+        let received = self.inboxes.get_mut(&client_id)?.pop_front();
+        if received.is_some() {
+            self.replayed += 1;
+        }
+        received
+    }
+
+    /// The other end went away, which the app hears about.
+    fn close(&mut self, client_id: ClientId) {
+        if self.connected.remove(&client_id) {
+            self.inboxes.remove(&client_id);
+            self.events.push((client_id, LinkEvent::Lost));
+        }
+    }
+
+    /// We dropped them. Like quinnet, that isn't reported back as a lost connection.
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        self.connected.remove(&client_id);
+        self.inboxes.remove(&client_id);
+    }
+
+    pub fn address(&self, client_id: ClientId) -> Option<IpAddr> {
+        self.addresses.get(&client_id).copied()
+    }
+
+    /// Everything has been handed over and the connections closed a while ago.
+    fn done(&self) -> bool {
+        match (self.closed, self.finished_at, self.frame) {
+            (true, Some(finished), Some(frame)) => frame - finished >= 2 * REPLAY_LINGER_FRAMES,
+            _ => false,
+        }
+    }
+}
+
+/// Makes a fresh copy of a server recording's world to replay into, and the config to run it with.
+pub fn replay_server_config(recording: &Path, header: &RecordingHeader) -> Result<ServerConfig, String> {
+    let toml = header.config.as_deref().ok_or_else(|| String::from("the recording has no server config"))?;
+    let mut config: ServerConfig = toml::from_str(toml).map_err(|e| format!("the recording's server config is bad: {}", e))?;
+
+    let mut scratch = recording.as_os_str().to_owned();
+    scratch.push(".replay");
+    let scratch = PathBuf::from(scratch);
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch).map_err(|e| format!("couldn't clear {}: {}", scratch.display(), e))?;
+    }
+    let copy = world_copy(recording);
+    copy_dir(&copy, &scratch).map_err(|e| format!("couldn't copy {}: {}", copy.display(), e))?;
+
+    // Nobody can join a replay, so don't tell anyone about it.
+    config.world = scratch;
+    config.lan_announce = false;
+    Ok(config)
+}
+
+/// Numbers frames in a recording and flushes it every frame; steps a replay through its frames
+/// and stops it once it's played out.
+pub struct TrafficPlugin;
+
+impl Plugin for TrafficPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(First, (
+            start_recording_frame.after(TimeSystem).run_if(resource_exists::<TrafficRecorder>),
+            start_replay_frame.before(TimeSystem).run_if(resource_exists::<ReplayLink>),
+        ))
+        .add_systems(Last, (
+            flush_recording.run_if(resource_exists::<TrafficRecorder>),
+            finish_replay.run_if(resource_exists::<ReplayLink>),
+        ))
+        ;
+    }
+}
+
+pub fn start_recording_frame(time: Res<Time<Real>>, mut recorder: ResMut<TrafficRecorder>) {
+    recorder.start_frame(time.delta());
+}
+
+/// Time moves on by exactly what it did when recording, however long this frame really took.
+pub fn start_replay_frame(mut replay: ResMut<ReplayLink>, mut strategy: ResMut<TimeUpdateStrategy>) {
+    *strategy = TimeUpdateStrategy::ManualDuration(replay.start_frame());
+}
+
+pub fn flush_recording(mut recorder: ResMut<TrafficRecorder>) {
+    recorder.flush();
+}
+
+pub fn finish_replay(replay: Res<ReplayLink>, mut exit: EventWriter<AppExit>) {
+    if replay.done() {
+        info!("Replay finished: {} messages over {} frames", replay.replayed, replay.frame.unwrap_or(0));
+        exit.send(AppExit::Success);
+    }
+}
+
+/// For a headless client replay, where nothing else shows what arrived.
+pub fn log_server_messages(mut messages: EventReader<FromServer>) {
+    for FromServer(message) in messages.read() {
+        let mut text = format!("{:?}", message);
+        if text.len() > MAX_LOGGED_MESSAGE {
+            let cut = (0..=MAX_LOGGED_MESSAGE).rev().find(|i| text.is_char_boundary(*i)).unwrap_or(0);
+            text.truncate(cut);
+            text.push_str("...");
+        }
+        info!("{}", text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> RecordingHeader {
+        RecordingHeader { magic: MAGIC, protocol: PROTOCOL_VERSION, side: RecordedSide::Server, config: None }
+    }
+
+    fn record(frame: u64, event: TrafficEvent) -> TrafficRecord {
+        TrafficRecord { frame, time: frame as f64 / 60.0, event }
+    }
+
+    fn received(client_id: ClientId, payload: u8) -> TrafficEvent {
+        TrafficEvent::Received { client_id, channel: 0, payload: vec![payload] }
+    }
+
+    #[test]
+    fn read_recording_keeps_whole_records_of_a_truncated_file() {
+        let path = std::env::temp_dir().join(format!("traffic-test-{}.rec", std::process::id()));
+        let mut recorder = TrafficRecorder::create(&path, RecordedSide::Client, None).unwrap();
+        recorder.start_frame(Duration::from_millis(16));
+        recorder.record(received(0, 1));
+        recorder.start_frame(Duration::from_millis(17));
+        recorder.record(received(0, 2));
+        recorder.flush();
+        drop(recorder);
+
+        let (read_header, records) = read_recording(&path).unwrap();
+        assert_eq!(read_header.side, RecordedSide::Client);
+        assert_eq!(records.iter().map(|r| (r.frame, r.event.clone())).collect::<Vec<_>>(), vec![
+            (0, TrafficEvent::Frame { delta: 0.016 }),
+            (0, received(0, 1)),
+            (1, TrafficEvent::Frame { delta: 0.017 }),
+            (1, received(0, 2)),
+        ]);
+
+        // As if the app died partway through writing the last record.
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let (_, truncated) = read_recording(&path).unwrap();
+        assert_eq!(truncated, records[..3]);
+
+        fs::write(&path, b"not a recording").unwrap();
+        assert_eq!(read_recording(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn poll_hands_over_records_on_their_frame_in_order() {
+        let mut replay = ReplayLink::new(header(), vec![
+            record(0, TrafficEvent::Frame { delta: 0.02 }),
+            record(0, TrafficEvent::Connected { client_id: 1, address: Some(String::from("10.0.0.7")) }),
+            record(0, received(1, 1)),
+            record(0, received(1, 2)),
+            record(1, TrafficEvent::Frame { delta: 0.03 }),
+            record(1, received(1, 3)),
+            record(1, TrafficEvent::Disconnected { client_id: 1 }),
+            record(1, received(1, 4)),
+        ]);
+
+        assert_eq!(replay.start_frame(), Duration::from_secs_f64(0.02));
+        assert_eq!(replay.poll(), vec![(1, LinkEvent::Connected)]);
+        assert_eq!(replay.address(1), Some("10.0.0.7".parse().unwrap()));
+        assert_eq!(replay.try_receive(1), Some((0, vec![1])));
+        assert_eq!(replay.try_receive(1), Some((0, vec![2])));
+        assert_eq!(replay.try_receive(1), None);
+
+        // Frame 1's message is queued, but the connection drops in the same frame and takes it along.
+        assert_eq!(replay.start_frame(), Duration::from_secs_f64(0.03));
+        assert_eq!(replay.poll(), vec![(1, LinkEvent::Lost)]);
+        assert!(!replay.is_connected(1));
+        assert_eq!(replay.try_receive(1), None);
+        assert_eq!(replay.replayed, 2);
+    }
+
+    #[test]
+    fn replay_closes_connections_and_finishes_after_lingering() {
+        let mut replay = ReplayLink::new(header(), vec![
+            record(0, TrafficEvent::Connected { client_id: 1, address: None }),
+        ]);
+        let mut frames = 0;
+        let mut lost = false;
+        while !replay.done() {
+            replay.start_frame();
+            lost |= replay.poll().contains(&(1, LinkEvent::Lost));
+            frames += 1;
+            assert!(frames <= 3 * REPLAY_LINGER_FRAMES, "replay never finished");
+        }
+        assert!(lost);
+        assert_eq!(frames, 2 * REPLAY_LINGER_FRAMES + 1);
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr},
+    sync::{mpsc::{channel, Receiver, Sender, TryRecvError}, Mutex},
+};
+
+use bevy::{ecs::system::{SystemParam, SystemState}, prelude::*};
+use bevy_quinnet::{
+    client::QuinnetClient,
+    server::QuinnetServer,
+    shared::{channels::ChannelId, ClientId},
+};
+
+use crate::traffic::{ReplayLink, TrafficEvent, TrafficRecorder};
+
+/// The singleplayer host's id on its own server. Quinnet counts its ids up from zero, so this never clashes.
+pub const LOOPBACK_CLIENT_ID: ClientId = ClientId::MAX;
+
+/// What the in-process client sends its server. Dropping the sender is how it leaves.
+enum ToServer {
+    Connect,
+    Payload(ChannelId, Vec<u8>),
+}
+
+enum ToClient {
+    Payload(ChannelId, Vec<u8>),
+    Disconnect,
+}
+
+/// Connection changes on a loopback or replay link, the way quinnet reports them for real connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    Connected,
+    Lost,
+}
+
+/// The server's end of a singleplayer link. Everything arrives in order, whatever the channel.
+#[derive(Resource)]
+pub struct LoopbackServer {
+    from_client: Mutex<Receiver<ToServer>>,
+    to_client: Sender<ToClient>,
+    inbox: VecDeque<(ChannelId, Vec<u8>)>,
+    events: Vec<LinkEvent>,
+    pub connected: bool,
+    /// The client has gone for good, so the world can shut down.
+    pub closed: bool,
+}
+
+/// The client's end of a singleplayer link.
+#[derive(Resource)]
+pub struct LoopbackClient {
+    to_server: Sender<ToServer>,
+    from_server: Mutex<Receiver<ToClient>>,
+    events: Vec<LinkEvent>,
+}
+
+/// Two ends for one client and one server App, usually on different threads.
+pub fn loopback_pair() -> (LoopbackServer, LoopbackClient) {
+    let (to_server, from_client) = channel();
+    let (to_client, from_server) = channel();
+    (
+        LoopbackServer {
+            from_client: Mutex::new(from_client),
+            to_client,
+            inbox: VecDeque::new(),
+            events: Vec::new(),
+            connected: false,
+            closed: false,
+        },
+        LoopbackClient {
+            to_server,
+            from_server: Mutex::new(from_server),
+            events: Vec::new(),
+        },
+    )
+}
+
+impl LoopbackServer {
+    /// Moves whatever the client sent into the inbox.
+    fn poll(&mut self) {
+        loop {
+            let received = self.from_client.lock().unwrap().try_recv();
+            match received {
+                Ok(ToServer::Connect) => {
+                    if self.connected {
+                        self.events.push(LinkEvent::Lost);
+                        self.inbox.clear();
+                    }
+                    self.connected = true;
+                    self.events.push(LinkEvent::Connected);
+                }
+                Ok(ToServer::Payload(channel, payload)) => {
+                    if self.connected {
+                        self.inbox.push_back((channel, payload));
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !self.closed {
+                        self.closed = true;
+                        if self.connected {
+                            self.connected = false;
+                            self.events.push(LinkEvent::Lost);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, channel: ChannelId, payload: Vec<u8>) -> Result<(), String> {
+        if !self.connected {
+            return Err(String::from("the local player isn't connected"));
+        }
+        self.to_client.send(ToClient::Payload(channel, payload)).map_err(|_| String::from("the local player has gone"))
+    }
+
+    fn disconnect(&mut self) {
+        if self.connected {
+            self.connected = false;
+            self.inbox.clear();
+            let _ = self.to_client.send(ToClient::Disconnect);
+        }
+    }
+}
+
+impl LoopbackClient {
+    pub fn connect(&mut self) {
+        if self.to_server.send(ToServer::Connect).is_ok() {
+            self.events.push(LinkEvent::Connected);
+        } else {
+            self.events.push(LinkEvent::Lost);
+        }
+    }
+
+    fn send(&mut self, channel: ChannelId, payload: Vec<u8>) -> Result<(), String> {
+        self.to_server.send(ToServer::Payload(channel, payload)).map_err(|_| String::from("the local server has stopped"))
+    }
+
+    fn try_receive(&mut self) -> Option<(ChannelId, Vec<u8>)> {
+        let received = self.from_server.lock().unwrap().try_recv();
+        match received {
+            Ok(ToClient::Payload(channel, payload)) => Some((channel, payload)),
+            Ok(ToClient::Disconnect) | Err(TryRecvError::Disconnected) => {
+                if self.events.last() != Some(&LinkEvent::Lost) {
+                    self.events.push(LinkEvent::Lost);
+                }
+                None
+            }
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+}
+
+/// Sends and receives for the server, over quinnet and, in singleplayer, the loopback link.
+/// Quinnet only has an endpoint once we listen, which a singleplayer world may never do.
+/// When replaying, the recorded clients are the only ones; when recording, everything is written down.
+#[derive(SystemParam)]
+pub struct ServerNet<'w> {
+    quinnet: ResMut<'w, QuinnetServer>,
+    loopback: Option<ResMut<'w, LoopbackServer>>,
+    replay: Option<ResMut<'w, ReplayLink>>,
+    recorder: Option<ResMut<'w, TrafficRecorder>>,
+}
+
+impl ServerNet<'_> {
+    pub fn send(&mut self, client_id: ClientId, channel: ChannelId, payload: Vec<u8>) -> Result<(), String> {
+        let recorded = self.recorder.is_some().then(|| payload.clone());
+        let result = if client_id == LOOPBACK_CLIENT_ID {
+            match self.loopback.as_mut() {
+                Some(loopback) => loopback.send(channel, payload),
+                None => Err(String::from("no local player")),
+            }
+        } else if let Some(replay) = self.replay.as_ref() {
+            // Replayed clients aren't really there; what we tell them only goes in a recording.
+            if replay.is_connected(client_id) { Ok(()) } else { Err(String::from("not connected")) }
+        } else {
+            match self.quinnet.get_endpoint_mut() {
+                Some(endpoint) => endpoint.send_payload_on(client_id, channel, payload).map_err(|e| e.to_string()),
+                None => Err(String::from("not listening")),
+            }
+        };
+
+        if let (Ok(()), Some(payload)) = (&result, recorded) {
+            self.record(TrafficEvent::Sent { client_id, channel, payload });
+        }
+        result
+    }
+
+    /// Every open connection, joined or not.
+    pub fn clients(&self) -> Vec<ClientId> {
+        let mut clients = self.quinnet.get_endpoint().map(|endpoint| endpoint.clients()).unwrap_or_default();
+        if self.loopback.as_ref().map(|l| l.connected).unwrap_or(false) {
+            clients.push(LOOPBACK_CLIENT_ID);
+        }
+        if let Some(replay) = self.replay.as_ref() {
+            clients.extend(replay.clients());
+        }
+        clients
+    }
+
+    pub fn try_receive(&mut self, client_id: ClientId) -> Option<Vec<u8>> {
+        let (channel, payload) = if client_id == LOOPBACK_CLIENT_ID {
+            self.loopback.as_mut()?.inbox.pop_front()?
+        } else if let Some(replay) = self.replay.as_mut() {
+            replay.try_receive(client_id)?
+        } else {
+            self.quinnet.get_endpoint_mut()?.try_receive_payload_from(client_id).map(|(channel, payload)| (channel, payload.into()))?
+        };
+
+        if self.recorder.is_some() {
+            self.record(TrafficEvent::Received { client_id, channel, payload: payload.clone() });
+        }
+        Some(payload)
+    }
+
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        if client_id == LOOPBACK_CLIENT_ID {
+            if let Some(loopback) = self.loopback.as_mut() {
+                loopback.disconnect();
+            }
+        } else if let Some(replay) = self.replay.as_mut() {
+            replay.disconnect(client_id);
+        } else if let Some(endpoint) = self.quinnet.get_endpoint_mut() {
+            let _ = endpoint.disconnect_client(client_id);
+        }
+    }
+
+    /// Where a client is connecting from, if we know. Quinnet 0.9 keeps its connections' peer
+    /// addresses to itself, so for network clients we don't.
+    pub fn client_ip(&self, client_id: ClientId) -> Option<IpAddr> {
+        if client_id == LOOPBACK_CLIENT_ID {
+            return Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        self.replay.as_ref()?.address(client_id)
+    }
+
+    /// Picks up what the local player sent, or what's come due in a replay, and returns how
+    /// those connections changed.
+    pub fn poll_links(&mut self) -> Vec<(ClientId, LinkEvent)> {
+        let mut events = Vec::new();
+        if let Some(loopback) = self.loopback.as_mut() {
+            loopback.poll();
+            events.extend(loopback.events.drain(..).map(|event| (LOOPBACK_CLIENT_ID, event)));
+        }
+        if let Some(replay) = self.replay.as_mut() {
+            events.extend(replay.poll());
+        }
+        events
+    }
+
+    /// Writes to the recording, if there is one.
+    pub fn record(&mut self, event: TrafficEvent) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(event);
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.quinnet.get_endpoint().is_some()
+    }
+
+    pub fn quinnet_mut(&mut self) -> &mut QuinnetServer {
+        &mut self.quinnet
+    }
+}
+
+/// For exclusive systems, like the server commands.
+pub fn with_server_net<R>(world: &mut World, f: impl FnOnce(&mut ServerNet) -> R) -> R {
+    let mut state = SystemState::<ServerNet>::new(world);
+    let mut net = state.get_mut(world);
+    let result = f(&mut net);
+    state.apply(world);
+    result
+}
+
+/// The client's side of `ServerNet`. Offline there's neither, and sends go nowhere.
+/// A client records and replays everything as client 0.
+#[derive(SystemParam)]
+pub struct ClientNet<'w> {
+    quinnet: Option<ResMut<'w, QuinnetClient>>,
+    loopback: Option<ResMut<'w, LoopbackClient>>,
+    replay: Option<ResMut<'w, ReplayLink>>,
+    recorder: Option<ResMut<'w, TrafficRecorder>>,
+}
+
+impl ClientNet<'_> {
+    pub fn send(&mut self, channel: ChannelId, payload: Vec<u8>) -> Result<(), String> {
+        let recorded = self.recorder.is_some().then(|| payload.clone());
+        let result = if let Some(loopback) = self.loopback.as_mut() {
+            loopback.send(channel, payload)
+        } else if self.replay.is_some() {
+            Ok(())
+        } else {
+            match self.quinnet.as_mut().and_then(|client| client.get_connection_mut()) {
+                Some(connection) => connection.send_payload_on(channel, payload).map_err(|e| e.to_string()),
+                None => return Ok(()),
+            }
+        };
+
+        if let (Ok(()), Some(payload)) = (&result, recorded) {
+            self.record(TrafficEvent::Sent { client_id: 0, channel, payload });
+        }
+        result
+    }
+
+    pub fn try_receive(&mut self) -> Option<Vec<u8>> {
+        let (channel, payload) = if let Some(loopback) = self.loopback.as_mut() {
+            loopback.try_receive()?
+        } else if let Some(replay) = self.replay.as_mut() {
+            replay.try_receive(0)?
+        } else {
+            self.quinnet.as_mut()?.get_connection_mut()?.try_receive_payload().map(|(channel, payload)| (channel, payload.into()))?
+        };
+
+        if self.recorder.is_some() {
+            self.record(TrafficEvent::Received { client_id: 0, channel, payload: payload.clone() });
+        }
+        Some(payload)
+    }
+
+    /// How the loopback or replay link changed since last time.
+    pub fn take_link_events(&mut self) -> Vec<LinkEvent> {
+        let mut events = Vec::new();
+        if let Some(loopback) = self.loopback.as_mut() {
+            events.append(&mut loopback.events);
+        }
+        if let Some(replay) = self.replay.as_mut() {
+            events.extend(replay.poll().into_iter().map(|(_, event)| event));
+        }
+        events
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Writes to the recording, if there is one.
+    pub fn record(&mut self, event: TrafficEvent) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(event);
+        }
+    }
+
+    pub fn loopback_mut(&mut self) -> Option<&mut LoopbackClient> {
+        self.loopback.as_deref_mut()
+    }
+
+    pub fn quinnet_mut(&mut self) -> Option<&mut QuinnetClient> {
+        self.quinnet.as_deref_mut()
+    }
+}
@@ -0,0 +1,283 @@
+use std::path::Path;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::structure::StructureTemplate;
+
+/// Candidate blocks for voxelized buildings and the base color they stand in for.
+static BUILDING_PALETTE: [(u32, [f32; 3]); 7] = [
+    (5, [0.35, 0.35, 0.35]),  // cobble
+    (9, [0.6, 0.6, 0.6]),     // smooth stone
+    (10, [0.55, 0.4, 0.2]),   // planks
+    (1, [0.85, 0.8, 0.55]),   // sand
+    (4, [0.35, 0.25, 0.15]),  // dirt
+    (13, [0.6, 0.15, 0.1]),   // red stone
+    (47, [0.8, 0.8, 0.85]),   // metal plate
+];
+
+static GLASS: u32 = 8;
+
+fn block_for_color(color: [f32; 4]) -> u32 {
+    if color[3] < 0.9 {
+        return GLASS;
+    }
+    let rgb = Vec3::new(color[0], color[1], color[2]);
+
+    BUILDING_PALETTE
+        .iter()
+        .min_by(|a, b| {
+            let da = rgb.distance_squared(Vec3::from_array(a.1));
+            let db = rgb.distance_squared(Vec3::from_array(b.1));
+            da.total_cmp(&db)
+        })
+        .map(|(id, _)| *id)
+        .unwrap()
+}
+
+/// A triangle of a glTF scene, in scene space, and what it looks like.
+pub struct GltfTriangle<'a> {
+    pub positions: [Vec3; 3],
+    uvs: Option<[Vec2; 3]>,
+    factor: [f32; 4],
+    texture: Option<&'a gltf::image::Data>,
+}
+
+impl GltfTriangle<'_> {
+    /// The base color at `positions[0] + (positions[1] - positions[0]) * u + (positions[2] - positions[0]) * v`:
+    /// the texture there, if there is one, times the material's factor.
+    pub fn color_at(&self, u: f32, v: f32) -> [f32; 4] {
+        let (Some(uvs), Some(texture)) = (self.uvs, self.texture) else {
+            return self.factor;
+        };
+        let uv = uvs[0] + (uvs[1] - uvs[0]) * u + (uvs[2] - uvs[0]) * v;
+        let texel = sample_texture(texture, uv);
+        [0, 1, 2, 3].map(|i| texel[i] * self.factor[i])
+    }
+}
+
+/// The nearest texel, wrapping like glTF's default sampler.
+fn sample_texture(image: &gltf::image::Data, uv: Vec2) -> [f32; 4] {
+    use gltf::image::Format;
+
+    let (width, height) = (image.width as usize, image.height as usize);
+    if width == 0 || height == 0 {
+        return [1.0; 4];
+    }
+    let x = ((uv.x.rem_euclid(1.0) * width as f32) as usize).min(width - 1);
+    let y = ((uv.y.rem_euclid(1.0) * height as f32) as usize).min(height - 1);
+    let channels = match image.format {
+        Format::R8 => 1,
+        Format::R8G8 => 2,
+        Format::R8G8B8 => 3,
+        Format::R8G8B8A8 => 4,
+        // Nothing we ship; treat as untextured.
+        _ => return [1.0; 4],
+    };
+    let start = (y * width + x) * channels;
+    let Some(texel) = image.pixels.get(start..start + channels) else {
+        return [1.0; 4];
+    };
+    let channel = |i: usize| texel.get(i).map(|c| *c as f32 / 255.0);
+    let r = channel(0).unwrap_or(1.0);
+    [r, channel(1).unwrap_or(r), channel(2).unwrap_or(r), channel(3).unwrap_or(1.0)]
+}
+
+fn voxelize_triangle(tri: &GltfTriangle, scale: f32, out: &mut HashMap<IVec3, u32>) {
+    let [a, b, c] = tri.positions;
+    let longest = (b - a).length()
+        .max((c - b).length())
+        .max((a - c).length()) * scale;
+
+    // Sample at half-voxel spacing so thin walls stay watertight.
+    let steps = (longest * 2.0).ceil().max(1.0) as i32;
+
+    for i in 0..=steps {
+        for j in 0..=(steps - i) {
+            let u = i as f32 / steps as f32;
+            let v = j as f32 / steps as f32;
+            let p = a + (b - a) * u + (c - a) * v;
+            out.insert((p * scale).floor().as_ivec3(), block_for_color(tri.color_at(u, v)));
+        }
+    }
+}
+
+fn walk_node(node: gltf::Node, parent: Mat4, buffers: &[gltf::buffer::Data], images: &[gltf::image::Data], f: &mut impl FnMut(&GltfTriangle)) {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let pbr = primitive.material().pbr_metallic_roughness();
+            let texture = pbr.base_color_texture();
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let uvs: Option<Vec<Vec2>> = texture.as_ref()
+                .and_then(|info| reader.read_tex_coords(info.tex_coord()))
+                .map(|uvs| uvs.into_f32().map(Vec2::from_array).collect());
+
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+            let positions: Vec<Vec3> = positions
+                .map(|p| transform.transform_point3(Vec3::from_array(p)))
+                .collect();
+
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            for tri in indices.chunks_exact(3) {
+                let corners = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+                f(&GltfTriangle {
+                    positions: corners.map(|i| positions[i]),
+                    uvs: uvs.as_ref().map(|uvs| corners.map(|i| uvs.get(i).copied().unwrap_or_default())),
+                    factor: pbr.base_color_factor(),
+                    texture: texture.as_ref().and_then(|info| images.get(info.texture().source().index())),
+                });
+            }
+        }
+    }
+
+    for child in node.children() {
+        walk_node(child, transform, buffers, images, f);
+    }
+}
+
+/// Calls `f` with every triangle of a glTF file's default scene.
+pub fn for_each_gltf_triangle(path: impl AsRef<Path>, mut f: impl FnMut(&GltfTriangle)) -> Result<(), gltf::Error> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    if let Some(scene) = document.default_scene().or_else(|| document.scenes().next()) {
+        for node in scene.nodes() {
+            walk_node(node, Mat4::IDENTITY, &buffers, &images, &mut f);
+        }
+    }
+    Ok(())
+}
+
+/// Rasterizes every triangle of a glTF scene into blocks, `scale` blocks per glTF unit.
+/// Only the surface is filled: the result is a shell one block thick, and a closed mesh
+/// comes out hollow. A face on a unit boundary lands in the block past it, so a cube
+/// `n` blocks across is `n + 1` blocks wide.
+pub fn voxelize_gltf(path: impl AsRef<Path>, scale: f32) -> Result<StructureTemplate, gltf::Error> {
+    let name = path.as_ref().file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut voxels = HashMap::new();
+    for_each_gltf_triangle(path, |tri| {
+        voxelize_triangle(tri, scale, &mut voxels);
+    })?;
+
+    if voxels.is_empty() {
+        return Ok(StructureTemplate::new(&name, IVec3::ZERO));
+    }
+
+    let min = voxels.keys().fold(IVec3::MAX, |a, b| a.min(*b));
+    let max = voxels.keys().fold(IVec3::MIN, |a, b| a.max(*b));
+
+    let mut structure = StructureTemplate::new(&name, max - min + IVec3::ONE);
+    for (spot, block) in voxels {
+        structure.set(&(spot - min), block);
+    }
+
+    Ok(structure)
+}
+
+/// `voxelize <input.gltf> <output.jstruct> [--blocks-per-unit N]`
+pub fn run_voxelize_cli(input: &Path, output: &Path, scale: f32) {
+    let structure = match voxelize_gltf(input, scale) {
+        Ok(structure) => structure,
+        Err(e) => {
+            eprintln!("Couldn't voxelize {}: {}", input.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let size = structure.size();
+    if let Err(e) = structure.save(output) {
+        eprintln!("Couldn't write {}: {}", output.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {} ({}x{}x{})", output.display(), size.x, size.y, size.z);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Writes a glTF of the unit cube from the origin, with no material, and returns its path.
+    fn write_unit_cube(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("voxelize-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Corner i is at (i & 1, i >> 1 & 1, i >> 2 & 1).
+        let corners: Vec<[f32; 3]> = (0..8).map(|i| [(i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2 & 1) as f32]).collect();
+        let faces: [u16; 36] = [
+            0, 2, 6, 0, 6, 4, 1, 3, 7, 1, 7, 5, // x
+            0, 1, 5, 0, 5, 4, 2, 3, 7, 2, 7, 6, // y
+            0, 1, 3, 0, 3, 2, 4, 5, 7, 4, 7, 6, // z
+        ];
+        let mut bin = Vec::new();
+        corners.iter().flatten().for_each(|f| bin.extend_from_slice(&f.to_le_bytes()));
+        faces.iter().for_each(|i| bin.extend_from_slice(&i.to_le_bytes()));
+        fs::write(dir.join("cube.bin"), &bin).unwrap();
+
+        let json = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scene": 0,
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [{{"mesh": 0}}],
+                "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1}}]}}],
+                "buffers": [{{"uri": "cube.bin", "byteLength": {}}}],
+                "bufferViews": [
+                    {{"buffer": 0, "byteOffset": 0, "byteLength": 96}},
+                    {{"buffer": 0, "byteOffset": 96, "byteLength": 72}}
+                ],
+                "accessors": [
+                    {{"bufferView": 0, "componentType": 5126, "count": 8, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 1]}},
+                    {{"bufferView": 1, "componentType": 5123, "count": 36, "type": "SCALAR"}}
+                ]
+            }}"#,
+            bin.len()
+        );
+        let path = dir.join("cube.gltf");
+        fs::write(&path, json).unwrap();
+        path
+    }
+
+    fn all_spots(size: IVec3) -> impl Iterator<Item = IVec3> {
+        (0..size.x).flat_map(move |x| (0..size.y).flat_map(move |y| (0..size.z).map(move |z| IVec3::new(x, y, z))))
+    }
+
+    #[test]
+    fn unit_cube_at_one_block_per_unit() {
+        let path = write_unit_cube("unit");
+        let structure = voxelize_gltf(&path, 1.0).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(structure.size(), IVec3::splat(2));
+        for spot in all_spots(structure.size()) {
+            // Untextured and white, which is nearest metal plate.
+            assert_eq!(structure.get(&spot), 47, "no block at {}", spot);
+        }
+    }
+
+    #[test]
+    fn closed_mesh_comes_out_a_hollow_shell() {
+        let path = write_unit_cube("hollow");
+        for scale in [4.0, 8.0] {
+            let structure = voxelize_gltf(&path, scale).unwrap();
+            let n = scale as i32;
+            assert_eq!(structure.size(), IVec3::splat(n + 1), "at {} blocks per unit", scale);
+
+            for spot in all_spots(structure.size()) {
+                let on_face = spot.min_element() == 0 || spot.max_element() == n;
+                assert_eq!(structure.get(&spot) != 0, on_face, "at {} blocks per unit, {}", scale, spot);
+            }
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+use std::{path::Path, thread};
+
+use bevy::prelude::*;
+use image::{Rgb, RgbImage, RgbaImage};
+use noise::Perlin;
+
+use crate::{blockinfo::Blocks, chunk::{natural_blockat, CH, WL}, cube::CubeSide};
+
+static WATER: u32 = 2;
+/// Coarse step for the top-down scan; the gap above each hit is refined block by block.
+static SCAN_STEP: i32 = 4;
+/// Water this deep or deeper is drawn at full opacity.
+static MAX_WATER_DEPTH: f32 = 16.0;
+
+/// Average color of every block's top face in the texture atlas.
+pub fn block_colors(atlas: &RgbaImage) -> Vec<[f32; 3]> {
+    let tile = atlas.width() / 16;
+
+    (0..Blocks::get_texs_length() as u32)
+        .map(|id| {
+            let tex = Blocks::get_tex_coords(id, CubeSide::TOP);
+            // Atlas rows are counted from the bottom, matching get_uv_coords.
+            let x0 = tex.0 as u32 * tile;
+            let y0 = (15 - tex.1 as u32) * tile;
+
+            let mut sum = Vec3::ZERO;
+            let mut weight = 0.0;
+            for x in x0..x0 + tile {
+                for y in y0..y0 + tile {
+                    let p = atlas.get_pixel(x, y).0;
+                    let a = p[3] as f32 / 255.0;
+                    sum += Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0 * a;
+                    weight += a;
+                }
+            }
+            if weight > 0.0 {
+                (sum / weight).to_array()
+            } else {
+                [1.0, 0.0, 1.0]
+            }
+        })
+        .collect()
+}
+
+pub struct Column {
+    pub top: i32,
+    pub block: u32,
+    pub floor: i32,
+    pub floor_block: u32,
+}
+
+pub fn sample_column(perlin: &Perlin, x: i32, z: i32) -> Column {
+    let mut y = CH - 1;
+    while y > 0 && natural_blockat(perlin, &IVec3::new(x, y, z)) == 0 {
+        y = (y - SCAN_STEP).max(0);
+    }
+    for above in (y + 1..(y + SCAN_STEP).min(CH)).rev() {
+        if natural_blockat(perlin, &IVec3::new(x, above, z)) != 0 {
+            y = above;
+            break;
+        }
+    }
+
+    let block = natural_blockat(perlin, &IVec3::new(x, y, z)) & Blocks::block_id_bits();
+
+    let mut floor = y;
+    let mut floor_block = block;
+    while floor_block == WATER && floor > 0 {
+        floor -= 1;
+        floor_block = natural_blockat(perlin, &IVec3::new(x, floor, z)) & Blocks::block_id_bits();
+    }
+
+    Column { top: y, block, floor, floor_block }
+}
+
+fn color_of(colors: &[[f32; 3]], block: u32) -> Vec3 {
+    match colors.get(block as usize) {
+        Some(c) => Vec3::from_array(*c),
+        None => Vec3::new(1.0, 0.0, 1.0),
+    }
+}
+
+/// Renders the rectangle starting at `origin` (x, z) top-down, one pixel per block column.
+pub fn render_map(perlin: &Perlin, colors: &[[f32; 3]], origin: IVec2, size: UVec2) -> RgbImage {
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1) as u32;
+    let rows_per = (size.y + threads - 1) / threads.max(1);
+
+    // One extra column to the west so every pixel has a neighbor to shade against.
+    let columns: Vec<Vec<Column>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                scope.spawn(move || {
+                    let mut rows = Vec::new();
+                    for row in (t * rows_per)..((t + 1) * rows_per).min(size.y) {
+                        rows.push(
+                            (-1..size.x as i32)
+                                .map(|col| sample_column(perlin, origin.x + col, origin.y + row as i32))
+                                .collect::<Vec<_>>(),
+                        );
+                    }
+                    rows
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+
+    let water = color_of(colors, WATER);
+    let mut img = RgbImage::new(size.x, size.y);
+
+    for (row, cols) in columns.iter().enumerate() {
+        for col in 0..size.x as usize {
+            let c = &cols[col + 1];
+            let west = &cols[col];
+
+            let mut color = color_of(colors, c.floor_block);
+
+            // Brighter with altitude, plus a simple hillshade lit from the west.
+            let height = 0.7 + 0.5 * ((c.floor as f32 - WL) / (CH as f32 - WL)).clamp(-0.5, 1.0);
+            let slope = 1.0 + ((c.floor - west.floor) as f32 * 0.08).clamp(-0.3, 0.3);
+            color *= height * slope;
+
+            if c.block == WATER {
+                let depth = ((c.top - c.floor) as f32 / MAX_WATER_DEPTH).clamp(0.0, 1.0);
+                color = color.lerp(water, 0.4 + 0.6 * depth);
+            }
+
+            let color = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).to_array();
+            img.put_pixel(col as u32, row as u32, Rgb([color[0] as u8, color[1] as u8, color[2] as u8]));
+        }
+    }
+
+    img
+}
+
+/// `worldgen-map`, see `cli::WorldgenMapArgs`.
+pub fn run_worldmap_cli(corner: IVec2, size: UVec2, out: &Path, seed: Option<u32>) {
+    if size.x == 0 || size.y == 0 {
+        eprintln!("The map needs a width and depth of at least 1");
+        std::process::exit(1);
+    }
+
+    let perlin = match seed {
+        Some(seed) => Perlin::new(seed),
+        None => Perlin::default(),
+    };
+
+    let atlas = match image::open("assets/world.png") {
+        Ok(atlas) => atlas.to_rgba8(),
+        Err(e) => {
+            eprintln!("Couldn't open assets/world.png: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let colors = block_colors(&atlas);
+
+    let img = render_map(&perlin, &colors, corner, size);
+
+    if let Err(e) = img.save(out) {
+        eprintln!("Couldn't write {}: {}", out.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {}", out.display());
+}